@@ -0,0 +1,69 @@
+// CPU affinity of the jobs (--pin, --cpuset), every slot runs its jobs on a
+// CPU of its own, round robin if there are more slots than CPUs
+use std::process::{Child, Command};
+
+// The size of a cpu_set_t
+const MAX_CPUS: usize = 1024;
+
+// `0-3,8,10-11`
+pub fn parse_cpuset(s: &str) -> Result<Vec<usize>, String> {
+    let invalid = || format!("invalid CPU list '{s}', expected e.g. 0-3,8");
+    let mut cpus = vec![];
+    for part in s.split(',') {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let first: usize = first.trim().parse().map_err(|_| invalid())?;
+        let last: usize = last.trim().parse().map_err(|_| invalid())?;
+        if first > last || last >= MAX_CPUS {
+            return Err(invalid());
+        }
+        cpus.extend(first..=last);
+    }
+    Ok(cpus)
+}
+
+// The CPU of a slot (starting at 1)
+pub fn cpu(cpus: &[usize], slot: usize) -> Option<usize> {
+    (!cpus.is_empty()).then(|| cpus[slot.saturating_sub(1) % cpus.len()])
+}
+
+// Before the job is started
+#[cfg(target_os = "linux")]
+pub fn pin(cmd: &mut Command, cpu: usize) {
+    use std::os::unix::process::CommandExt;
+    // SAFETY: sched_setaffinity(2) only reads the set, which is moved into
+    // the closure
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        cmd.pre_exec(move || {
+            if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin(_cmd: &mut Command, _cpu: usize) {}
+
+// Right after the job was started, processes it starts inherit the mask
+#[cfg(target_os = "windows")]
+pub fn pin_child(child: &Child, cpu: usize) {
+    use std::{ffi::c_void, os::windows::io::AsRawHandle};
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetProcessAffinityMask(process: *mut c_void, mask: usize) -> i32;
+    }
+
+    if cpu < usize::BITS as usize {
+        // SAFETY: the handle is valid while child is alive
+        unsafe {
+            SetProcessAffinityMask(child.as_raw_handle().cast(), 1 << cpu);
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn pin_child(_child: &Child, _cpu: usize) {}
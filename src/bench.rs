@@ -0,0 +1,106 @@
+// Benchmark mode (--bench N), every job is run N times in its slot (after
+// --warmup runs that are not measured) and the timings are compared at the end
+use std::{fmt::Write, time::Duration};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Bench {
+    pub runs: usize,
+    pub warmup: usize,
+}
+
+#[derive(Debug)]
+struct Stats {
+    runs: usize,
+    min: f64,
+    median: f64,
+    mean: f64,
+    stddev: f64,
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn stats(timings: &[Duration]) -> Option<Stats> {
+    let mut seconds: Vec<f64> = timings.iter().map(Duration::as_secs_f64).collect();
+    seconds.sort_by(f64::total_cmp);
+    let runs = seconds.len();
+    let min = *seconds.first()?;
+    let median = if runs.is_multiple_of(2) {
+        (seconds[runs / 2 - 1] + seconds[runs / 2]) / 2.0
+    } else {
+        seconds[runs / 2]
+    };
+    let mean = seconds.iter().sum::<f64>() / runs as f64;
+    // Of the sample, 0 for a single run
+    let stddev = if runs > 1 {
+        (seconds.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (runs - 1) as f64).sqrt()
+    } else {
+        0.0
+    };
+    Some(Stats {
+        runs,
+        min,
+        median,
+        mean,
+        stddev,
+    })
+}
+
+fn format_seconds(seconds: f64) -> String {
+    if seconds < 1.0 {
+        format!("{:.1}ms", seconds * 1000.0)
+    } else {
+        format!("{seconds:.3}s")
+    }
+}
+
+// One line per job, fastest first, with its mean relative to the fastest one
+pub fn report(jobs: &[(String, Vec<Duration>)]) -> String {
+    let mut rows: Vec<(&str, Stats)> = jobs
+        .iter()
+        .filter_map(|(job, timings)| Some((job.as_str(), stats(timings)?)))
+        .collect();
+    rows.sort_by(|a, b| a.1.mean.total_cmp(&b.1.mean));
+    let fastest = rows.first().map_or(0.0, |(_, stats)| stats.mean);
+    let table: Vec<[String; 7]> = rows
+        .iter()
+        .map(|(job, stats)| {
+            let relative = if fastest > 0.0 {
+                format!("{:.2}x", stats.mean / fastest)
+            } else {
+                String::from("-")
+            };
+            [
+                stats.runs.to_string(),
+                format_seconds(stats.min),
+                format_seconds(stats.median),
+                format_seconds(stats.mean),
+                format_seconds(stats.stddev),
+                relative,
+                (*job).to_string(),
+            ]
+        })
+        .collect();
+    let header = [
+        "Runs", "Min", "Median", "Mean", "Stddev", "Relative", "Command",
+    ];
+    let mut widths = header.map(str::len);
+    for row in &table {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut report = String::new();
+    for row in std::iter::once(header.map(String::from)).chain(table) {
+        let mut line = String::new();
+        for (i, cell) in row.iter().enumerate() {
+            // The command is left aligned and last, without padding
+            if i + 1 == row.len() {
+                line.push_str(cell);
+            } else {
+                let _ = write!(line, "{:>width$}  ", cell, width = widths[i]);
+            }
+        }
+        report.push_str(&line);
+        report.push('\n');
+    }
+    report
+}
@@ -0,0 +1,122 @@
+// Jobs that succeeded in an earlier run (--cache DIR), so they are skipped
+// when the same list of jobs is run again
+//
+// Every successful job leaves a file DIR/<hash> with its command, the hash is
+// taken over the command and, with --cache-inputs, the contents of the input
+// files ({}) it was given. A changed input file means the job is run again.
+// With --cache-key the command is replaced by a template of its own, e.g.
+// `{1}`, so jobs differing only in other parts of the command are the same.
+use crate::template::Template;
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// FNV-1a, it has to be the same in every run (and every build)
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+#[derive(Debug, Clone, Copy)]
+struct Hash(u64);
+
+impl Hash {
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Cache {
+    dir: PathBuf,
+    inputs: bool,
+    // Filled in with the arguments instead of the command (--cache-key)
+    by: Option<Template>,
+    // Jobs skipped so far
+    hits: AtomicUsize,
+}
+
+impl Cache {
+    pub fn create(dir: &Path, inputs: bool, by: Option<Template>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Cache {
+            dir: dir.to_path_buf(),
+            inputs,
+            by,
+            hits: AtomicUsize::new(0),
+        })
+    }
+
+    // What a job is known by: its command, or --cache-key filled in with its
+    // arguments. Jobs without arguments always go by their command.
+    pub fn key_text(&self, command: &str, args: &[String]) -> String {
+        match &self.by {
+            Some(by) if !args.is_empty() => by.expand(args),
+            _ => command.to_string(),
+        }
+    }
+
+    // The hash of `key_text`, arguments that are no (readable) files are only
+    // part of the text
+    pub fn key(&self, text: &str, args: &[String]) -> String {
+        let mut hash = Hash(FNV_OFFSET);
+        hash.update(text.as_bytes());
+        if self.inputs {
+            for arg in args {
+                let Ok(mut file) = File::open(arg) else {
+                    continue;
+                };
+                hash.update(&[0]);
+                hash.update(arg.as_bytes());
+                let mut buffer = [0; 64 * 1024];
+                while let Ok(n @ 1..) = file.read(&mut buffer) {
+                    hash.update(&buffer[..n]);
+                }
+            }
+        }
+        format!("{:016x}", hash.0)
+    }
+
+    // The text is compared as well, in case two of them have the same hash
+    pub fn contains(&self, key: &str, text: &str) -> bool {
+        let found = fs::read_to_string(self.dir.join(key)).is_ok_and(|cached| cached == text);
+        if found {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        found
+    }
+
+    pub fn insert(&self, key: &str, text: &str) -> io::Result<()> {
+        fs::write(self.dir.join(key), text)
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_from_template() {
+        let dir = std::env::temp_dir().join(format!("parallel-sh-{}-cache", std::process::id()));
+        let cache = Cache::create(&dir, false, Some(Template::raw(String::from("{1}")))).unwrap();
+        let args = [String::from("a.png"), String::from("2026-10-16")];
+        assert_eq!(
+            cache.key_text("convert a.png --date 2026-10-16", &args),
+            "a.png"
+        );
+        assert_eq!(cache.key_text("make all", &[]), "make all");
+        let key = cache.key("a.png", &args);
+        assert!(!cache.contains(&key, "a.png"));
+        cache.insert(&key, "a.png").unwrap();
+        assert!(cache.contains(&key, "a.png"));
+        assert_eq!(cache.hits(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
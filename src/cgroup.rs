@@ -0,0 +1,176 @@
+// Per-job cgroups (cgroup v2 on Linux), used to apply resource limits
+//
+// Job cgroups are created next to each other below the cgroup parallel-sh
+// runs in, which needs the controllers to be delegated to it. With --freeze
+// they are all frozen while parallel-sh is stopped.
+use log::debug;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::{fd::AsRawFd, unix::process::CommandExt},
+    path::{Path, PathBuf},
+    process::{self, Command},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+const MOUNT: &str = "/sys/fs/cgroup";
+
+// The job cgroups that exist, and whether they are frozen
+static JOBS: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+static FROZEN: AtomicBool = AtomicBool::new(false);
+
+// Freeze or thaw all job cgroups, ones created while frozen start frozen
+pub fn freeze_all(frozen: bool) {
+    let jobs = JOBS.lock().unwrap_or_else(PoisonError::into_inner);
+    FROZEN.store(frozen, Ordering::SeqCst);
+    for path in jobs.iter() {
+        if let Err(e) = fs::write(path.join("cgroup.freeze"), if frozen { "1" } else { "0" }) {
+            debug!("Could not freeze cgroup '{}': {}", path.display(), e);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Root {
+    path: PathBuf,
+    next: AtomicUsize,
+    // Whether a controller could be enabled for the job cgroups
+    controllers: Mutex<HashMap<String, bool>>,
+}
+
+impl Root {
+    // The cgroup of parallel-sh, if it is a cgroup v2 one
+    pub fn detect() -> Option<Root> {
+        let own = fs::read_to_string("/proc/self/cgroup").ok()?;
+        let own = own.lines().find_map(|line| line.strip_prefix("0::"))?;
+        let path = PathBuf::from(MOUNT).join(own.trim_start_matches('/'));
+        if !path.join("cgroup.controllers").exists() {
+            return None;
+        }
+        Some(Root {
+            path,
+            next: AtomicUsize::new(1),
+            controllers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Make `controller` usable for job cgroups, false if that is not possible
+    pub fn enable(&self, controller: &str) -> bool {
+        let mut controllers = self.controllers.lock().unwrap();
+        if let Some(&enabled) = controllers.get(controller) {
+            return enabled;
+        }
+        let enabled = self.try_enable(controller);
+        controllers.insert(controller.to_string(), enabled);
+        enabled
+    }
+
+    fn try_enable(&self, controller: &str) -> bool {
+        let available =
+            fs::read_to_string(self.path.join("cgroup.controllers")).unwrap_or_default();
+        if !available.split_whitespace().any(|c| c == controller) {
+            debug!("cgroup controller '{}' is not available", controller);
+            return false;
+        }
+        let subtree_control = self.path.join("cgroup.subtree_control");
+        let enabled = fs::read_to_string(&subtree_control).unwrap_or_default();
+        if enabled.split_whitespace().any(|c| c == controller) {
+            return true;
+        }
+        // Only cgroups without processes can pass controllers on
+        if let Err(e) =
+            leave(&self.path).and_then(|()| fs::write(&subtree_control, format!("+{controller}")))
+        {
+            debug!("Could not enable cgroup controller '{}': {}", controller, e);
+            return false;
+        }
+        true
+    }
+
+    // Create a cgroup for a single job, with `settings` written to its files
+    pub fn create(&self, settings: &[(&str, String)]) -> io::Result<Job> {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        let path = self.path.join(format!("parallel-sh-{}-{n}", process::id()));
+        fs::create_dir(&path)?;
+        let job = Job {
+            procs: OpenOptions::new()
+                .write(true)
+                .open(path.join("cgroup.procs"))?,
+            path,
+        };
+        for (file, value) in settings {
+            fs::write(job.path.join(file), value)?;
+        }
+        let mut jobs = JOBS.lock().unwrap_or_else(PoisonError::into_inner);
+        if FROZEN.load(Ordering::SeqCst) {
+            fs::write(job.path.join("cgroup.freeze"), "1")?;
+        }
+        jobs.insert(job.path.clone());
+        Ok(job)
+    }
+}
+
+// Move parallel-sh itself into a leaf cgroup below `path`
+fn leave(path: &Path) -> io::Result<()> {
+    let leaf = path.join(format!("parallel-sh-{}", process::id()));
+    if !leaf.exists() {
+        fs::create_dir(&leaf)?;
+        fs::write(leaf.join("cgroup.procs"), "0")?;
+    }
+    Ok(())
+}
+
+// Removed again once dropped
+#[derive(Debug)]
+pub struct Job {
+    path: PathBuf,
+    procs: File,
+}
+
+impl Job {
+    // Move the job's process into the cgroup before it execs
+    pub fn attach(&self, cmd: &mut Command) {
+        let fd = self.procs.as_raw_fd();
+        // SAFETY: write(2) is async-signal-safe, the fd stays open until after spawn
+        unsafe {
+            cmd.pre_exec(move || {
+                // "0" stands for the writing process
+                if libc::write(fd, b"0".as_ptr().cast(), 1) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        JOBS.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.path);
+        // Left over processes would keep the cgroup busy
+        if let Ok(mut kill) = OpenOptions::new()
+            .write(true)
+            .open(self.path.join("cgroup.kill"))
+        {
+            let _ = kill.write_all(b"1");
+        }
+        for _ in 0..10 {
+            match fs::remove_dir(&self.path) {
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                Err(e) => {
+                    debug!("Could not remove cgroup '{}': {}", self.path.display(), e);
+                    return;
+                }
+                Ok(()) => return,
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// Defaults from ~/.config/parallel-sh/config.toml, put in front of the
+// command line arguments so these override them
+//
+// A small subset of TOML: `key = value` lines with long options as keys and
+// strings, numbers, booleans or arrays of them as values, e.g.
+//   jobs = "50%"
+//   tag = true
+//   env = ["PATH", "HOME"]
+//
+//   [profile.build]
+//   jobs = 4
+//   halt-on-error = true
+// Keys before the first section apply to every run, the ones in a
+// `[profile.NAME]` section only with --profile NAME.
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+fn path() -> Option<PathBuf> {
+    #[cfg(target_os = "windows")]
+    let dir = std::env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(target_os = "windows"))]
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    Some(dir?.join("parallel-sh").join("config.toml"))
+}
+
+// The --profile given on the command line, it is needed before parsing it
+pub fn profile(args: &[OsString]) -> Option<String> {
+    let mut args = args.iter().map(|arg| arg.to_string_lossy());
+    let mut profile = None;
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--profile" {
+            profile = args.next().map(|name| name.into_owned());
+        } else if let Some(name) = arg.strip_prefix("--profile=") {
+            profile = Some(name.to_string());
+        }
+    }
+    profile
+}
+
+// The options from the config file, for every run and for `profile`
+pub fn args(profile: Option<&str>) -> Result<Vec<OsString>, String> {
+    let path = path();
+    let content = match path.as_ref().map(fs::read_to_string) {
+        Some(Ok(content)) => content,
+        _ if profile.is_none() => return Ok(vec![]),
+        _ => {
+            return Err(format!(
+                "profile '{}' requires a config file at '{}'",
+                profile.unwrap_or_default(),
+                path.unwrap_or_default().display()
+            ))
+        }
+    };
+    parse(&content, profile).map_err(|e| {
+        format!(
+            "{} in '{}'",
+            e,
+            path.as_deref().unwrap_or(Path::new("")).display()
+        )
+    })
+}
+
+fn parse(content: &str, profile: Option<&str>) -> Result<Vec<OsString>, String> {
+    let mut args = vec![];
+    // Whether the current section applies to this run
+    let mut active = true;
+    let mut found = profile.is_none();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |what: &str| format!("{} on line {}", what, n + 1);
+        if let Some(section) = line.strip_prefix('[') {
+            let section = strip_comment(section)
+                .strip_suffix(']')
+                .ok_or_else(|| invalid("invalid section"))?
+                .trim();
+            let name = section
+                .strip_prefix("profile.")
+                .ok_or_else(|| invalid("unknown section"))?;
+            active = Some(name.trim().trim_matches('"')) == profile;
+            found |= active;
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| invalid("expected key = value"))?;
+        let key = key.trim().trim_matches('"');
+        let values = parse_value(value.trim()).ok_or_else(|| invalid("invalid value"))?;
+        if !active {
+            continue;
+        }
+        for value in values {
+            match value {
+                Value::Bool(false) => {}
+                Value::Bool(true) => args.push(OsString::from(format!("--{key}"))),
+                Value::String(value) => {
+                    args.push(OsString::from(format!("--{key}")));
+                    args.push(OsString::from(value));
+                }
+            }
+        }
+    }
+    if !found {
+        return Err(format!("no profile '{}'", profile.unwrap_or_default()));
+    }
+    Ok(args)
+}
+
+#[derive(Debug)]
+enum Value {
+    Bool(bool),
+    // Numbers are passed on as they are written
+    String(String),
+}
+
+// A value or an array of them, followed by an optional comment
+fn parse_value(s: &str) -> Option<Vec<Value>> {
+    let Some(mut rest) = s.strip_prefix('[') else {
+        let (value, rest) = parse_scalar(s)?;
+        return strip_comment(rest).is_empty().then(|| vec![value]);
+    };
+    let mut values = vec![];
+    loop {
+        rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix(']') {
+            return strip_comment(after).is_empty().then_some(values);
+        }
+        let (value, after) = parse_scalar(rest)?;
+        values.push(value);
+        rest = after.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest);
+    }
+}
+
+fn parse_scalar(s: &str) -> Option<(Value, &str)> {
+    if let Some(quoted) = s.strip_prefix('\'') {
+        let end = quoted.find('\'')?;
+        return Some((Value::String(quoted[..end].to_string()), &quoted[end + 1..]));
+    }
+    if let Some(quoted) = s.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = quoted.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Some((Value::String(value), &quoted[i + 1..])),
+                '\\' => match chars.next()?.1 {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    c => value.push(c),
+                },
+                c => value.push(c),
+            }
+        }
+        return None;
+    }
+    let end = s
+        .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#')
+        .unwrap_or(s.len());
+    let value = match &s[..end] {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        number if number.parse::<f64>().is_ok() => Value::String(number.replace('_', "")),
+        _ => return None,
+    };
+    Some((value, &s[end..]))
+}
+
+fn strip_comment(s: &str) -> &str {
+    s.split_once('#').map_or(s, |(s, _)| s).trim()
+}
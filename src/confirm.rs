@@ -0,0 +1,73 @@
+// -p/--interactive, every command is confirmed on the terminal before it is
+// started (stdin may be the list of jobs)
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    sync::{Mutex, PoisonError},
+};
+
+#[cfg(not(target_os = "windows"))]
+const INPUT: &str = "/dev/tty";
+#[cfg(not(target_os = "windows"))]
+const OUTPUT: &str = "/dev/tty";
+#[cfg(target_os = "windows")]
+const INPUT: &str = "CONIN$";
+#[cfg(target_os = "windows")]
+const OUTPUT: &str = "CONOUT$";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    Run,
+    Skip,
+    // Run no more jobs
+    Quit,
+}
+
+#[derive(Debug)]
+struct Terminal {
+    input: BufReader<File>,
+    output: File,
+    // Answered with `a(ll)`
+    all: bool,
+}
+
+// Workers ask one at a time
+#[derive(Debug)]
+pub struct Confirm(Mutex<Terminal>);
+
+impl Confirm {
+    pub fn open() -> io::Result<Self> {
+        Ok(Confirm(Mutex::new(Terminal {
+            input: BufReader::new(File::open(INPUT)?),
+            output: OpenOptions::new().write(true).open(OUTPUT)?,
+            all: false,
+        })))
+    }
+
+    pub fn ask(&self, command: &str) -> Answer {
+        let mut terminal = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if terminal.all {
+            return Answer::Run;
+        }
+        loop {
+            if write!(terminal.output, "{command}\nrun? [y/N/a(ll)/q(uit)] ").is_err() {
+                return Answer::Quit;
+            }
+            let mut line = String::new();
+            match terminal.input.read_line(&mut line) {
+                Ok(0) | Err(_) => return Answer::Quit,
+                Ok(_) => {}
+            }
+            match line.trim().to_lowercase().as_str() {
+                "y" | "yes" => return Answer::Run,
+                "" | "n" | "no" => return Answer::Skip,
+                "a" | "all" => {
+                    terminal.all = true;
+                    return Answer::Run;
+                }
+                "q" | "quit" => return Answer::Quit,
+                _ => {}
+            }
+        }
+    }
+}
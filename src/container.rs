@@ -0,0 +1,85 @@
+// Jobs run in a container (--container IMAGE), with podman or docker
+//
+// Every job gets a container of its own, removed once it is done, with the
+// current directory mounted at the same path and the variables of --env,
+// --env-file, --locale and --tz (and PARALLEL_SEQ, PARALLEL_SLOT,
+// PARALLEL_SH_ATTEMPT) passed on.
+use crate::find_program;
+use std::{
+    env,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const ENGINES: [&str; 2] = ["podman", "docker"];
+
+#[derive(Debug)]
+pub struct Container {
+    engine: PathBuf,
+    image: String,
+    // `SRC:DST[:OPTIONS]` as given to --volume
+    volumes: Vec<String>,
+    // Mounted at the same path, and the working directory by default
+    cwd: PathBuf,
+}
+
+impl Container {
+    // With `engine` or the first of podman and docker that is installed
+    pub fn new(image: String, engine: Option<&str>, volumes: Vec<String>) -> Result<Self, String> {
+        let engine = match engine {
+            Some(engine) => find_program(engine)
+                .ok_or_else(|| format!("container engine '{engine}' not found"))?,
+            None => ENGINES
+                .iter()
+                .find_map(|engine| find_program(engine))
+                .ok_or("neither podman nor docker was found")?,
+        };
+        let cwd = env::current_dir().map_err(|e| format!("no current directory: {e}"))?;
+        Ok(Container {
+            engine,
+            image,
+            volumes,
+            cwd,
+        })
+    }
+
+    // `command` is run by `shell` in the container (`sh -c` for POSIX shells)
+    // or split into words without one
+    pub fn command<'a>(
+        &self,
+        command: &str,
+        shell: Option<&[OsString]>,
+        wd: Option<&Path>,
+        vars: impl Iterator<Item = &'a str>,
+    ) -> Command {
+        let mut cmd = Command::new(&self.engine);
+        cmd.args(["run", "--rm", "--interactive", "--init"]);
+        let mut volume = OsString::from(&self.cwd);
+        volume.push(":");
+        volume.push(&self.cwd);
+        cmd.arg("--volume").arg(volume);
+        for volume in &self.volumes {
+            cmd.args(["--volume", volume]);
+        }
+        let wd = wd.map_or_else(|| self.cwd.clone(), |wd| self.cwd.join(wd));
+        // E.g. a scratch directory (--wd ...)
+        if !wd.starts_with(&self.cwd) {
+            let mut volume = OsString::from(&wd);
+            volume.push(":");
+            volume.push(&wd);
+            cmd.arg("--volume").arg(volume);
+        }
+        cmd.arg("--workdir").arg(wd);
+        // Their values are taken from the environment of the engine
+        for var in vars.chain(["PARALLEL_SEQ", "PARALLEL_SLOT", "PARALLEL_SH_ATTEMPT"]) {
+            cmd.args(["--env", var]);
+        }
+        cmd.arg(&self.image);
+        match shell {
+            Some(shell) => cmd.args(shell).arg(command),
+            None => cmd.args(crate::template::split_words(command)),
+        };
+        cmd
+    }
+}
@@ -0,0 +1,101 @@
+// Rows for --format csv and tsv, one per finished job
+//
+// CSV fields are quoted as in RFC 4180 when needed. TSV fields cannot be
+// quoted, tabs, newlines and backslashes are escaped with a backslash instead.
+
+// Longer output is cut, spreadsheets do not take more than 32767 characters
+// per cell and are hard to read long before that
+const MAX_CELL: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Separator {
+    Comma,
+    Tab,
+}
+
+pub const HEADER: [&str; 6] = ["seq", "job", "exit_code", "duration_ms", "stdout", "stderr"];
+
+fn field(value: &str, separator: Separator, out: &mut String) {
+    match separator {
+        Separator::Comma if value.contains([',', '"', '\n', '\r']) => {
+            out.push('"');
+            out.push_str(&value.replace('"', "\"\""));
+            out.push('"');
+        }
+        Separator::Comma => out.push_str(value),
+        Separator::Tab => {
+            for c in value.chars() {
+                match c {
+                    '\t' => out.push_str("\\t"),
+                    '\n' => out.push_str("\\n"),
+                    '\r' => out.push_str("\\r"),
+                    '\\' => out.push_str("\\\\"),
+                    c => out.push(c),
+                }
+            }
+        }
+    }
+}
+
+// Without the final newline
+pub fn record<S: AsRef<str>>(fields: &[S], separator: Separator) -> String {
+    let mut out = String::new();
+    for (i, value) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push(match separator {
+                Separator::Comma => ',',
+                Separator::Tab => '\t',
+            });
+        }
+        field(value.as_ref(), separator, &mut out);
+    }
+    out
+}
+
+// The output of a job as a cell, without its last newline
+pub fn cell(output: &[u8]) -> String {
+    let output = String::from_utf8_lossy(output);
+    let output = output.strip_suffix('\n').unwrap_or(&output);
+    match output.char_indices().nth(MAX_CELL) {
+        Some((end, _)) => format!("{}...", &output[..end]),
+        None => output.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_csv() {
+        assert_eq!(
+            record(
+                &["1", "echo a,b", "say \"hi\"", "two\nlines", "plain"],
+                Separator::Comma
+            ),
+            "1,\"echo a,b\",\"say \"\"hi\"\"\",\"two\nlines\",plain"
+        );
+    }
+
+    #[test]
+    fn escaped_tsv() {
+        assert_eq!(
+            record(
+                &["a\tb", "two\nlines\r", "c:\\dir", "plain"],
+                Separator::Tab
+            ),
+            "a\\tb\ttwo\\nlines\\r\tc:\\\\dir\tplain"
+        );
+    }
+
+    #[test]
+    fn cells() {
+        assert_eq!(cell(b"output\n"), "output");
+        assert_eq!(cell(b"two\nlines\n\n"), "two\nlines\n");
+        let long = "é".repeat(MAX_CELL + 1);
+        assert_eq!(
+            cell(long.as_bytes()),
+            format!("{}...", "é".repeat(MAX_CELL))
+        );
+    }
+}
@@ -0,0 +1,353 @@
+// Jobs held back until they can run
+//
+// Jobs can wait for others, `NAME: DEPS :: COMMAND` in job files, e.g.
+//   compile: :: make
+//   test: compile :: make test
+//   docs: compile :: make docs
+//
+// The name is the job's label, so a dependency on a label that several jobs
+// carry (--label-sep) waits for all of them. A job is held back until all of
+// its dependencies have finished, and skipped by the worker if any of them
+// failed or never ran.
+//
+// Jobs with the same lock (`@lock=KEY: COMMAND`, --extract-lock) are run one
+// after the other. --source-cmd jobs can share one as a group, of which more
+// than one job may run at a time ("concurrency").
+//
+// Follow-up jobs written by running jobs (--allow-enqueue) are held back as
+// well, the queue stays open until every job has finished.
+use crate::{jobctl, Job};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::SyncSender,
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Label {
+    unfinished: usize,
+    failed: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    labels: HashMap<String, Label>,
+    waiting: Vec<Job>,
+    // Locks of the jobs running, and how many of them hold each
+    locked: HashMap<String, usize>,
+    // How many jobs may hold a lock at the same time, 1 unless set
+    limits: HashMap<String, usize>,
+    input_done: bool,
+    // Jobs queued and not finished yet, which may add follow-up jobs
+    unfinished: usize,
+    follow_ups: bool,
+}
+
+impl State {
+    fn take_lock(&mut self, key: &str) -> bool {
+        let limit = self.limits.get(key).copied().unwrap_or(1);
+        let held = self.locked.entry(key.to_string()).or_default();
+        if *held >= limit {
+            return false;
+        }
+        *held += 1;
+        true
+    }
+
+    fn finished(&self, dependency: &str) -> bool {
+        match self.labels.get(dependency) {
+            Some(label) => label.unfinished == 0,
+            // It will never come
+            None => self.input_done,
+        }
+    }
+
+    // Jobs that can only wait for each other (a cycle)
+    fn stuck(&self) -> bool {
+        self.input_done
+            && self.locked.is_empty()
+            && self.labels.iter().all(|(name, label)| {
+                label.unfinished == 0
+                    || self
+                        .waiting
+                        .iter()
+                        .any(|job| job.label.as_deref() == Some(name))
+            })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Dag {
+    state: Mutex<State>,
+    changed: Condvar,
+    // The sequence number of the last job
+    seq: AtomicUsize,
+}
+
+impl Dag {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn next_seq(&self) -> usize {
+        self.seq.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    // Keep the queue open for follow-up jobs until all jobs are done
+    pub fn allow_follow_ups(&self) {
+        self.lock().follow_ups = true;
+    }
+
+    // Called for every job before it is queued
+    pub fn add(&self, label: Option<&str>) {
+        let mut state = self.lock();
+        state.unfinished += 1;
+        if let Some(label) = label {
+            state
+                .labels
+                .entry(label.to_string())
+                .or_default()
+                .unfinished += 1;
+        }
+    }
+
+    // Let `limit` jobs with the lock `key` run at the same time
+    pub fn limit(&self, key: &str, limit: usize) {
+        self.lock().limits.insert(key.to_string(), limit);
+        self.changed.notify_all();
+    }
+
+    // A job added by a running one, call before that one has finished
+    pub fn follow_up(&self, job: Job) {
+        self.add(job.label.as_deref());
+        self.hold(job);
+    }
+
+    // Queued by the dispatcher once its dependencies are done
+    pub fn hold(&self, job: Job) {
+        self.lock().waiting.push(job);
+        self.changed.notify_all();
+    }
+
+    pub fn input_done(&self) {
+        self.lock().input_done = true;
+        self.changed.notify_all();
+    }
+
+    // Also for jobs that were skipped
+    pub fn finished(&self, job: &Job, success: bool) {
+        let mut state = self.lock();
+        state.unfinished = state.unfinished.saturating_sub(1);
+        if let Some(key) = &job.lock {
+            if let Some(held) = state.locked.get_mut(key) {
+                *held -= 1;
+                if *held == 0 {
+                    state.locked.remove(key);
+                }
+            }
+        }
+        if let Some(label) = job
+            .label
+            .as_ref()
+            .and_then(|label| state.labels.get_mut(label))
+        {
+            label.unfinished = label.unfinished.saturating_sub(1);
+            label.failed |= !success;
+        }
+        drop(state);
+        self.changed.notify_all();
+    }
+
+    // A dependency that failed or never ran, if any
+    pub fn failed_dependency<'a>(&self, dependencies: &'a [String]) -> Option<&'a str> {
+        let state = self.lock();
+        dependencies
+            .iter()
+            .find(|name| {
+                state
+                    .labels
+                    .get(name.as_str())
+                    .is_none_or(|label| label.failed || label.unfinished > 0)
+            })
+            .map(String::as_str)
+    }
+
+    // Queue the jobs held back once they can run, until all input was read
+    // and no job is waiting anymore
+    pub fn dispatch(self: &Arc<Self>, tx: SyncSender<Job>) {
+        let dag = Arc::clone(self);
+        thread::spawn(move || {
+            let mut state = dag.lock();
+            while !jobctl::stopping() {
+                let mut ready = vec![];
+                for job in std::mem::take(&mut state.waiting) {
+                    if job.deps.iter().all(|dep| state.finished(dep))
+                        && job.lock.as_ref().is_none_or(|key| state.take_lock(key))
+                    {
+                        ready.push(job);
+                    } else {
+                        state.waiting.push(job);
+                    }
+                }
+                if ready.is_empty() && state.stuck() {
+                    ready = std::mem::take(&mut state.waiting);
+                }
+                if state.input_done
+                    && state.waiting.is_empty()
+                    && ready.is_empty()
+                    && (!state.follow_ups || state.unfinished == 0)
+                {
+                    break;
+                }
+                if ready.is_empty() {
+                    state = dag
+                        .changed
+                        .wait_timeout(state, POLL_INTERVAL)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                    continue;
+                }
+                drop(state);
+                for job in ready {
+                    if tx.send(job).is_err() {
+                        return;
+                    }
+                }
+                state = dag.lock();
+            }
+        });
+    }
+}
+
+// `NAME: DEPS :: COMMAND` into the name, the dependencies and the command,
+// other lines are left as they are
+pub fn split(line: String) -> (Option<String>, Vec<String>, String) {
+    let parsed = line.split_once(" :: ").and_then(|(head, command)| {
+        let (name, deps) = head.split_once(':')?;
+        let name = name.trim();
+        let valid = |name: &str| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '/'))
+        };
+        let deps: Vec<String> = deps.split_whitespace().map(str::to_string).collect();
+        (valid(name) && deps.iter().all(|dep| valid(dep)))
+            .then(|| (name.to_string(), deps, command.trim_start().to_string()))
+    });
+    match parsed {
+        Some((name, deps, command)) => (Some(name), deps, command),
+        None => (None, vec![], line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deps(deps: &[&str]) -> Vec<String> {
+        deps.iter().map(|dep| (*dep).to_string()).collect()
+    }
+
+    fn job(seq: usize, line: &str) -> Job {
+        let (label, deps, cmd) = split(String::from(line));
+        Job {
+            seq,
+            cmd,
+            label,
+            after: None,
+            limits: crate::Limits::default(),
+            args: vec![],
+            input: None,
+            priority: 0,
+            deps,
+            lock: None,
+            template: None,
+            attempt: 1,
+        }
+    }
+
+    #[test]
+    fn waits_for_dependencies() {
+        let dag = Arc::new(Dag::default());
+        let (tx, rx) = std::sync::mpsc::sync_channel(10);
+        dag.dispatch(tx);
+        for (seq, line) in [(1, "test: compile :: make test"), (2, "compile: :: make")] {
+            let job = job(seq, line);
+            dag.add(job.label.as_deref());
+            dag.hold(job);
+        }
+        dag.input_done();
+        let compile = rx.recv().unwrap();
+        assert_eq!(compile.cmd, "make");
+        assert!(rx.recv_timeout(POLL_INTERVAL * 2).is_err());
+        dag.finished(&compile, false);
+        let test = rx.recv().unwrap();
+        assert_eq!(test.cmd, "make test");
+        assert_eq!(dag.failed_dependency(&test.deps), Some("compile"));
+        dag.finished(&test, true);
+        assert!(rx.recv().is_err());
+    }
+
+    #[test]
+    fn split_lines() {
+        assert_eq!(
+            split(String::from("build: fetch lint :: make all")),
+            (
+                Some(String::from("build")),
+                deps(&["fetch", "lint"]),
+                String::from("make all")
+            )
+        );
+        assert_eq!(
+            split(String::from("fetch: :: curl -O x")),
+            (
+                Some(String::from("fetch")),
+                vec![],
+                String::from("curl -O x")
+            )
+        );
+        // Not a name, or no ` :: `
+        for line in ["echo a: b :: c", "a:b::c", "echo x", ": :: echo"] {
+            assert_eq!(
+                split(String::from(line)),
+                (None, vec![], String::from(line))
+            );
+        }
+    }
+
+    #[test]
+    fn group_concurrency() {
+        let dag = Arc::new(Dag::default());
+        let (tx, rx) = std::sync::mpsc::sync_channel(10);
+        dag.dispatch(tx);
+        dag.limit("db", 2);
+        for (seq, lock) in [(1, "db"), (2, "db"), (3, "db"), (4, "web"), (5, "web")] {
+            let mut job = job(seq, &format!("job {seq}"));
+            job.lock = Some(lock.to_string());
+            dag.add(None);
+            dag.hold(job);
+        }
+        dag.input_done();
+        let mut started: Vec<Job> = (0..3).map(|_| rx.recv().unwrap()).collect();
+        started.sort_by_key(|job| job.seq);
+        assert_eq!(
+            started.iter().map(|job| job.seq).collect::<Vec<_>>(),
+            [1, 2, 4]
+        );
+        assert!(rx.recv_timeout(POLL_INTERVAL * 2).is_err());
+        // The next web job, the db ones still wait
+        dag.finished(&started[2], true);
+        assert_eq!(rx.recv().unwrap().seq, 5);
+        assert!(rx.recv_timeout(POLL_INTERVAL * 2).is_err());
+        dag.finished(&started[0], true);
+        assert_eq!(rx.recv().unwrap().seq, 3);
+    }
+}
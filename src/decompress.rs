@@ -0,0 +1,93 @@
+// Job files compressed with gzip or zstd (--file jobs.txt.gz) are read as they
+// are decompressed, by `gzip -dc` or `zstd -dc`, recognized by their first
+// bytes and not by their name
+//
+// The file is not read twice, it may be a pipe (`-f <(...)`): the decoder
+// gets the bytes looked at as well.
+use log::error;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+    process::{Child, ChildStdout, Command, Stdio},
+    thread,
+};
+
+const GZIP: [u8; 2] = [0x1f, 0x8b];
+const ZSTD: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+pub fn open(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let mut file = BufReader::new(File::open(path)?);
+    // Not consumed, a pipe delivers what was written at once
+    let program = {
+        let magic = file.fill_buf()?;
+        if magic.starts_with(&GZIP) {
+            "gzip"
+        } else if magic.starts_with(&ZSTD) {
+            "zstd"
+        } else {
+            return Ok(Box::new(file));
+        }
+    };
+    let mut child = Command::new(program)
+        .arg("-dc")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("{program} is needed to read it: {e}")))?;
+    let (Some(mut stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        unreachable!("stdin and stdout are piped");
+    };
+    // Ends with the file, or once the decoder exits early
+    thread::spawn(move || {
+        let _ = io::copy(&mut file, &mut stdin);
+    });
+    Ok(Box::new(BufReader::new(Decoder {
+        program,
+        child,
+        stdout,
+    })))
+}
+
+struct Decoder {
+    program: &'static str,
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for Decoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stdout.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            // The jobs read so far are run anyway, a truncated file is
+            // reported at least
+            if !status.success() {
+                error!("Could not decompress job file, {} {}", self.program, status);
+                return Err(io::Error::other(format!("{} {}", self.program, status)));
+            }
+        }
+        Ok(read)
+    }
+}
+
+// Like `-f <(printf ...)`, the pipe cannot seek
+#[cfg(all(test, not(target_os = "windows")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_list_from_pipe() {
+        use std::{io::Write, os::fd::AsRawFd};
+        let (reader, mut writer) = io::pipe().unwrap();
+        writer.write_all(b"echo one\necho two\n").unwrap();
+        drop(writer);
+        let path = format!("/dev/fd/{}", reader.as_raw_fd());
+        let lines: Vec<String> = open(Path::new(&path))
+            .unwrap()
+            .lines()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(lines, ["echo one", "echo two"]);
+    }
+}
@@ -0,0 +1,214 @@
+// `parallel-sh doctor`: checks whether this system can run jobs the way
+// parallel-sh expects, and prints what to do about it
+use crate::{default_shell, shell_command};
+use std::{
+    ffi::OsString,
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+// Shell startups measured to estimate the per job overhead
+const SAMPLES: u32 = 5;
+// Per job overhead worth pointing out
+const SLOW_STARTUP: Duration = Duration::from_millis(20);
+// Open files below this limit allow only a few hundred jobs at once
+#[cfg(not(target_os = "windows"))]
+const MIN_NOFILE: u64 = 1024;
+
+#[derive(Debug, Default)]
+struct Report {
+    errors: usize,
+}
+
+impl Report {
+    fn ok(&mut self, msg: &str) {
+        println!("[ok]    {msg}");
+    }
+
+    fn warn(&mut self, msg: &str) {
+        println!("[warn]  {msg}");
+    }
+
+    fn error(&mut self, msg: &str) {
+        self.errors += 1;
+        println!("[error] {msg}");
+    }
+}
+
+// Handles `parallel-sh doctor [--shell SHELL [--shell-arg FLAG]... | --no-shell]`,
+// returns the exit code if it was called that way
+pub fn subcommand() -> Option<i32> {
+    let mut args = std::env::args_os().skip(1);
+    if args.next().as_deref() != Some("doctor".as_ref()) {
+        return None;
+    }
+    let mut shell = Some(default_shell());
+    let mut shell_args = vec![];
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-s" | "--shell") => shell = args.next(),
+            Some("--shell-arg") => shell_args.extend(args.next()),
+            Some("--no-shell") => shell = None,
+            _ => {
+                eprintln!("ERROR: unexpected argument '{}'", arg.to_string_lossy());
+                return Some(1);
+            }
+        }
+    }
+
+    let mut report = Report::default();
+    check_shell(&mut report, &shell, &shell_args);
+    #[cfg(not(target_os = "windows"))]
+    check_limits(&mut report);
+    #[cfg(not(target_os = "windows"))]
+    check_kill(&mut report);
+    Some(i32::from(report.errors > 0))
+}
+
+// Average time to start `shell` and run an empty command
+fn startup_time(
+    shell: &Option<OsString>,
+    shell_args: &[OsString],
+    no_profile: bool,
+) -> Result<Duration, String> {
+    let start = Instant::now();
+    for _ in 0..SAMPLES {
+        let output = shell_command("echo parallel-sh", shell, shell_args, no_profile)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("could not be started: {e}"))?;
+        if !output.status.success() {
+            return Err(format!("failed with {}", output.status));
+        }
+        if String::from_utf8_lossy(&output.stdout).trim() != "parallel-sh" {
+            return Err(String::from("did not run the command it was given"));
+        }
+    }
+    Ok(start.elapsed() / SAMPLES)
+}
+
+fn check_shell(report: &mut Report, shell: &Option<OsString>, shell_args: &[OsString]) {
+    let name = shell.as_ref().map_or_else(
+        || String::from("none (--no-shell)"),
+        |s| s.to_string_lossy().into_owned(),
+    );
+    match startup_time(shell, shell_args, false) {
+        Ok(time) => {
+            report.ok(&format!("shell '{name}' works, {time:.1?} per job"));
+            if time > SLOW_STARTUP && shell.is_some() {
+                match startup_time(shell, shell_args, true) {
+                    Ok(fast) if fast < time * 3 / 4 => report.warn(&format!(
+                        "shell '{name}' takes {fast:.1?} per job with --shell-no-profile"
+                    )),
+                    _ => report.warn(&format!(
+                        "shell '{name}' is slow to start, consider a lighter shell or --no-shell"
+                    )),
+                }
+            }
+        }
+        Err(e) => report.error(&format!(
+            "shell '{name}' {e}, choose another one with --shell"
+        )),
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn check_limits(report: &mut Report) {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: limit is a valid out pointer
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        report.warn("could not read the open files limit");
+        return;
+    }
+    #[allow(clippy::useless_conversion)]
+    let (soft, hard) = (u64::from(limit.rlim_cur), u64::from(limit.rlim_max));
+    // Every running job needs a pipe each for stdout and stderr
+    let jobs = soft / 4;
+    if soft < MIN_NOFILE && hard > soft {
+        report.warn(&format!(
+            "open files limit is {soft}, enough for about {jobs} parallel jobs (raise it with ulimit -n {hard})"
+        ));
+    } else if soft < MIN_NOFILE {
+        report.warn(&format!(
+            "open files limit is {soft}, enough for about {jobs} parallel jobs (the hard limit has to be raised first)"
+        ));
+    } else {
+        report.ok(&format!(
+            "open files limit is {soft}, enough for about {jobs} parallel jobs"
+        ));
+    }
+}
+
+// Start a job which starts a process of its own, stop the job and check
+// that both are gone
+#[cfg(not(target_os = "windows"))]
+fn check_kill(report: &mut Report) {
+    use crate::jobctl;
+    use std::io::{BufRead, BufReader};
+
+    let mut cmd = shell_command(
+        "sleep 30 & echo $!; wait",
+        &Some(OsString::from("sh")),
+        &[],
+        false,
+    );
+    cmd.stdin(Stdio::null()).stdout(Stdio::piped());
+    jobctl::configure(&mut cmd, true);
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            report.error(&format!("could not start a test job: {e}"));
+            return;
+        }
+    };
+    jobctl::register(&child, true);
+    let mut line = String::new();
+    if let Some(stdout) = child.stdout.take() {
+        let _ = BufReader::new(stdout).read_line(&mut line);
+    }
+    // Like a worker, which unregisters the job once it exited
+    let pid = child.id();
+    let waiter = std::thread::spawn(move || {
+        let _ = child.wait();
+        jobctl::unregister(pid);
+    });
+    let start = Instant::now();
+    jobctl::terminate_all();
+    let _ = waiter.join();
+    let stopped_in = start.elapsed();
+
+    let alive = |pid: libc::pid_t| {
+        // SAFETY: signal 0 only checks whether the process exists
+        let exists = unsafe { libc::kill(pid, 0) == 0 };
+        // Zombies are dead, just not reaped yet
+        exists
+            && !std::fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| {
+                stat.rsplit(')')
+                    .next()
+                    .is_some_and(|s| s.trim_start().starts_with('Z'))
+            })
+    };
+    let grandchild = line.trim().parse::<libc::pid_t>().ok();
+    // The grandchild is reaped by init, which may take a moment
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while grandchild.is_some_and(alive) && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    match grandchild {
+        None => report.warn("could not test stopping jobs, the test job did not report its child"),
+        Some(pid) if alive(pid) => {
+            // SAFETY: see above, the leftover process is not ours to keep
+            unsafe {
+                libc::kill(pid, libc::SIGKILL);
+            }
+            report.error("processes started by a job survived stopping the job");
+        }
+        Some(_) if stopped_in > Duration::from_secs(1) => report.warn(&format!(
+            "stopping a job took {stopped_in:.1?}, it had to be killed"
+        )),
+        Some(_) => report.ok("jobs and the processes they start are stopped together"),
+    }
+}
@@ -0,0 +1,189 @@
+// Environment files (--env-file) and the `env record`/`env diff` helpers
+//
+// `parallel-sh env record FILE` stores the current environment, e.g. of a
+// clean login shell. Later, `parallel-sh env diff FILE` prints everything
+// that was added or changed since, ready to be used with --env-file.
+// Shell functions are included once exported (`export -f` in bash).
+use log::warn;
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+};
+
+fn unquote(value: &str) -> String {
+    if let Some(quoted) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        let mut unescaped = String::with_capacity(quoted.len());
+        let mut chars = quoted.chars();
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                match chars.next() {
+                    Some('n') => unescaped.push('\n'),
+                    Some(c) => unescaped.push(c),
+                    None => unescaped.push('\\'),
+                }
+            } else {
+                unescaped.push(c);
+            }
+        }
+        unescaped
+    } else if let Some(quoted) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        quoted.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+pub fn read_file(path: &Path) -> Result<Vec<(String, String)>, io::Error> {
+    let file = File::open(path)?;
+    let mut vars = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, value)) = line.split_once('=') else {
+            warn!(
+                "Ignoring malformed line in '{}': '{}'",
+                path.display(),
+                line
+            );
+            continue;
+        };
+        vars.push((key.trim().to_string(), unquote(value.trim())));
+    }
+    Ok(vars)
+}
+
+fn current() -> BTreeMap<String, String> {
+    std::env::vars_os()
+        .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+        .collect()
+}
+
+fn record(path: &Path) -> Result<(), io::Error> {
+    let mut file = File::create(path)?;
+    for (key, value) in current() {
+        writeln!(file, "{key}={}", quote(&value))?;
+    }
+    Ok(())
+}
+
+fn diff(path: &Path) -> Result<(), io::Error> {
+    let recorded: BTreeMap<_, _> = read_file(path)?.into_iter().collect();
+    let current = current();
+    let mut out = io::stdout().lock();
+    for (key, value) in &current {
+        if recorded.get(key) != Some(value) {
+            writeln!(out, "{key}={}", quote(value))?;
+        }
+    }
+    for key in recorded.keys().filter(|k| !current.contains_key(*k)) {
+        writeln!(out, "# unset {key}")?;
+    }
+    Ok(())
+}
+
+// Handles `parallel-sh env ...`, returns the exit code if it was called that way
+pub fn subcommand() -> Option<i32> {
+    let args: Vec<OsString> = std::env::args_os().skip(1).take(4).collect();
+    if args.first().map(OsString::as_os_str) != Some("env".as_ref()) {
+        return None;
+    }
+    let result = match (
+        args.get(1).and_then(|a| a.to_str()),
+        args.get(2),
+        args.len(),
+    ) {
+        (Some("record"), Some(file), 3) => record(Path::new(file)),
+        (Some("diff"), Some(file), 3) => diff(Path::new(file)),
+        _ => return None,
+    };
+    Some(match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("ERROR: {e}");
+            1
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(name: &str, text: &str) -> Vec<(String, String)> {
+        let path =
+            std::env::temp_dir().join(format!("parallel-sh-{}-{}", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        let vars = read_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        vars
+    }
+
+    fn pairs(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn quoting() {
+        let vars = read(
+            "quoting",
+            "A=plain\nB=\"two\\nlines \\\"quoted\\\" \\\\\"\nC='single $HOME \\n'\nD = spaced \n",
+        );
+        assert_eq!(
+            vars,
+            pairs(&[
+                ("A", "plain"),
+                ("B", "two\nlines \"quoted\" \\"),
+                ("C", "single $HOME \\n"),
+                ("D", "spaced"),
+            ])
+        );
+    }
+
+    #[test]
+    fn export_and_comments() {
+        let vars = read(
+            "export",
+            "# a comment\n\n  export PATH=/bin\nexport  X=1\nEXPORTED=yes\n",
+        );
+        assert_eq!(
+            vars,
+            pairs(&[("PATH", "/bin"), ("X", "1"), ("EXPORTED", "yes")])
+        );
+    }
+
+    #[test]
+    fn malformed_lines() {
+        let vars = read("malformed", "no equals sign\nA=1\nexport\nB==2\n");
+        assert_eq!(vars, pairs(&[("A", "1"), ("B", "=2")]));
+    }
+
+    #[test]
+    fn quote_roundtrip() {
+        let value = "a \"b\" \\c\nd";
+        assert_eq!(unquote(&quote(value)), value);
+    }
+}
@@ -0,0 +1,111 @@
+// Job durations of an earlier run, for --schedule sjf and ljf: taken from the
+// --joblog or --results-db it left behind, before they are written again
+//
+// A job is recognized by its command, the latest duration of it is used.
+use crate::joblog;
+use log::{debug, warn};
+use std::{collections::HashMap, fs, io, path::Path, process::Command, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Fifo,
+    ShortestFirst,
+    LongestFirst,
+}
+
+impl std::str::FromStr for Order {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fifo" => Ok(Order::Fifo),
+            "sjf" => Ok(Order::ShortestFirst),
+            "ljf" => Ok(Order::LongestFirst),
+            _ => Err(format!("unknown schedule '{s}', expected fifo, sjf or ljf")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Schedule {
+    order: Order,
+    durations: HashMap<String, Duration>,
+}
+
+impl Schedule {
+    // Files that do not exist yet are skipped, the first run has no history
+    pub fn load(order: Order, joblog: Option<&Path>, results_db: Option<&Path>) -> Self {
+        let mut durations = HashMap::new();
+        if let Some(path) = joblog.filter(|path| path.exists()) {
+            match fs::read_to_string(path) {
+                Ok(log) => {
+                    durations.extend(joblog::read(&log).map(|entry| (entry.command, entry.runtime)))
+                }
+                Err(e) => warn!("Could not read job log '{}': {}", path.display(), e),
+            }
+        }
+        if let Some(path) = results_db.filter(|path| path.exists()) {
+            match results_db_durations(path) {
+                Ok(known) => durations.extend(known),
+                Err(e) => warn!(
+                    "Could not read job durations from '{}': {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        if durations.is_empty() {
+            warn!("No earlier durations of the jobs known, they are run in input order");
+        } else {
+            debug!("Known durations of {} job(s)", durations.len());
+        }
+        Schedule { order, durations }
+    }
+
+    // Known jobs first, jobs without a duration after them in input order
+    pub fn sort<T>(&self, jobs: &mut [T], command: impl Fn(&T) -> &str) {
+        if self.order == Order::Fifo {
+            return;
+        }
+        jobs.sort_by_cached_key(|job| {
+            let duration = self.durations.get(command(job));
+            (
+                duration.is_none(),
+                match (duration, self.order) {
+                    (Some(duration), Order::LongestFirst) => Duration::MAX - *duration,
+                    (Some(duration), _) => *duration,
+                    (None, _) => Duration::ZERO,
+                },
+            )
+        });
+    }
+}
+
+// The commands as hex, they may contain any character
+fn results_db_durations(path: &Path) -> io::Result<Vec<(String, Duration)>> {
+    let output = Command::new("sqlite3")
+        .args(["-batch", "-readonly"])
+        .arg(path)
+        .arg("SELECT hex(command), end_time - start_time FROM jobs ORDER BY end_time;")
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("sqlite3 is needed: {e}")))?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (hex, seconds) = line.split_once('|')?;
+            let command = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+            Some((
+                String::from_utf8(command).ok()?,
+                Duration::try_from_secs_f64(seconds.parse().ok()?).ok()?,
+            ))
+        })
+        .collect())
+}
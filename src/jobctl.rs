@@ -0,0 +1,749 @@
+// Bookkeeping of running jobs, so they never outlive parallel-sh
+//
+// On Unix every job leads its own process group, which is signalled as a
+// whole, and the direct child gets a parent-death signal on Linux. Fatal
+// signals and panics stop all jobs before parallel-sh goes away. On Windows
+// all jobs belong to one Job Object that kills them once parallel-sh exits,
+// and every job to one of its own, so it is killed with all its processes.
+use log::{debug, warn};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    process::{self, Child, Command},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Mutex, OnceLock, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Time running jobs get to exit after being asked to stop
+const GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+// Period of the stop/continue cycle used by throttle()
+#[cfg(not(target_os = "windows"))]
+const THROTTLE_PERIOD: Duration = Duration::from_millis(100);
+
+// Set while a fatal signal is being handled
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+// Set once no new jobs should be started
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+// Set by the first Ctrl-C, which lets running jobs finish
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Set by SIGQUIT or `abort` on the --listen socket, all jobs are killed
+static ABORTED_RUN: AtomicBool = AtomicBool::new(false);
+
+// Running jobs by pid, whether they lead their own process group and when
+// they were started, counting up
+static RUNNING: Mutex<BTreeMap<u32, (bool, u64)>> = Mutex::new(BTreeMap::new());
+static STARTED: AtomicU64 = AtomicU64::new(0);
+
+// Prints the state of the run on SIGUSR1 (SIGINFO on BSD)
+static REPORT: OnceLock<Box<dyn Fn() + Send + Sync>> = OnceLock::new();
+
+// Freezes the running jobs when parallel-sh is stopped (SIGTSTP), and thaws
+// them once it is continued (--freeze)
+#[cfg(not(target_os = "windows"))]
+static FREEZE: OnceLock<Box<dyn Fn(bool) + Send + Sync>> = OnceLock::new();
+
+// Jobs killed by evict_youngest() that have not been noticed yet
+static EVICTED: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+
+// Jobs stopped by suspend_youngest(), in the order they were stopped
+static SUSPENDED: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+fn running() -> Vec<(u32, bool)> {
+    RUNNING
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .map(|(&pid, &(group, _))| (pid, group))
+        .collect()
+}
+
+pub fn register(child: &Child, group: bool) {
+    sys::adopt(child);
+    let started = STARTED.fetch_add(1, Ordering::SeqCst);
+    RUNNING
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(child.id(), (group, started));
+}
+
+pub fn unregister(pid: u32) {
+    RUNNING
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .remove(&pid);
+    SUSPENDED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .retain(|&suspended| suspended != pid);
+    sys::release(pid);
+}
+
+// Kill the job started last to free its memory, unless it is the only one.
+// Returns its pid.
+pub fn evict_youngest() -> Option<u32> {
+    let running = RUNNING.lock().unwrap_or_else(PoisonError::into_inner);
+    if running.len() < 2 {
+        return None;
+    }
+    let (&pid, &(group, _)) = running.iter().max_by_key(|(_, (_, started))| *started)?;
+    EVICTED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(pid);
+    sys::kill(pid, group);
+    Some(pid)
+}
+
+// Stop the youngest job that still runs until resume_suspended(), unless it is
+// the only one. Returns its pid.
+#[cfg(not(target_os = "windows"))]
+pub fn suspend_youngest() -> Option<u32> {
+    let running = RUNNING.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut suspended = SUSPENDED.lock().unwrap_or_else(PoisonError::into_inner);
+    if running.len() < suspended.len() + 2 {
+        return None;
+    }
+    let (&pid, &(group, _)) = running
+        .iter()
+        .filter(|(pid, _)| !suspended.contains(pid))
+        .max_by_key(|(_, (_, started))| *started)?;
+    suspended.push(pid);
+    sys::pause(pid, group);
+    Some(pid)
+}
+
+// Continue the job suspended last. Returns its pid.
+#[cfg(not(target_os = "windows"))]
+pub fn resume_suspended() -> Option<u32> {
+    let running = RUNNING.lock().unwrap_or_else(PoisonError::into_inner);
+    let pid = SUSPENDED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .pop()?;
+    if let Some(&(group, _)) = running.get(&pid) {
+        sys::resume(pid, group);
+    }
+    Some(pid)
+}
+
+// True if no job runs but suspended ones, one of them has to go on
+#[cfg(not(target_os = "windows"))]
+pub fn only_suspended() -> bool {
+    let running = RUNNING.lock().unwrap_or_else(PoisonError::into_inner);
+    let suspended = SUSPENDED.lock().unwrap_or_else(PoisonError::into_inner);
+    !suspended.is_empty() && running.len() == suspended.len()
+}
+
+pub fn idle() -> bool {
+    RUNNING
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .is_empty()
+}
+
+pub fn suspended() -> bool {
+    !SUSPENDED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .is_empty()
+}
+
+// Call once the job has exited, true if it was killed by evict_youngest()
+pub fn evicted(pid: u32) -> bool {
+    EVICTED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .remove(&pid)
+}
+
+fn wait_for_exit(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if running().is_empty() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    running().is_empty()
+}
+
+// Stops a single job once it runs longer than its timeout (--timeout)
+#[derive(Debug)]
+pub struct Watchdog {
+    done: mpsc::Sender<()>,
+    thread: thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    pub fn start(pid: u32, group: bool, timeout: Duration) -> Self {
+        let (done, finished) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            if finished.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+                return false;
+            }
+            sys::stop(pid, group);
+            if finished.recv_timeout(GRACE_PERIOD) == Err(RecvTimeoutError::Timeout) {
+                sys::kill(pid, group);
+            }
+            true
+        });
+        Watchdog { done, thread }
+    }
+
+    // Call once the job has exited, true if it was stopped
+    pub fn finish(self) -> bool {
+        drop(self.done);
+        self.thread.join().unwrap_or(false)
+    }
+}
+
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub fn aborted() -> bool {
+    ABORTED_RUN.load(Ordering::SeqCst)
+}
+
+// Kill all jobs at once and start no new ones, the run then ends as usual:
+// the results of the killed jobs are written and the summary is printed
+pub fn abort() {
+    ABORTED_RUN.store(true, Ordering::SeqCst);
+    STOPPING.store(true, Ordering::SeqCst);
+    let jobs = running();
+    jobs.iter().for_each(|&(pid, group)| sys::kill(pid, group));
+    warn!("Aborted, killed {} running job(s)", jobs.len());
+}
+
+// Start no new jobs, the running ones are left to finish
+pub fn stop_starting() {
+    STOPPING.store(true, Ordering::SeqCst);
+}
+
+// Stop starting new jobs, false if this happened before already
+fn interrupt() -> bool {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    stop_starting();
+    warn!(
+        "Interrupted, waiting for {} running job(s) to finish (press Ctrl-C again to stop them)",
+        running().len()
+    );
+    true
+}
+
+// Ask all running jobs to stop, and kill the ones that do not within the grace period.
+// No new jobs are started afterwards.
+pub fn terminate_all() {
+    STOPPING.store(true, Ordering::SeqCst);
+    let jobs = running();
+    if jobs.is_empty() {
+        return;
+    }
+    debug!("Stopping {} running job(s)", jobs.len());
+    jobs.iter().for_each(|&(pid, group)| sys::stop(pid, group));
+    if !wait_for_exit(GRACE_PERIOD) {
+        running()
+            .iter()
+            .for_each(|&(pid, group)| sys::kill(pid, group));
+    }
+}
+
+// Exit status after an abort
+pub const ABORTED: i32 = 254;
+
+// Kill whatever is still running and exit
+pub fn exit(code: i32) -> ! {
+    // Leave it to the signal handler, which exits with the right status
+    while SIGNALLED.load(Ordering::SeqCst) {
+        thread::park();
+    }
+    running()
+        .iter()
+        .for_each(|&(pid, group)| sys::kill(pid, group));
+    process::exit(code);
+}
+
+// Limit every running job to `quota` percent of a CPU by stopping and
+// continuing it, for systems where cgroups cannot be used
+#[cfg(not(target_os = "windows"))]
+pub fn throttle(quota: f64) {
+    let fraction = quota / 100.0;
+    if fraction >= 1.0 {
+        debug!("A CPU quota of {}% needs no throttling", quota);
+        return;
+    }
+    let running_time = THROTTLE_PERIOD.mul_f64(fraction);
+    let stopped_time = THROTTLE_PERIOD - running_time;
+    thread::spawn(move || loop {
+        thread::sleep(running_time);
+        // Jobs suspended for lack of memory stay stopped
+        let mut jobs = running();
+        let suspended = SUSPENDED.lock().unwrap_or_else(PoisonError::into_inner);
+        jobs.retain(|(pid, _)| !suspended.contains(pid));
+        drop(suspended);
+        jobs.iter().for_each(|&(pid, group)| sys::pause(pid, group));
+        thread::sleep(stopped_time);
+        jobs.iter()
+            .for_each(|&(pid, group)| sys::resume(pid, group));
+    });
+}
+
+// Stop or continue every running job with SIGSTOP and SIGCONT, for --freeze
+// without cgroups. Jobs suspended for lack of memory stay stopped.
+#[cfg(not(target_os = "windows"))]
+pub fn pause_all(paused: bool) {
+    let mut jobs = running();
+    let suspended = SUSPENDED.lock().unwrap_or_else(PoisonError::into_inner);
+    jobs.retain(|(pid, _)| !suspended.contains(pid));
+    drop(suspended);
+    for (pid, group) in jobs {
+        if paused {
+            sys::pause(pid, group);
+        } else {
+            sys::resume(pid, group);
+        }
+    }
+}
+
+// Prepare a job's command, `group` puts it into its own process group
+pub fn configure(cmd: &mut Command, group: bool) {
+    sys::configure(cmd, group);
+}
+
+// Needed for every process parallel-sh starts
+pub fn unblock_signals(cmd: &mut Command) {
+    sys::unblock_signals(cmd);
+}
+
+// Must be called before any other thread is started
+pub fn on_report(report: impl Fn() + Send + Sync + 'static) {
+    let _ = REPORT.set(Box::new(report));
+}
+
+// Must be called before any job is started
+#[cfg(not(target_os = "windows"))]
+pub fn on_stop(freeze: impl Fn(bool) + Send + Sync + 'static) {
+    let _ = FREEZE.set(Box::new(freeze));
+}
+
+#[cfg(not(target_os = "windows"))]
+fn freeze(frozen: bool) {
+    if let Some(freeze) = FREEZE.get() {
+        freeze(frozen);
+    }
+}
+
+pub fn install_handlers() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        running()
+            .iter()
+            .for_each(|&(pid, group)| sys::kill(pid, group));
+        default_hook(info);
+    }));
+    sys::install_handlers();
+}
+
+#[cfg(not(target_os = "windows"))]
+mod sys {
+    use log::debug;
+    use std::{
+        os::unix::process::CommandExt,
+        process::{Child, Command},
+        sync::atomic::Ordering,
+        thread,
+    };
+
+    const FATAL_SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+    // Kills all jobs right away, without a grace period
+    const ABORT_SIGNAL: libc::c_int = libc::SIGQUIT;
+    // Stop and continue parallel-sh, along with the jobs with --freeze
+    const STOP_SIGNALS: [libc::c_int; 2] = [libc::SIGTSTP, libc::SIGCONT];
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly"
+    ))]
+    const REPORT_SIGNALS: [libc::c_int; 2] = [libc::SIGUSR1, libc::SIGINFO];
+    #[cfg(not(any(
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly"
+    )))]
+    const REPORT_SIGNALS: [libc::c_int; 1] = [libc::SIGUSR1];
+
+    #[allow(clippy::cast_possible_wrap)]
+    fn signal(pid: u32, group: bool, signal: libc::c_int) {
+        let pid = pid as libc::pid_t;
+        // SAFETY: sending a signal has no memory safety implications
+        unsafe {
+            libc::kill(if group { -pid } else { pid }, signal);
+        }
+    }
+
+    pub fn stop(pid: u32, group: bool) {
+        signal(pid, group, libc::SIGTERM);
+        // A throttled job only sees SIGTERM once it is continued
+        signal(pid, group, libc::SIGCONT);
+    }
+
+    pub fn pause(pid: u32, group: bool) {
+        signal(pid, group, libc::SIGSTOP);
+    }
+
+    pub fn resume(pid: u32, group: bool) {
+        signal(pid, group, libc::SIGCONT);
+    }
+
+    pub fn kill(pid: u32, group: bool) {
+        signal(pid, group, libc::SIGKILL);
+    }
+
+    pub fn adopt(_child: &Child) {}
+
+    pub fn release(_pid: u32) {}
+
+    pub fn configure(cmd: &mut Command, group: bool) {
+        // SAFETY: getpid(2) is always safe to call
+        let parent = unsafe { libc::getpid() };
+        // SAFETY: only async-signal-safe functions are called after fork
+        unsafe {
+            cmd.pre_exec(move || {
+                if group {
+                    libc::setpgid(0, 0);
+                }
+                #[cfg(target_os = "linux")]
+                {
+                    libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+                    // parallel-sh might have died before prctl()
+                    if libc::getppid() != parent {
+                        libc::_exit(1);
+                    }
+                }
+                #[cfg(not(target_os = "linux"))]
+                let _ = parent;
+                Ok(())
+            });
+        }
+    }
+
+    // The signal mask survives exec, so processes started by parallel-sh
+    // have to unblock the signals it handles in its own thread
+    pub fn unblock_signals(cmd: &mut Command) {
+        let set = signal_set();
+        // SAFETY: pthread_sigmask(3) is async-signal-safe, set is valid
+        unsafe {
+            cmd.pre_exec(move || {
+                libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+                Ok(())
+            });
+        }
+    }
+
+    fn signal_set() -> libc::sigset_t {
+        // SAFETY: the set is initialized by sigemptyset before use
+        unsafe {
+            let mut set = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for sig in FATAL_SIGNALS {
+                libc::sigaddset(&mut set, sig);
+            }
+            libc::sigaddset(&mut set, ABORT_SIGNAL);
+            for sig in STOP_SIGNALS.into_iter().chain(REPORT_SIGNALS) {
+                libc::sigaddset(&mut set, sig);
+            }
+            set
+        }
+    }
+
+    // Fatal signals are blocked in all threads and handled by a dedicated
+    // thread, which is free to take locks while stopping the jobs
+    pub fn install_handlers() {
+        let set = signal_set();
+        // SAFETY: set is a valid, initialized signal set
+        unsafe {
+            libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        }
+        thread::spawn(move || {
+            let mut sig = 0;
+            loop {
+                // SAFETY: set is valid and sig is a valid out pointer
+                if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+                    return;
+                }
+                if REPORT_SIGNALS.contains(&sig) {
+                    if let Some(report) = super::REPORT.get() {
+                        report();
+                    }
+                    continue;
+                }
+                if sig == ABORT_SIGNAL {
+                    super::abort();
+                    continue;
+                }
+                if sig == libc::SIGTSTP {
+                    super::freeze(true);
+                    // What SIGTSTP does by default, SIGSTOP cannot be blocked
+                    // SAFETY: raising a signal has no memory safety implications
+                    unsafe {
+                        libc::raise(libc::SIGSTOP);
+                    }
+                    continue;
+                }
+                if sig == libc::SIGCONT {
+                    super::freeze(false);
+                    continue;
+                }
+                // The first Ctrl-C only stops new jobs from being started
+                if sig != libc::SIGINT || !super::interrupt() {
+                    break;
+                }
+            }
+            debug!("Received signal {}, stopping all jobs", sig);
+            super::SIGNALLED.store(true, Ordering::SeqCst);
+            super::terminate_all();
+            // SAFETY: restore the default action and deliver the signal again,
+            // so parallel-sh terminates the way the sender expects
+            unsafe {
+                libc::signal(sig, libc::SIG_DFL);
+                libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+                libc::raise(sig);
+            }
+            std::process::exit(128 + sig);
+        });
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod sys {
+    use std::{
+        collections::BTreeMap,
+        ffi::c_void,
+        os::windows::{io::AsRawHandle, process::CommandExt},
+        process::{Child, Command},
+        sync::{Mutex, OnceLock, PoisonError},
+    };
+
+    const CTRL_C_EVENT: u32 = 0;
+    const CTRL_BREAK_EVENT: u32 = 1;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+    const PROCESS_TERMINATE: u32 = 0x0001;
+    const JOB_OBJECT_EXTENDED_LIMIT_INFORMATION_CLASS: i32 = 9;
+    const JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE: u32 = 0x0000_2000;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct BasicLimitInformation {
+        per_process_user_time_limit: i64,
+        per_job_user_time_limit: i64,
+        limit_flags: u32,
+        minimum_working_set_size: usize,
+        maximum_working_set_size: usize,
+        active_process_limit: u32,
+        affinity: usize,
+        priority_class: u32,
+        scheduling_class: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct IoCounters {
+        read_operation_count: u64,
+        write_operation_count: u64,
+        other_operation_count: u64,
+        read_transfer_count: u64,
+        write_transfer_count: u64,
+        other_transfer_count: u64,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct ExtendedLimitInformation {
+        basic_limit_information: BasicLimitInformation,
+        io_info: IoCounters,
+        process_memory_limit: usize,
+        job_memory_limit: usize,
+        peak_process_memory_used: usize,
+        peak_job_memory_used: usize,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GenerateConsoleCtrlEvent(ctrl_event: u32, process_group_id: u32) -> i32;
+        fn SetConsoleCtrlHandler(
+            handler: Option<unsafe extern "system" fn(u32) -> i32>,
+            add: i32,
+        ) -> i32;
+        fn OpenProcess(desired_access: u32, inherit_handle: i32, process_id: u32) -> *mut c_void;
+        fn TerminateProcess(process: *mut c_void, exit_code: u32) -> i32;
+        fn CloseHandle(object: *mut c_void) -> i32;
+        fn CreateJobObjectW(attributes: *mut c_void, name: *const u16) -> *mut c_void;
+        fn SetInformationJobObject(
+            job: *mut c_void,
+            class: i32,
+            info: *const c_void,
+            length: u32,
+        ) -> i32;
+        fn AssignProcessToJobObject(job: *mut c_void, process: *mut c_void) -> i32;
+        fn TerminateJobObject(job: *mut c_void, exit_code: u32) -> i32;
+    }
+
+    struct JobObject(*mut c_void);
+
+    impl JobObject {
+        fn new(kill_on_close: bool) -> Option<Self> {
+            // SAFETY: the handle is checked, info outlives the call
+            unsafe {
+                let job = CreateJobObjectW(std::ptr::null_mut(), std::ptr::null());
+                if job.is_null() {
+                    return None;
+                }
+                if kill_on_close {
+                    let mut info = ExtendedLimitInformation::default();
+                    info.basic_limit_information.limit_flags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+                    #[allow(clippy::cast_possible_truncation)]
+                    let length = std::mem::size_of::<ExtendedLimitInformation>() as u32;
+                    SetInformationJobObject(
+                        job,
+                        JOB_OBJECT_EXTENDED_LIMIT_INFORMATION_CLASS,
+                        std::ptr::addr_of!(info).cast(),
+                        length,
+                    );
+                }
+                Some(JobObject(job))
+            }
+        }
+
+        fn assign(&self, child: &Child) -> bool {
+            // SAFETY: both handles are valid while child is alive
+            unsafe { AssignProcessToJobObject(self.0, child.as_raw_handle().cast()) != 0 }
+        }
+    }
+
+    impl Drop for JobObject {
+        fn drop(&mut self) {
+            // SAFETY: the handle is owned and not used afterwards
+            unsafe {
+                CloseHandle(self.0);
+            }
+        }
+    }
+
+    // SAFETY: a job object handle may be used from any thread
+    unsafe impl Send for JobObject {}
+    // SAFETY: see above
+    unsafe impl Sync for JobObject {}
+
+    // Never closed explicitly: Windows closes it when parallel-sh exits,
+    // which kills every process still assigned to it
+    static JOB_OBJECT: OnceLock<Option<JobObject>> = OnceLock::new();
+
+    // Every job also gets a Job Object of its own, nested in the one above,
+    // so the processes it started are killed along with it, like a process
+    // group on Unix
+    static JOBS: Mutex<BTreeMap<u32, JobObject>> = Mutex::new(BTreeMap::new());
+
+    fn job_object() -> Option<&'static JobObject> {
+        JOB_OBJECT.get_or_init(|| JobObject::new(true)).as_ref()
+    }
+
+    // Every job is the root of its own process group, which is the unit
+    // CTRL_BREAK_EVENT can be delivered to
+    pub fn stop(pid: u32, group: bool) {
+        if group {
+            // SAFETY: plain Win32 call without pointers
+            unsafe {
+                GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, pid);
+            }
+        }
+    }
+
+    pub fn kill(pid: u32, _group: bool) {
+        if let Some(job) = JOBS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&pid)
+        {
+            // SAFETY: the handle is valid until the job is released
+            if unsafe { TerminateJobObject(job.0, 1) } != 0 {
+                return;
+            }
+        }
+        // SAFETY: the handle is checked before use and closed afterwards
+        unsafe {
+            let handle = OpenProcess(PROCESS_TERMINATE, 0, pid);
+            if !handle.is_null() {
+                TerminateProcess(handle, 1);
+                CloseHandle(handle);
+            }
+        }
+    }
+
+    pub fn adopt(child: &Child) {
+        if let Some(job) = job_object() {
+            job.assign(child);
+        }
+        // Nested Job Objects need Windows 8, without them only the job itself
+        // is killed
+        if let Some(job) = JobObject::new(false).filter(|job| job.assign(child)) {
+            JOBS.lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(child.id(), job);
+        }
+    }
+
+    // Processes the job left behind keep running, as on Unix
+    pub fn release(pid: u32) {
+        JOBS.lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&pid);
+    }
+
+    pub fn configure(cmd: &mut Command, group: bool) {
+        if group {
+            cmd.creation_flags(CREATE_NEW_PROCESS_GROUP);
+        }
+    }
+
+    pub fn unblock_signals(_cmd: &mut Command) {}
+
+    // Jobs in their own process group do not see Ctrl-C on the console anymore,
+    // so pass it on as Ctrl-Break before parallel-sh itself exits. The first
+    // Ctrl-C only stops new jobs from being started.
+    unsafe extern "system" fn forward_ctrl(ctrl_type: u32) -> i32 {
+        if ctrl_type == CTRL_C_EVENT && super::interrupt() {
+            return 1;
+        }
+        if ctrl_type == CTRL_C_EVENT || ctrl_type == CTRL_BREAK_EVENT {
+            super::running()
+                .iter()
+                .for_each(|&(pid, group)| stop(pid, group));
+        }
+        0
+    }
+
+    pub fn install_handlers() {
+        // SAFETY: forward_ctrl is a valid handler for the whole process lifetime
+        unsafe {
+            SetConsoleCtrlHandler(Some(forward_ctrl), 1);
+        }
+    }
+}
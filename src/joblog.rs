@@ -0,0 +1,115 @@
+// Job log in the format of GNU parallel's --joblog, so tools written for it
+// can read ours
+use crate::{signal, JobResult};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+const HEADER: &str = "Seq\tHost\tStarttime\tJobRuntime\tSend\tReceive\tExitval\tSignal\tCommand";
+
+#[derive(Debug)]
+pub struct JobLog(BufWriter<File>);
+
+impl JobLog {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut log = BufWriter::new(File::create(path)?);
+        writeln!(log, "{HEADER}")?;
+        log.flush()?;
+        Ok(JobLog(log))
+    }
+
+    // Continues the log of an earlier run (--resume, --retry-failed)
+    pub fn append(path: &Path) -> io::Result<Self> {
+        if !path.exists() {
+            return Self::create(path);
+        }
+        Ok(JobLog(BufWriter::new(
+            OpenOptions::new().append(true).open(path)?,
+        )))
+    }
+
+    // Written right away, so the log is complete up to the last finished job
+    pub fn write(&mut self, result: &JobResult) -> io::Result<()> {
+        let start = result
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        // Nothing is transferred
+        writeln!(
+            self.0,
+            "{}\t{}\t{:.3}\t{:.3}\t0\t0\t{}\t{}\t{}",
+            result.seq,
+            result.host.as_deref().unwrap_or(crate::ssh::LOCAL),
+            start,
+            result.duration.as_secs_f64(),
+            result.output.status.code().unwrap_or(0),
+            signal(result.output.status).unwrap_or(0),
+            result.job
+        )?;
+        self.0.flush()
+    }
+}
+
+// A finished job of an earlier log
+#[derive(Debug)]
+pub struct Entry {
+    pub command: String,
+    pub runtime: Duration,
+    pub success: bool,
+}
+
+// The lines that cannot be read are skipped, e.g. a last one cut off
+pub fn read(log: &str) -> impl Iterator<Item = Entry> + '_ {
+    log.lines().skip(1).filter_map(|line| {
+        let fields: Vec<&str> = line.splitn(9, '\t').collect();
+        let runtime = fields.get(3)?.parse::<f64>().ok()?;
+        let exit = fields.get(6)?.parse::<i32>().ok()?;
+        let signal = fields.get(7)?.parse::<i32>().ok()?;
+        Some(Entry {
+            command: (*fields.get(8)?).to_string(),
+            runtime: Duration::try_from_secs_f64(runtime).ok()?,
+            success: exit == 0 && signal == 0,
+        })
+    })
+}
+
+// The commands that succeeded the last time they ran, skipped by --resume and
+// --retry-failed, so failed jobs and those that never ran are run
+pub fn succeeded(log: &str) -> impl Iterator<Item = String> {
+    let mut last = HashMap::new();
+    for entry in read(log) {
+        last.insert(entry.command, entry.success);
+    }
+    last.into_iter()
+        .filter(|(_, success)| *success)
+        .map(|(command, _)| command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn resume() {
+        let log = [
+            HEADER,
+            "1\t:\t1.0\t0.1\t0\t0\t0\t0\techo ok",
+            "2\t:\t1.0\t0.1\t0\t0\t1\t0\tfalse",
+            "3\t:\t1.0\t0.1\t0\t0\t0\t15\tsleep 9",
+            "4\t:\t1.0\t0.1\t0\t0\t1\t0\tflaky",
+            "4\t:\t2.0\t0.1\t0\t0\t0\t0\tflaky",
+            "5\t:\t1.0\t0.1\t0\t0\t0\t0\tbroken",
+            "5\t:\t2.0\t0.1\t0\t0\t2\t0\tbroken",
+            "6\t:\t1.0\t0.1\t0",
+        ]
+        .join("\n");
+        let skipped: HashSet<String> = succeeded(&log).collect();
+        assert_eq!(skipped, HashSet::from(["echo ok".into(), "flaky".into()]));
+    }
+}
@@ -0,0 +1,260 @@
+// --listen PATH: job lines written by other processes, like a local job queue
+//
+// PATH is a FIFO if one exists there already, which ends the queue once its
+// last writer closes it. Otherwise parallel-sh creates a Unix socket at PATH
+// that any number of clients can connect to, one after the other or at the
+// same time. Either way a line `end` ends the queue, and a line `abort` kills
+// all jobs as well, like SIGQUIT.
+//
+// --follow reads a --file like `tail -f`, new lines are jobs until a line
+// `end` or until parallel-sh is stopped.
+//
+// With --idle-exit either one also ends once no job has been running and no
+// line has come in for a while, so a queue started on demand goes away again.
+use log::{info, warn};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+const END: &str = "end";
+const ABORT: &str = "abort";
+
+// How often a followed file is checked for new lines
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+struct IdleExit {
+    after: Duration,
+    since: Option<Instant>,
+}
+
+impl IdleExit {
+    fn new(after: Option<Duration>) -> Option<Self> {
+        Some(IdleExit {
+            after: after?,
+            since: None,
+        })
+    }
+
+    // Checked while waiting for a line
+    fn expired(&mut self) -> bool {
+        if !crate::jobctl::idle() {
+            self.since = None;
+            return false;
+        }
+        if self.since.get_or_insert_with(Instant::now).elapsed() < self.after {
+            return false;
+        }
+        info!("No jobs for {:?}, ending the queue", self.after);
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct Listener {
+    lines: Receiver<String>,
+    // The socket, removed once the queue ends
+    socket: Option<PathBuf>,
+    idle_exit: Option<IdleExit>,
+}
+
+impl Listener {
+    // Records end with `delimiter`, a newline or NUL (-0)
+    pub fn bind(path: &Path, delimiter: u8, idle_exit: Option<Duration>) -> io::Result<Self> {
+        let idle_exit = IdleExit::new(idle_exit);
+        let (tx, lines) = channel();
+        if is_fifo(path) {
+            // Opening blocks until there is a writer
+            let path = path.to_path_buf();
+            thread::spawn(move || match File::open(&path) {
+                Ok(fifo) => forward(fifo, delimiter, &tx),
+                Err(e) => warn!("Could not open '{}': {}", path.display(), e),
+            });
+            return Ok(Listener {
+                lines,
+                socket: None,
+                idle_exit,
+            });
+        }
+        let socket = bind_socket(path, delimiter, tx)?;
+        Ok(Listener {
+            lines,
+            socket: Some(socket),
+            idle_exit,
+        })
+    }
+}
+
+impl Iterator for Listener {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let Some(idle_exit) = self.idle_exit.as_mut() else {
+            return self.lines.recv().ok().filter(|line| line != END);
+        };
+        loop {
+            match self.lines.recv_timeout(POLL_INTERVAL) {
+                Ok(line) => {
+                    idle_exit.since = None;
+                    return (line != END).then_some(line);
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if idle_exit.expired() || crate::jobctl::stopping() {
+                        return None;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Some(socket) = &self.socket {
+            let _ = fs::remove_file(socket);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Follow {
+    file: BufReader<File>,
+    delimiter: u8,
+    // A record still being written
+    record: Vec<u8>,
+    idle_exit: Option<IdleExit>,
+}
+
+impl Follow {
+    pub fn open(path: &Path, delimiter: u8, idle_exit: Option<Duration>) -> io::Result<Self> {
+        Ok(Follow {
+            file: BufReader::new(File::open(path)?),
+            delimiter,
+            record: vec![],
+            idle_exit: IdleExit::new(idle_exit),
+        })
+    }
+}
+
+impl Iterator for Follow {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            self.file
+                .read_until(self.delimiter, &mut self.record)
+                .ok()?;
+            if self.record.last() == Some(&self.delimiter) {
+                self.record.pop();
+                let mut line = match String::from_utf8(std::mem::take(&mut self.record)) {
+                    Ok(line) => line,
+                    Err(e) => {
+                        warn!(
+                            "Skipping input that is not UTF-8: {}",
+                            String::from_utf8_lossy(e.as_bytes())
+                        );
+                        continue;
+                    }
+                };
+                if self.delimiter == b'\n' && line.ends_with('\r') {
+                    line.pop();
+                }
+                if let Some(idle_exit) = self.idle_exit.as_mut() {
+                    idle_exit.since = None;
+                }
+                return (line != END).then_some(line);
+            }
+            if crate::jobctl::stopping() || self.idle_exit.as_mut().is_some_and(IdleExit::expired) {
+                return None;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+fn forward(input: impl Read, delimiter: u8, tx: &Sender<String>) {
+    for record in BufReader::new(input).split(delimiter).map_while(Result::ok) {
+        let mut line = match String::from_utf8(record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(
+                    "Skipping input that is not UTF-8: {}",
+                    String::from_utf8_lossy(e.as_bytes())
+                );
+                continue;
+            }
+        };
+        if delimiter == b'\n' && line.ends_with('\r') {
+            line.pop();
+        }
+        // Right away, not once the line is read in turn
+        if line == ABORT {
+            crate::jobctl::abort();
+            line = END.to_string();
+        }
+        if tx.send(line).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    fs::metadata(path).is_ok_and(|meta| meta.file_type().is_fifo())
+}
+
+#[cfg(not(target_os = "windows"))]
+fn bind_socket(path: &Path, delimiter: u8, tx: Sender<String>) -> io::Result<PathBuf> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+    // Left behind by an earlier run that did not end its queue
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    log::debug!("Listening for jobs on '{}'", path.display());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = tx.clone();
+                    thread::spawn(move || forward(stream, delimiter, &tx));
+                }
+                Err(e) => warn!("Could not accept connection: {}", e),
+            }
+        }
+    });
+    Ok(path.to_path_buf())
+}
+
+#[cfg(target_os = "windows")]
+fn is_fifo(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(target_os = "windows")]
+fn bind_socket(_path: &Path, _delimiter: u8, _tx: Sender<String>) -> io::Result<PathBuf> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--listen is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forward_not_utf8() {
+        let (tx, rx) = channel();
+        forward(&b"echo a\r\necho \xff\necho b\n"[..], b'\n', &tx);
+        drop(tx);
+        assert_eq!(rx.iter().collect::<Vec<_>>(), ["echo a", "echo b"]);
+    }
+}
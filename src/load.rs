@@ -0,0 +1,129 @@
+// New jobs wait while the machine is busy (--load) or short of memory
+// (--memfree, --memsuspend)
+use log::{info, warn};
+use std::{thread, time::Duration};
+
+// The load average of the last minute
+#[cfg(not(target_os = "windows"))]
+fn load_average() -> Option<f64> {
+    let mut load = [0.0];
+    // SAFETY: getloadavg writes at most one value into load
+    let n = unsafe { libc::getloadavg(load.as_mut_ptr(), 1) };
+    (n == 1).then_some(load[0])
+}
+
+#[cfg(target_os = "windows")]
+fn load_average() -> Option<f64> {
+    None
+}
+
+// Block while the load average is at or above `max`, or until no more jobs
+// should be started
+pub fn wait(max: f64) {
+    let mut waiting = false;
+    while let Some(load) = load_average() {
+        if load < max || crate::jobctl::stopping() {
+            return;
+        }
+        if !waiting {
+            info!("Waiting for the load average ({load:.2}) to drop below {max}");
+            waiting = true;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+// Memory available for new processes without swapping, in bytes
+#[cfg(target_os = "linux")]
+fn available_memory() -> Option<usize> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kb = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<usize>()
+        .ok()?;
+    kb.checked_mul(1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn available_memory() -> Option<usize> {
+    None
+}
+
+// Block while less than `min` bytes of memory are available, or until no
+// more jobs should be started
+pub fn wait_for_memory(min: usize) {
+    let mut waiting = false;
+    while let Some(available) = available_memory() {
+        if available >= min || crate::jobctl::stopping() {
+            return;
+        }
+        if !waiting {
+            info!(
+                "Waiting for {} MiB of free memory ({} MiB available)",
+                min >> 20,
+                available >> 20
+            );
+            waiting = true;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+// Kill the youngest job while less than half of `min` is available, it is
+// run again once there is enough memory
+pub fn watch_memory(min: usize) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        if available_memory().is_some_and(|available| available < min / 2) {
+            if let Some(pid) = crate::jobctl::evict_youngest() {
+                warn!("Memory is running low, killed the job started last (pid {pid})");
+            }
+        }
+    });
+}
+
+// Stop the youngest job while less than `min` is available, and continue
+// them one by one, the one stopped last first, once twice as much is. One
+// job always runs.
+#[cfg(not(target_os = "windows"))]
+pub fn suspend_on_low_memory(min: usize) {
+    use crate::jobctl;
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        let Some(available) = available_memory() else {
+            continue;
+        };
+        if available >= min.saturating_mul(2) || jobctl::only_suspended() {
+            if let Some(pid) = jobctl::resume_suspended() {
+                info!("Resumed the suspended job with pid {pid}");
+            }
+        } else if available < min {
+            if let Some(pid) = jobctl::suspend_youngest() {
+                warn!("Memory is running low, suspended the job started last (pid {pid})");
+            }
+        }
+    });
+}
+
+// Block while jobs are suspended for lack of memory or less than `min` is
+// available, or until no more jobs should be started. The suspended jobs go
+// first, and a job is started anyway if none runs.
+pub fn wait_for_suspended(min: usize) {
+    use crate::jobctl;
+    let mut waiting = false;
+    loop {
+        let low = available_memory().is_some_and(|available| available < min);
+        if !(low || jobctl::suspended()) || jobctl::idle() || jobctl::stopping() {
+            return;
+        }
+        if !waiting {
+            info!("Waiting for memory, suspended jobs are resumed first");
+            waiting = true;
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
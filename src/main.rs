@@ -1,105 +1,1294 @@
+mod affinity;
+mod bench;
+mod cache;
+#[cfg(target_os = "linux")]
+mod cgroup;
+mod config;
+mod confirm;
+mod container;
+mod csv;
+mod dag;
+mod decompress;
+mod doctor;
+mod env;
+mod history;
+mod jobctl;
+mod joblog;
+mod json;
+mod listen;
+mod load;
+#[cfg(feature = "otel")]
+mod otel;
+mod output;
+mod pipe;
+mod progress;
+mod report;
+mod results;
+mod results_db;
+mod sandbox;
+#[cfg(feature = "scripting")]
+mod script;
+mod sem;
+mod sha1;
+mod source;
+mod ssh;
+mod status;
+mod template;
+mod watch;
+mod window;
+
+// Stand-in when built without the `scripting` feature
+#[cfg(not(feature = "scripting"))]
+mod script {
+    #[derive(Debug)]
+    pub struct Script;
+
+    impl Script {
+        pub fn load(_path: &std::path::Path) -> Result<Self, String> {
+            Err(String::from(
+                "parallel-sh was built without the `scripting` feature",
+            ))
+        }
+
+        pub fn has_priority(&self) -> bool {
+            false
+        }
+
+        pub fn accept(&self, _job: &str) -> bool {
+            true
+        }
+
+        pub fn priority(&self, _job: &str) -> i64 {
+            0
+        }
+
+        pub fn classify(&self, _job: &str, _exit: Option<i32>) -> Option<bool> {
+            None
+        }
+    }
+}
+
+// Stand-in when built without the `otel` feature
+#[cfg(not(feature = "otel"))]
+mod otel {
+    #[derive(Debug)]
+    pub struct Tracer;
+
+    impl Tracer {
+        pub fn new(_endpoint: &str) -> Result<Self, String> {
+            Err(String::from(
+                "parallel-sh was built without the `otel` feature",
+            ))
+        }
+
+        pub fn traceparent(&self) -> String {
+            String::new()
+        }
+
+        pub fn job(&self, _result: &super::JobResult, _success: bool) {}
+
+        pub fn finish(&self, _exit: i32) {}
+    }
+}
+
+use joblog::JobLog;
 use log::{debug, error, info, warn};
+use otel::Tracer;
+use output::{ExitCodes, FailureSummary, Format, Grouping, Newline, OutputEncoding, Printer};
+use progress::Progress;
+use regex::Regex;
+use results::Results;
+use results_db::ResultsDb;
+use script::Script;
 use simplelog::{
     ColorChoice, CombinedLogger, ConfigBuilder, LevelFilter, SharedLogger, TermLogger,
     TerminalMode, WriteLogger,
 };
+use source::Source;
 #[cfg(not(target_os = "windows"))]
 use std::os::unix::process::ExitStatusExt;
 #[cfg(target_os = "windows")]
 use std::os::windows::process::ExitStatusExt;
 use std::{
+    borrow::Cow,
+    cell::{Cell, OnceCell},
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashSet},
     ffi::OsString,
-    fs::{File, OpenOptions},
-    io::{self, BufRead, BufReader},
-    path::PathBuf,
-    process::{self, ExitStatus, Output},
+    fs::File,
+    io::{self, BufRead, BufReader, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::{self, ExitStatus, Output, Stdio},
     sync::{
-        mpsc::{channel, Receiver, Sender},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender},
+        Arc, Condvar, Mutex, PoisonError, Weak,
     },
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
+use template::{Chunking, Template};
+use window::{Delay, Rate, Window};
 
 const HELP: &str = "\
 Execute commands in parallel
 
 Usage: parallel-sh [OPTIONS] [clijobs]...
+       parallel-sh [OPTIONS] <TEMPLATE> ::: <ARGS>... [::: <ARGS>...]
+       parallel-sh env record <FILE>
+       parallel-sh env diff <FILE>
+       parallel-sh doctor [--shell <SHELL> [--shell-arg <FLAG>]... | --no-shell]
+       parallel-sh report --gantt <JOBLOG>
 
 Arguments:
   [clijobs]...
 
 Options:
-  -q, --quiet           Do not print `parallel-sh` warnings
-  -n, --dry-run         Perform a trial run, only print what would be done (with -vv)
-  -v, --verbose...      Sets the level of verbosity
-  -l, --log <FILE>      Log output to file
-      --halt-on-error   Stop execution if an error occurs in any thread
-  -j, --jobs <THREADS>  Number of parallel executions
-  -s, --shell <SHELL>   Shell to use for command execution. Must support '-c' (defaults to sh)
-      --no-shell        Do not pass commands through a shell, but execute them directly
-  -f, --file <FILE>     Read commands from file (one command per line)
-  -h, --help            Print help
-  -V, --version         Print version
+  -q, --quiet                      Do not print `parallel-sh` warnings
+  -n, --dry-run                    Perform a trial run, only print the commands and count them
+  -p, --interactive                Ask on the terminal before every job is started
+  -v, --verbose...                 Sets the level of verbosity
+  -l, --log <FILE>                 Log output to file
+      --log-required               Stop if the log file cannot be written to, instead of logging to the terminal only
+      --exit-status <POLICY>       Exit with the code of the first-fail or last-fail job (default), the count of failed jobs (at most 101) or 1 for any
+      --halt <WHEN,COND=N>         Stop once N (or N%) of the jobs failed or succeeded, e.g. now,fail=1 or soon,fail=20%
+      --halt-on-error              Stop execution if an error occurs in any thread (same as --halt now,fail=1)
+      --halt-on-spawn-error        Stop execution if a job cannot be started (e.g. command not found)
+  -k, --keep-order                 Print the output of the jobs in the order they were given, not as they finish
+  -j, --jobs <THREADS>             Number of parallel executions, or 50% of the CPUs, +2/-1 CPUs, 0 for as many as possible
+      --sem[=<NAME>]               Wait until fewer than -j other invocations with --sem NAME run, then run the jobs one at a time
+      --jobs-file <FILE>           Read the number of parallel executions from FILE, again every second
+  -s, --shell <SHELL>              Shell to use for command execution. Must support '-c' (defaults to sh, on Windows to pwsh or powershell)
+      --shell-arg <FLAG>           Pass FLAG instead of -c to the shell, before the command (repeatable)
+      --no-shell                   Do not pass commands through a shell, but execute them directly
+      --shell-no-profile           Keep the shell from reading its startup files (e.g. bash --noprofile --norc)
+      --prologue <FILE>            Prepend the contents of FILE (e.g. shell functions) to every job
+  -f, --file <FILE>                Read commands from file (one command per line, or one argument for a `{}` template), repeatable, `-` is stdin, may be compressed with gzip or zstd
+      --follow                     Keep reading --file as it grows, like tail -f, until a line 'end'
+      --listen <PATH>              Read commands from a FIFO at PATH, or a Unix socket created there, until a line 'end'
+      --idle-exit <DURATION>       End the queue of --listen or --follow once no job has run for DURATION
+      --watch <PATH>               Run the jobs again whenever a file below PATH changes, until stopped (repeatable)
+      --watch-changed              With --watch, only run the jobs again that have a changed file as an argument
+      --echo                       Print every job to stdout as it is started, with its sequence and slot number
+      --allow-enqueue              Run the lines a job prints starting with PARALLEL_SH_ENQUEUE: as jobs as well
+      --jobs-from <COMMAND>        Read commands from the output of COMMAND as it is written, like from --file
+  -0, --null                       Commands or arguments read from stdin or --file end with a NUL byte instead of a newline
+      --strict-lines               Run every input line as it is, without skipping empty lines and # comments or joining lines ending with \\
+  -N, --max-args <N>               Pass up to N input lines as arguments to every command ({} stands for all of them)
+      --max-chars <N>              Pass as many input lines as fit into a command of N characters
+      --colsep <REGEX>             Split input lines at REGEX into the arguments {1}, {2}, ... of the template
+      --header                     Take the first input line as the names of the columns, {name} in the template
+  -a, --arg-file <FILE>            Use the lines of FILE as an input source, like ::: (repeatable, {1}, {2}, ... in order)
+      --quote                      Quote every word of the command before ::: for the shell, so it is run exactly as given
+      --link                       Pair the arguments of ::: input sources by position instead of combining all of them (or :::+)
+      --profile <NAME>             Use the options of the [profile.NAME] section of the config file
+      --wd <DIR>                   Run the jobs in DIR, filled in like the command ('...' for a new temporary directory per job)
+      --env <VAR>                  Pass only VAR (repeatable) of the environment on to the jobs
+      --clean-env                  Start the jobs with an empty environment, except for --env, --env-file, --locale and --tz
+      --env-file <FILE>            Load KEY=VALUE pairs from FILE into each job's environment, FILE is filled in like the command
+      --locale <LOCALE>            Run all jobs with LANG and LC_ALL set to LOCALE (e.g. C)
+      --tz <TZ>                    Run all jobs with TZ set to TZ (e.g. UTC)
+      --sample <N>                 Only run a random subset of N jobs (or N% of all jobs)
+      --shuf                       Run the jobs in random order
+      --schedule <ORDER>           Run the jobs that took shortest (sjf) or longest (ljf) in the --joblog or --results-db of an earlier run first, or in input order (fifo)
+      --seed <SEED>                Seed for the random number generator (e.g. for --sample, --shuf)
+      --close-stdin                Start jobs with a closed stdin (default is /dev/null)
+      --inherit-stdin              Let jobs read from the stdin of parallel-sh
+      --stdin <STDIN>              Start jobs with null (default), closed, inherit or file:PATH as their stdin
+      --foreground-job <REGEX>     Connect the terminal to the first job matching REGEX
+      --umask <MODE>               Set the file mode creation mask (octal) for all jobs, or with #[umask=MODE] for one
+      --max-fds <N>                Limit the number of open files of each job (RLIMIT_NOFILE)
+      --max-procs-per-job <N>      Limit the number of processes of each job (RLIMIT_NPROC, counted per user)
+      --nice <N>                   Run all jobs with niceness N (-20 to 19, e.g. 10 for a lower priority)
+      --pin                        Run the jobs of every slot on a CPU of its own
+      --cpuset <LIST>              Like --pin, with the CPUs in LIST (e.g. 0-3,8)
+      --io-class <CLASS>           I/O scheduling class of all jobs: idle, best-effort[:0-7] or realtime[:0-7] (or --ionice)
+      --cpu-quota <PCT>            Limit each job to PCT percent of a CPU (e.g. 50%, or --cgroup-cpu)
+      --container <IMAGE>          Run every job in a new container of IMAGE, with the current directory mounted
+      --container-engine <ENGINE>  Run the containers with ENGINE (defaults to podman, or docker)
+      --volume <SRC:DST>           Also mount SRC at DST in the containers (repeatable)
+      --unshare <NS,...>           Run every job in new namespaces: net (no network), pid, mount, ipc, uts (Linux)
+      --private-tmp                Give every job an empty /tmp of its own (Linux)
+      --cgroup-mem <SIZE>          Limit each job to SIZE of memory (e.g. 2G), in a cgroup of its own
+      --freeze                     Freeze the running jobs while parallel-sh is stopped (Ctrl-Z), in a cgroup per job
+      --group                      Print the output of a job all at once when it is done (default)
+  -u, --ungroup                    Pass the output of the jobs on as it is written, the fastest
+      --line-buffer                Pass the output of the jobs on a whole line at a time while they run
+      --binary                     Pass the jobs' output on byte for byte, e.g. binary data (like --output-encoding bytes)
+      --output-encoding <ENC>      Encoding of the jobs' output: utf8 (default), utf16, utf16be, latin1, cp1252, bytes or auto (or --encoding)
+      --newline <NL>               Convert line endings of the jobs' output to lf, crlf or native
+      --bar                        Show a progress bar with the number of finished and failed jobs and an ETA
+      --tui                        Show every slot's running job, the jobs finished last and a progress bar (instead of log messages)
+      --status-port <[ADDR:]PORT>  Serve the state of the jobs on http://ADDR:PORT/status (JSON) and /metrics (Prometheus)
+      --eta                        Show the number of finished and failed jobs and an ETA, without a bar
+  -S, --sshlogin <[N/]HOST,...>    Run jobs on HOST via ssh, N at a time (defaults to -j); ':' is the local machine
+      --sshloginfile <FILE>        Read ssh logins from FILE, one per line
+      --host-check <CMD>           Send jobs only to hosts that connect and where CMD succeeds, checked again every --host-interval
+      --host-interval <DURATION>   How often --host-check is run again (defaults to 1m)
+      --host-timeout <DURATION>    How long a host may take to connect and run --host-check (defaults to 10s)
+      --transfer                   Copy the input files ({}) of remote jobs to their host first
+      --return <FILE>              Copy FILE (e.g. {}.out) back from the host after a remote job
+      --cleanup                    Delete transferred and returned files on the host afterwards
+      --pipe                       Cut stdin into blocks of lines and run the command once per block, with the block on its stdin
+      --block <SIZE>               Size of the --pipe blocks (defaults to 1M), auto for jobs of about 5s each
+      --pipepart <FILE>            Like --pipe, but every job reads its part of FILE itself
+      --format <FORMAT>            Print the jobs' output as text, or as one JSON object (json) or CSV/TSV row (csv, tsv) per job
+      --output <TEMPLATE>          Write the output of every job to a file named like the command is filled in, e.g. 'logs/{#}-{/}.log'
+      --output-err <TEMPLATE>      Write the stderr of every job to a file of its own, named like with --output
+      --tee-output                 Print the output of the jobs as well with --output and --results
+      --output-file <FILE>         Write the JSON objects or CSV/TSV rows of --format to FILE instead of stdout
+      --source-cmd <CMD>           Read jobs as JSON lines from CMD, and report finished jobs to its stdin
+      --joblog <FILE>              Log every finished job to FILE, in the format of GNU parallel's --joblog
+      --resume                     Skip the jobs that succeeded in the --joblog of an earlier run, and add to it
+      --retry-failed               Same as --resume, the failed jobs and those that did not run are run
+      --results <DIR>              Write the output, exit code and duration of every job to DIR/<seq>/ instead of printing it
+      --results-db <FILE>          Add every finished job, its output, exit code and times to the SQLite database FILE
+      --cache <DIR>                Skip jobs that succeeded in an earlier run with the same DIR, remember the ones that succeed
+      --cache-inputs               Run cached jobs again if one of their input files ({}) changed
+      --cache-key <TEMPLATE>       Cache jobs by TEMPLATE filled in with their arguments instead of by their command (e.g. '{1}')
+      --manifest <FILE>            Write every started job with its sequence number to FILE (JSON lines)
+      --on-result <CMD>            Run CMD for each finished job, with a JSON description on its stdin
+      --pre <CMD>                  Run CMD before every job, in the job's slot ({#}, {%} and $PARALLEL_JOB are filled in)
+      --post <CMD>                 Run CMD after every job, in the job's slot (like --pre, and $PARALLEL_EXIT)
+      --otel-endpoint <URL>        Export an OpenTelemetry trace of the run to URL, e.g. http://localhost:4318 (requires the `otel` feature)
+      --script <FILE>              Rhai script to filter, prioritize and classify jobs (requires the `scripting` feature)
+      --max-fail-output <N>        Only print the output of the first N failed jobs, summarize the rest
+      --flush-interval <DURATION>  Buffer the jobs' output and write it at most every DURATION (e.g. 200ms)
+      --squash-repeats             Collapse consecutive identical output lines into 'line (xN)'
+      --timeout <DURATION>         Stop jobs running longer than DURATION (e.g. 30s, 5m), they fail with exit code 124
+      --timeout-total <DURATION>   Stop all jobs once the whole run took DURATION, and exit with 124
+      --retries <N>                Run failed jobs again, up to N more times
+      --bench <N>                  Run every job N times in a row and compare their timings at the end
+      --warmup <N>                 Run every job N more times before it is measured (--bench)
+      --retry-delay <DURATION>     Wait DURATION before the first retry, doubled for every further one (e.g. 1s)
+      --max-total-retries <N>      Retry at most N times in the whole run, later failures fail right away
+      --max-output <SIZE>          Keep only the first and last SIZE/2 bytes of each job's stdout and stderr (e.g. 10M)
+      --tag                        Prefix every output line with the job's command and a tab
+      --tagstring <STR>            Prefix every output line with STR and a tab ({} is the command, {#} the sequence number)
+      --window <HH:MM-HH:MM>       Only start new jobs during this time of day (e.g. 22:00-06:00)
+      --load <MAX>                 Only start new jobs while the load average of the last minute is below MAX
+      --memfree <SIZE>             Only start new jobs while SIZE of memory is free, kill the youngest job below half of it
+      --memsuspend <SIZE>          Suspend the youngest job while less than SIZE of memory is free, and resume it at twice SIZE
+      --delay <DURATION>           Wait DURATION between starting two jobs (e.g. 0.5s)
+      --rate <N/PERIOD>            Start at most N jobs per PERIOD (e.g. 10/1m)
+      --after-tag <LABEL>          Skip jobs once a job labelled LABEL has failed
+      --label-sep <SEP>            Split input lines at the last SEP into command and label
+      --filter <REGEX>             Only run the commands matching REGEX (all of them, if given multiple times)
+      --filter-not <REGEX>         Do not run the commands matching REGEX
+      --extract-lock <REGEX>       Never run commands at the same time in which REGEX (or its first group) matches the same text
+      --dedup                      Run identical commands only once
+      --skip <N>                   Skip the first N jobs
+      --head <N>                   Only run the first N jobs (after --skip)
+  -h, --help                       Print help
+  -V, --version                    Print version
 ";
 
+// Exit code after Ctrl-C, like a shell reports SIGINT
+const INTERRUPTED: i32 = 130;
+
+// Exit code of jobs stopped after --timeout, like timeout(1), and of
+// parallel-sh after --timeout-total
+const TIMED_OUT: i32 = 124;
+
+// Jobs that never ran listed after --timeout-total
+const MAX_LISTED_JOBS: usize = 20;
+
+// Open files kept for parallel-sh itself (log files, --source-cmd, ...), and
+// the ones needed per running job
+#[cfg(not(target_os = "windows"))]
+const RESERVED_FILES: usize = 64;
+#[cfg(not(target_os = "windows"))]
+const FILES_PER_JOB: usize = 4;
+// Percentages in --halt only apply once this many jobs have finished
+const HALT_MIN_JOBS: usize = 3;
+// Highest exit code of --exit-status count, like GNU parallel's
+const MAX_FAILED_EXIT: usize = 101;
+
+// --wd value for a new, empty directory per job
+const SCRATCH_DIR: &str = "...";
+// Lines of a job's stdout adding a job (--allow-enqueue)
+const ENQUEUE_PREFIX: &str = "PARALLEL_SH_ENQUEUE:";
+// Jobs read ahead of the workers
+const QUEUED_JOBS_PER_SLOT: usize = 4;
+const MAX_QUEUED_JOBS: usize = 10_000;
+// Workers (one per slot) and output readers mostly wait on a job, a small
+// stack keeps -j 1000 and more from reserving gigabytes of address space
+const WORKER_STACK_SIZE: usize = 256 * 1024;
+// Period (in microseconds) that cgroup CPU quotas are measured against
+const CPU_PERIOD: u64 = 100_000;
+
 #[derive(Debug)]
 struct Args {
     quiet: bool,
     dryrun: bool,
+    interactive: bool,
     verbose: usize,
     logfile: Option<OsString>,
-    halt: bool,
+    log_required: bool,
+    halt: Option<Halt>,
+    exit_status: ExitPolicy,
+    halt_on_spawn_error: bool,
+    keep_order: bool,
     threads: usize,
+    // Name of the semaphore (--sem), -j is its size
+    sem: Option<String>,
+    jobs_file: Option<OsString>,
     shell: Option<OsString>,
-    file: Option<OsString>,
+    shell_args: Vec<OsString>,
+    shell_no_profile: bool,
+    prologue: Option<OsString>,
+    files: Vec<OsString>,
+    listen: Option<OsString>,
+    // A command whose output are the jobs
+    jobs_from: Option<String>,
+    // Run the jobs again when something below these paths changes
+    watch: Vec<PathBuf>,
+    watch_changed: bool,
+    follow: bool,
+    // End the queue of --listen or --follow once there is nothing to do
+    idle_exit: Option<Duration>,
+    null: bool,
+    strict_lines: bool,
+    colsep: Option<Regex>,
+    // The first input line names the columns (--header)
+    header: bool,
+    link: bool,
+    quote: bool,
+    // Input sources like ::: (-a/--arg-file)
+    arg_files: Vec<OsString>,
+    extract_lock: Option<Regex>,
+    max_args: Option<usize>,
+    max_chars: Option<usize>,
+    env_files: Vec<OsString>,
+    wd: Option<String>,
+    env_vars: Vec<String>,
+    clean_env: bool,
+    locale: Option<String>,
+    tz: Option<String>,
+    source_cmd: Option<String>,
+    stdin: StdinPolicy,
+    foreground: Option<Regex>,
+    umask: Option<u32>,
+    max_fds: Option<u64>,
+    max_procs: Option<u64>,
+    cpu_quota: Option<f64>,
+    cgroup_memory: Option<usize>,
+    // Stop the running jobs along with parallel-sh (--freeze)
+    freeze: bool,
+    unshare: Option<sandbox::Sandbox>,
+    container: Option<String>,
+    container_engine: Option<String>,
+    volumes: Vec<String>,
+    private_tmp: bool,
+    nice: Option<i32>,
+    io_class: Option<IoClass>,
+    cpus: Vec<usize>,
+    window: Option<Window>,
+    delay: Option<Duration>,
+    rate: Option<(u32, Duration)>,
+    load: Option<f64>,
+    memfree: Option<usize>,
+    memsuspend: Option<usize>,
+    allow_enqueue: bool,
+    echo: bool,
+    shard: Option<Shard>,
+    skip: usize,
+    head: Option<usize>,
+    sample: Option<Sample>,
+    shuffle: bool,
+    schedule: history::Order,
+    dedup: bool,
+    filters: Vec<Regex>,
+    filters_not: Vec<Regex>,
+    seed: Option<u64>,
+    squash_repeats: bool,
+    tag: Option<String>,
+    max_output: Option<usize>,
+    timeout: Option<Duration>,
+    timeout_total: Option<Duration>,
+    retries: u32,
+    bench: Option<usize>,
+    warmup: usize,
+    retry_delay: Duration,
+    max_total_retries: Option<u64>,
+    flush_interval: Option<Duration>,
+    max_fail_output: Option<usize>,
+    encoding: OutputEncoding,
+    // Output written byte for byte (--binary)
+    binary: bool,
+    grouping: Grouping,
+    newline: Option<Newline>,
+    format: Format,
+    output_file: Option<OsString>,
+    // Files the output of every job goes to, filled in like the command
+    output: Option<String>,
+    output_err: Option<String>,
+    // Printed as well (--tee-output)
+    tee_output: bool,
+    pipe: bool,
+    pipepart: Option<OsString>,
+    block_size: usize,
+    // The block size follows the runtime of the jobs (--block auto)
+    block_auto: bool,
+    sshlogins: Vec<ssh::Login>,
+    sshloginfile: Option<OsString>,
+    host_check: Option<String>,
+    host_check_interval: Duration,
+    host_check_timeout: Duration,
+    transfer: bool,
+    returns: Vec<String>,
+    cleanup: bool,
+    bar: bool,
+    tui: bool,
+    status_port: Option<std::net::SocketAddr>,
+    eta: bool,
+    on_result: Option<String>,
+    pre: Option<String>,
+    post: Option<String>,
+    manifest: Option<OsString>,
+    cache: Option<OsString>,
+    cache_inputs: bool,
+    cache_key: Option<String>,
+    joblog: Option<OsString>,
+    // Skip the jobs of the job log that succeeded (--resume, --retry-failed)
+    resume: bool,
+    results: Option<OsString>,
+    results_db: Option<OsString>,
+    script: Option<OsString>,
+    otel_endpoint: Option<String>,
+    label_sep: Option<String>,
+    after_tag: Option<String>,
     clijobs: Vec<String>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum StdinPolicy {
+    Null,
+    Closed,
+    Inherit,
+    // Every job reads the file from the start
+    File(PathBuf),
+}
+
+impl std::str::FromStr for StdinPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "null" => Ok(StdinPolicy::Null),
+            "closed" => Ok(StdinPolicy::Closed),
+            "inherit" => Ok(StdinPolicy::Inherit),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(StdinPolicy::File(PathBuf::from(path))),
+                _ => Err(format!(
+                    "invalid stdin '{s}', expected null, closed, inherit or file:PATH"
+                )),
+            },
+        }
+    }
+}
+
+// Which part of the job stream is actually run
+#[derive(Debug)]
+struct Selection {
+    dedup: bool,
+    // Every job has to match all of `filters` and none of `filters_not`
+    filters: Vec<Regex>,
+    filters_not: Vec<Regex>,
+    shard: Option<Shard>,
+    skip: usize,
+    head: Option<usize>,
+    sample: Option<Sample>,
+    shuffle: bool,
+    seed: u64,
+    // Only the jobs with a changed file as an argument (--watch-changed)
+    changed: Option<HashSet<PathBuf>>,
+    // Jobs that succeeded in an earlier run (--resume, --retry-failed)
+    finished: HashSet<String>,
+    // Jobs ordered by their earlier duration (--schedule sjf, ljf)
+    schedule: Option<history::Schedule>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Sample {
+    Count(usize),
+    Percent(f64),
+}
+
+impl std::str::FromStr for Sample {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(p) = s.strip_suffix('%') {
+            match p.parse::<f64>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => Ok(Sample::Percent(p)),
+                _ => Err(format!("invalid percentage '{s}'")),
+            }
+        } else {
+            s.parse()
+                .map(Sample::Count)
+                .map_err(|e| format!("invalid sample size '{s}': {e}"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Shard {
+    index: u64,
+    count: u64,
+}
+
+impl std::str::FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| format!("invalid shard '{s}', expected I/M"))?;
+        let index: u64 = index
+            .parse()
+            .map_err(|e| format!("invalid shard index '{index}': {e}"))?;
+        let count: u64 = count
+            .parse()
+            .map_err(|e| format!("invalid shard count '{count}': {e}"))?;
+        if index == 0 || index > count {
+            return Err(format!("shard index must be between 1 and {count}"));
+        }
+        Ok(Shard { index, count })
+    }
+}
+
+impl Shard {
+    // FNV-1a, stable across platforms and releases
+    fn contains(self, job: &str) -> bool {
+        let hash = job.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+        hash % self.count == self.index - 1
+    }
+}
+
+// I/O scheduling class of the jobs, as with ionice(1)
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+enum IoClass {
+    Realtime(u8),
+    BestEffort(u8),
+    Idle,
+}
+
+impl std::str::FromStr for IoClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => match level.parse::<u8>() {
+                Ok(level) if level <= 7 => (class, Some(level)),
+                _ => return Err(format!("invalid I/O priority '{level}', expected 0-7")),
+            },
+            None => (s, None),
+        };
+        match class {
+            "realtime" => Ok(IoClass::Realtime(level.unwrap_or(4))),
+            "best-effort" => Ok(IoClass::BestEffort(level.unwrap_or(4))),
+            "idle" if level.is_none() => Ok(IoClass::Idle),
+            "idle" => Err("the idle I/O class has no priority levels".to_string()),
+            _ => Err(format!(
+                "invalid I/O class '{class}', expected realtime, best-effort or idle"
+            )),
+        }
+    }
+}
+
+impl IoClass {
+    // Value for ioprio_set(2)
+    #[cfg(target_os = "linux")]
+    fn ioprio(self) -> libc::c_int {
+        const CLASS_SHIFT: libc::c_int = 13;
+        match self {
+            IoClass::Realtime(level) => (1 << CLASS_SHIFT) | libc::c_int::from(level),
+            IoClass::BestEffort(level) => (2 << CLASS_SHIFT) | libc::c_int::from(level),
+            IoClass::Idle => 3 << CLASS_SHIFT,
+        }
+    }
+}
+
+// SplitMix64, good enough to pick and shuffle jobs
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, n)
+    #[allow(clippy::cast_possible_truncation)]
+    fn below(&mut self, n: usize) -> usize {
+        ((u128::from(self.next_u64()) * n as u128) >> 64) as usize
+    }
+
+    // Uniform in [0, 1)
+    #[allow(clippy::cast_precision_loss)]
+    fn fraction(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn default_seed() -> u64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    #[allow(clippy::cast_possible_truncation)]
+    let nanos = nanos as u64;
+    nanos ^ u64::from(process::id()).rotate_left(32)
+}
+
+// Per job overrides of the global limits, set by --source-cmd jobs and
+// `#[...]` directives
+#[derive(Debug, Clone, Default)]
+struct Limits {
+    timeout: Option<Duration>,
+    retries: Option<u32>,
+    shell: Option<OsString>,
+    // Percent of a single CPU, like --cpu-quota
+    cpu: Option<f64>,
+    memory: Option<usize>,
+    nice: Option<i32>,
+    umask: Option<u32>,
+}
+
+// A job as read from the input, before it is numbered
+#[derive(Debug)]
+struct JobSpec {
+    cmd: String,
+    label: Option<String>,
+    // Only run if no job with this label has failed
+    after: Option<String>,
+    limits: Limits,
+    // The arguments a template was filled in with
+    args: Vec<String>,
+    // Higher priorities are started first
+    priority: i64,
+    // Labels of the jobs to wait for
+    deps: Vec<String>,
+    // Jobs with the same lock never run at the same time
+    lock: Option<String>,
+    // Unless this many of them may (the "concurrency" of a --source-cmd group)
+    lock_limit: Option<usize>,
+    // The command is filled in again once the job is started, for `{#}` and
+    // `{%}`
+    template: Option<Arc<Template>>,
+}
+
+impl JobSpec {
+    // `separator` splits off a label at the end of the line
+    fn parse(line: String, separator: Option<&str>) -> Self {
+        match separator.and_then(|sep| line.rsplit_once(sep)) {
+            Some((cmd, label)) => JobSpec {
+                cmd: cmd.to_string(),
+                label: Some(label.to_string()),
+                after: None,
+                limits: Limits::default(),
+                args: vec![],
+                priority: 0,
+                deps: vec![],
+                lock: None,
+                lock_limit: None,
+                template: None,
+            },
+            None => JobSpec {
+                cmd: line,
+                label: None,
+                after: None,
+                limits: Limits::default(),
+                args: vec![],
+                priority: 0,
+                deps: vec![],
+                lock: None,
+                lock_limit: None,
+                template: None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Job {
+    seq: usize,
+    cmd: String,
+    label: Option<String>,
+    after: Option<String>,
+    limits: Limits,
+    args: Vec<String>,
+    // Written to the job's stdin (--pipe, --pipepart)
+    input: Option<pipe::Input>,
+    priority: i64,
+    deps: Vec<String>,
+    lock: Option<String>,
+    template: Option<Arc<Template>>,
+    // From 1, one more for every retry (--retries)
+    attempt: u32,
+}
+
+impl Job {
+    // `{#}`, `{%}` and `{attempt}` of a template once the job is started, and
+    // again for every retry, other commands are run as they are
+    fn fill_in(&mut self, slot: usize) {
+        if let Some(template) = &self.template {
+            self.cmd = template.expand_job(&self.args, self.seq, slot, self.attempt);
+        }
+    }
+}
+
+// The order jobs are started in: higher priorities first, then in input order
+impl Ord for Job {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Job {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Job {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Job {}
+
 #[derive(Debug)]
 struct JobResult {
+    seq: usize,
     duration: Duration,
     job: String,
+    label: Option<String>,
     output: Output,
+    // Set if the job could not be started, output is empty then
+    spawn_error: Option<io::Error>,
+    // Set if the job was not run (--after-tag), only sent for --keep-order
+    skipped: bool,
+    // The timeout the job was stopped after, if it ran too long
+    timed_out: Option<Duration>,
+    // Number of times the job was run (--retries)
+    attempts: u32,
+    started: SystemTime,
+    // Remote host the job ran on (--sshlogin)
+    host: Option<String>,
+    // Of the measured runs (--bench)
+    timings: Vec<Duration>,
+    // The arguments of a template (`{}`, `:::`)
+    args: Vec<String>,
+}
+
+impl JobResult {
+    // How the job is referred to in log messages
+    fn name(&self) -> String {
+        let name = match &self.label {
+            Some(label) => format!("'{}' [{}]", self.job, label),
+            None => format!("'{}'", self.job),
+        };
+        match &self.host {
+            Some(host) => format!("{name} on {host}"),
+            None => name,
+        }
+    }
+}
+
+// A thread-safe wrapper around a `Receiver`, the greatest of the items
+// received so far comes first
+#[derive(Debug)]
+struct Queue<T> {
+    receiver: Receiver<T>,
+    received: BinaryHeap<T>,
+    bound: usize,
 }
 
-// A thread-safe wrapper around a `Receiver`
 #[derive(Debug, Clone)]
-struct SharedReceiver<T>(Arc<Mutex<Receiver<T>>>);
+struct SharedReceiver<T>(Arc<Mutex<Queue<T>>>);
 
-impl<T> Iterator for SharedReceiver<T> {
+impl<T: Ord> Iterator for SharedReceiver<T> {
     type Item = T;
 
+    // Ends early once no more jobs should be started, so idle workers do not
+    // wait for input that may never come
     fn next(&mut self) -> Option<T> {
-        let guard = self.0.lock().unwrap();
-        guard.recv().ok()
+        let mut guard = self.0.lock().unwrap();
+        let queue = &mut *guard;
+        while !jobctl::stopping() {
+            // Up to `bound` more, so senders still have to wait
+            while queue.received.len() < queue.bound {
+                match queue.receiver.try_recv() {
+                    Ok(item) => queue.received.push(item),
+                    Err(_) => break,
+                }
+            }
+            if let Some(item) = queue.received.pop() {
+                return Some(item);
+            }
+            match queue.receiver.recv_timeout(Duration::from_millis(100)) {
+                Ok(item) => queue.received.push(item),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+        None
     }
 }
 
-fn shared_channel<T>() -> (Sender<T>, SharedReceiver<T>) {
-    let (sender, receiver) = channel();
-    (sender, SharedReceiver(Arc::new(Mutex::new(receiver))))
+impl<T: Ord> SharedReceiver<T> {
+    // The items nobody took, in the order they would have come
+    fn drain(&self) -> Vec<T> {
+        let mut queue = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut items = std::mem::take(&mut queue.received).into_sorted_vec();
+        items.reverse();
+        items.extend(queue.receiver.try_iter());
+        items
+    }
 }
 
-fn parse_args() -> Result<Args, lexopt::Error> {
-    use lexopt::prelude::*;
+// Sending blocks once `bound` items are waiting
+fn shared_channel<T: Ord>(bound: usize) -> (SyncSender<T>, SharedReceiver<T>) {
+    let (sender, receiver) = sync_channel(bound);
+    let queue = Queue {
+        receiver,
+        received: BinaryHeap::new(),
+        bound,
+    };
+    (sender, SharedReceiver(Arc::new(Mutex::new(queue))))
+}
+
+fn parse_nice(s: &str) -> Result<i32, String> {
+    match s.parse() {
+        Ok(nice) if (-20..=19).contains(&nice) => Ok(nice),
+        _ => Err(format!("invalid niceness '{s}', expected -20 to 19")),
+    }
+}
+
+fn parse_umask(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mask) if mask <= 0o777 => Ok(mask),
+        _ => Err(format!(
+            "invalid umask '{s}', expected an octal mode like 022"
+        )),
+    }
+}
+
+// Percent of a single CPU, more than 100% spans several CPUs
+fn parse_cpu_quota(s: &str) -> Result<f64, String> {
+    match s.strip_suffix('%').unwrap_or(s).parse::<f64>() {
+        Ok(pct) if pct.is_finite() && pct >= 1.0 => Ok(pct),
+        _ => Err(format!(
+            "invalid CPU quota '{s}', expected a percentage of at least 1%"
+        )),
+    }
+}
+
+// The cpu.max setting of a cgroup for a quota in percent of a single CPU
+fn cpu_max(quota: f64) -> String {
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let max = (quota / 100.0 * CPU_PERIOD as f64).round() as u64;
+    format!("{max} {CPU_PERIOD}")
+}
+
+// Whether --halt stops the running jobs, or lets them finish
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HaltWhen {
+    Soon,
+    Now,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Threshold {
+    Count(usize),
+    // Of the jobs finished so far
+    Percent(f64),
+}
+
+impl Threshold {
+    fn reached(self, count: usize, finished: usize) -> bool {
+        match self {
+            Threshold::Count(n) => count >= n,
+            #[allow(clippy::cast_precision_loss)]
+            Threshold::Percent(pct) => {
+                finished >= HALT_MIN_JOBS && count as f64 >= pct / 100.0 * finished as f64
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Halt {
+    when: HaltWhen,
+    // Count succeeded instead of failed jobs
+    on_success: bool,
+    threshold: Threshold,
+}
+
+// Which exit code a run with failed jobs ends with (--exit-status)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExitPolicy {
+    FirstFail,
+    LastFail,
+    // The number of failed jobs
+    Count,
+    // 1 if any job failed
+    Any,
+}
+
+fn parse_exit_status(s: &str) -> Result<ExitPolicy, String> {
+    match s {
+        "first-fail" => Ok(ExitPolicy::FirstFail),
+        "last-fail" => Ok(ExitPolicy::LastFail),
+        "count" => Ok(ExitPolicy::Count),
+        "any" => Ok(ExitPolicy::Any),
+        _ => Err(format!(
+            "invalid exit status '{s}', expected first-fail, last-fail, count or any"
+        )),
+    }
+}
+
+// `now,fail=1`, `soon,fail=20%`, `now,success=1` or `never`
+fn parse_halt(s: &str) -> Result<Option<Halt>, String> {
+    if s == "never" {
+        return Ok(None);
+    }
+    let invalid =
+        || format!("invalid halt policy '{s}', expected e.g. now,fail=1 or soon,fail=20%");
+    let (when, condition) = s.split_once(',').ok_or_else(invalid)?;
+    let when = match when {
+        "now" => HaltWhen::Now,
+        "soon" => HaltWhen::Soon,
+        _ => return Err(invalid()),
+    };
+    let (on_success, threshold) = match condition.split_once('=') {
+        Some(("fail", threshold)) => (false, threshold),
+        Some(("success", threshold)) => (true, threshold),
+        _ => return Err(invalid()),
+    };
+    let threshold = match threshold.strip_suffix('%') {
+        Some(pct) => pct
+            .parse::<f64>()
+            .ok()
+            .filter(|pct| *pct > 0.0 && *pct <= 100.0)
+            .map(Threshold::Percent),
+        None => threshold
+            .parse::<usize>()
+            .ok()
+            .filter(|&n| n > 0)
+            .map(Threshold::Count),
+    };
+    Ok(Some(Halt {
+        when,
+        on_success,
+        threshold: threshold.ok_or_else(invalid)?,
+    }))
+}
+
+// A number of jobs, a percentage of the CPUs (`50%`), a number of jobs more
+// or less than CPUs (`+2`, `-1`), or `0` for as many as possible
+fn parse_jobs(s: &str) -> Result<usize, String> {
+    let cpus = num_cpus::get();
+    let number = |n: &str| {
+        n.parse::<usize>()
+            .map_err(|_| format!("invalid number of jobs '{s}'"))
+    };
+    Ok(if let Some(percent) = s.strip_suffix('%') {
+        (cpus.saturating_mul(number(percent)?) / 100).max(1)
+    } else if let Some(more) = s.strip_prefix('+') {
+        cpus.saturating_add(number(more)?)
+    } else if let Some(less) = s.strip_prefix('-') {
+        cpus.saturating_sub(number(less)?).max(1)
+    } else {
+        match number(s)? {
+            0 => usize::MAX,
+            jobs => jobs,
+        }
+    })
+}
+
+// Bytes, or a number with one of the units K, M or G (powers of 1024)
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (number, scale) = match s.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        n if n.ends_with('K') => (n[..n.len() - 1].to_string(), 1 << 10),
+        n if n.ends_with('M') => (n[..n.len() - 1].to_string(), 1 << 20),
+        n if n.ends_with('G') => (n[..n.len() - 1].to_string(), 1 << 30),
+        n => (n.to_string(), 1),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(scale))
+        .ok_or_else(|| format!("invalid size '{s}', expected e.g. 512K, 10M or 1G"))
+}
+
+// Seconds, or a number with one of the units ms, s, m or h
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = s
+        .find(|c: char| c.is_ascii_alphabetic())
+        .map_or((s, "s"), |i| s.split_at(i));
+    let scale = match unit {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("invalid unit '{unit}' in duration '{s}'")),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .and_then(|n| Duration::try_from_secs_f64(n * scale).ok())
+        .ok_or_else(|| format!("invalid duration '{s}'"))
+}
+
+// `N/PERIOD`, e.g. 10/1m or 5/s
+fn parse_rate(s: &str) -> Result<(u32, Duration), String> {
+    let invalid = || format!("invalid rate '{s}', expected N/PERIOD (e.g. 10/1m)");
+    let (jobs, period) = s.split_once('/').ok_or_else(invalid)?;
+    let jobs: u32 = jobs.parse().map_err(|_| invalid())?;
+    let period = if period.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        parse_duration(&format!("1{period}"))?
+    } else {
+        parse_duration(period)?
+    };
+    if jobs == 0 || period.is_zero() {
+        return Err(invalid());
+    }
+    Ok((jobs, period))
+}
+
+// PowerShell 7 (pwsh) on Windows if it is installed, Windows PowerShell
+// otherwise
+fn default_shell() -> OsString {
+    if !cfg!(target_os = "windows") {
+        OsString::from("sh")
+    } else if find_program("pwsh").is_some() {
+        OsString::from("pwsh")
+    } else {
+        OsString::from("powershell")
+    }
+}
 
-    let mut shell = if cfg!(target_os = "windows") {
-        Some(OsString::from("powershell"))
+fn find_program(name: &str) -> Option<PathBuf> {
+    let name = if cfg!(target_os = "windows") {
+        format!("{name}.exe")
     } else {
-        Some(OsString::from("sh"))
+        name.to_string()
+    };
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(&name))
+        .find(|path| path.is_file())
+}
+
+// A job file run as a script, `#!/usr/bin/parallel-sh -j8 --halt-on-error`:
+// the system passes the options of the shebang line as a single argument, if
+// there are any, then the path of the script, which is read like --file
+fn shebang_args(args: Vec<OsString>) -> Vec<OsString> {
+    // The options after `parallel-sh` on the first line of a script, only
+    // regular files are read (not e.g. a FIFO given to --file)
+    let options = |arg: &OsString| -> Option<String> {
+        if !std::fs::metadata(arg).is_ok_and(|metadata| metadata.is_file()) {
+            return None;
+        }
+        let mut line = String::new();
+        File::open(arg)
+            .and_then(|file| BufReader::new(io::Read::take(file, 1024)).read_line(&mut line))
+            .ok()?;
+        let (_, options) = line.strip_prefix("#!")?.split_once("parallel-sh")?;
+        Some(options.trim().to_string())
+    };
+    let script = match args.as_slice() {
+        [script, ..] if options(script).is_some_and(|options| options.is_empty()) => 0,
+        [given, script, ..]
+            if given.to_str().is_some_and(|given| given.starts_with('-'))
+                && options(script).is_some_and(|options| *given == *options) =>
+        {
+            1
+        }
+        _ => return args,
     };
+    let mut result = vec![];
+    for (i, arg) in args.into_iter().enumerate() {
+        match arg.to_str() {
+            _ if i == script => {
+                result.push(OsString::from("--file"));
+                result.push(arg);
+            }
+            Some(options) if i < script => {
+                result.extend(
+                    template::split_words(options)
+                        .into_iter()
+                        .map(OsString::from),
+                );
+            }
+            _ => result.push(arg),
+        }
+    }
+    result
+}
+
+fn parse_args() -> Result<Args, lexopt::Error> {
+    use lexopt::prelude::*;
+
+    let mut shell = Some(default_shell());
 
     let mut quiet = false;
     let mut dryrun = false;
+    let mut interactive = false;
     let mut verbose = 0;
     let mut logfile = None;
-    let mut halt = false;
+    let mut log_required = false;
+    let mut halt = None;
+    let mut exit_status = ExitPolicy::LastFail;
+    let mut halt_on_spawn_error = false;
+    let mut keep_order = false;
     let mut threads = num_cpus::get();
-    let mut file = None;
+    let mut sem = None;
+    let mut jobs_file = None;
+    let mut shell_args = vec![];
+    let mut shell_no_profile = false;
+    let mut prologue = None;
+    let mut files = vec![];
+    let mut listen = None;
+    let mut jobs_from = None;
+    let mut watch = vec![];
+    let mut watch_changed = false;
+    let mut follow = false;
+    let mut idle_exit = None;
+    let mut null = false;
+    let mut strict_lines = false;
+    let mut colsep = None;
+    let mut header = false;
+    let mut link = false;
+    let mut quote = false;
+    let mut arg_files = vec![];
+    let mut extract_lock = None;
+    let mut max_args = None;
+    let mut max_chars = None;
+    let mut env_files = vec![];
+    let mut wd = None;
+    let mut env_vars = vec![];
+    let mut clean_env = false;
+    let mut locale = None;
+    let mut tz = None;
+    let mut source_cmd = None;
+    let mut stdin = StdinPolicy::Null;
+    let mut foreground = None;
+    let mut umask = None;
+    let mut max_fds = None;
+    let mut max_procs = None;
+    let mut cpu_quota = None;
+    let mut cgroup_memory = None;
+    let mut freeze = false;
+    let mut unshare = None;
+    let mut container = None;
+    let mut container_engine = None;
+    let mut volumes = vec![];
+    let mut private_tmp = false;
+    let mut nice = None;
+    let mut io_class = None;
+    let mut cpus = vec![];
+    let mut window = None;
+    let mut delay = None;
+    let mut rate = None;
+    let mut load = None;
+    let mut memfree = None;
+    let mut memsuspend = None;
+    let mut allow_enqueue = false;
+    let mut echo = false;
+    let mut shard = None;
+    let mut skip = 0;
+    let mut head = None;
+    let mut sample = None;
+    let mut shuffle = false;
+    let mut schedule = history::Order::Fifo;
+    let mut dedup = false;
+    let mut filters = vec![];
+    let mut filters_not = vec![];
+    let mut seed = None;
+    let mut squash_repeats = false;
+    let mut tag = None;
+    let mut max_output = None;
+    let mut timeout = None;
+    let mut retries = 0;
+    let mut timeout_total = None;
+    let mut bench = None;
+    let mut warmup = 0;
+    let mut retry_delay = Duration::ZERO;
+    let mut max_total_retries = None;
+    let mut max_fail_output = None;
+    let mut flush_interval = None;
+    let mut encoding = OutputEncoding::Utf8;
+    let mut binary = false;
+    let mut grouping = Grouping::Group;
+    let mut newline = None;
+    let mut format = Format::Text;
+    let mut output_file = None;
+    let mut output = None;
+    let mut output_err = None;
+    let mut tee_output = false;
+    let mut pipe = false;
+    let mut pipepart = None;
+    let mut block_size = pipe::DEFAULT_BLOCK_SIZE;
+    let mut block_auto = false;
+    let mut sshlogins = vec![];
+    let mut sshloginfile = None;
+    let mut host_check = None;
+    let mut host_check_interval = Duration::from_secs(60);
+    let mut host_check_timeout = Duration::from_secs(10);
+    let mut transfer = false;
+    let mut returns = vec![];
+    let mut cleanup = false;
+    let mut bar = false;
+    let mut tui = false;
+    let mut status_port = None;
+    let mut eta = false;
+    let mut on_result = None;
+    let mut pre = None;
+    let mut post = None;
+    let mut manifest = None;
+    let mut cache = None;
+    let mut cache_inputs = false;
+    let mut cache_key = None;
+    let mut joblog = None;
+    let mut resume = false;
+    let mut results = None;
+    let mut results_db = None;
+    let mut script = None;
+    let mut otel_endpoint = None;
+    let mut label_sep = None;
+    let mut after_tag = None;
     let mut clijobs = vec![];
 
-    let mut parser = lexopt::Parser::from_env();
+    // Options from the config file come first, then those from $PARALLEL_SH,
+    // so the command line overrides both
+    let mut cli: Vec<OsString> = std::env::var("PARALLEL_SH")
+        .map(|options| {
+            template::split_words(&options)
+                .into_iter()
+                .map(OsString::from)
+                .collect()
+        })
+        .unwrap_or_default();
+    cli.extend(shebang_args(std::env::args_os().skip(1).collect()));
+    let profile = config::profile(&cli);
+    let mut all = config::args(profile.as_deref())?;
+    all.extend(cli);
+    let mut parser = lexopt::Parser::from_args(all);
 
     while let Some(arg) = parser.next()? {
         match arg {
@@ -109,17 +1298,48 @@ fn parse_args() -> Result<Args, lexopt::Error> {
             Short('n') | Long("dry-run") => {
                 dryrun = true;
             }
+            Short('p') | Long("interactive") => {
+                interactive = true;
+            }
             Short('v') | Long("verbose") => {
                 verbose += 1;
             }
             Short('l') | Long("log") => {
                 logfile = Some(parser.value()?.parse()?);
             }
+            Long("log-required") => {
+                log_required = true;
+            }
+            Long("halt") => {
+                halt = parser.value()?.parse_with(parse_halt)?;
+            }
+            Long("exit-status") => {
+                exit_status = parser.value()?.parse_with(parse_exit_status)?;
+            }
             Long("halt-on-error") => {
-                halt = true;
+                halt = Some(Halt {
+                    when: HaltWhen::Now,
+                    on_success: false,
+                    threshold: Threshold::Count(1),
+                });
+            }
+            Long("halt-on-spawn-error") => {
+                halt_on_spawn_error = true;
+            }
+            Short('k') | Long("keep-order") => {
+                keep_order = true;
             }
             Short('j') | Long("jobs") => {
-                threads = parser.value()?.parse()?;
+                threads = parser.value()?.parse_with(parse_jobs)?;
+            }
+            Long("sem") => {
+                sem = Some(match parser.optional_value() {
+                    Some(name) => name.parse_with(sem::validate)?,
+                    None => String::from("default"),
+                });
+            }
+            Long("jobs-file") => {
+                jobs_file = Some(parser.value()?);
             }
             Short('s') | Long("shell") => {
                 shell = Some(parser.value()?.parse()?);
@@ -127,236 +1347,4013 @@ fn parse_args() -> Result<Args, lexopt::Error> {
             Long("no-shell") => {
                 shell = None;
             }
+            Long("shell-arg") => {
+                shell_args.push(parser.value()?);
+            }
+            Long("shell-no-profile") => {
+                shell_no_profile = true;
+            }
+            Long("prologue") => {
+                prologue = Some(parser.value()?.parse()?);
+            }
             Short('f') | Long("file") => {
-                file = Some(parser.value()?.parse()?);
+                files.push(parser.value()?.parse()?);
             }
-            Short('h') | Long("help") => {
-                println!("{HELP}");
-                process::exit(0);
+            Long("follow") => {
+                follow = true;
             }
-            Short('V') | Long("version") => {
-                println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"));
-                process::exit(0);
+            Long("listen") => {
+                listen = Some(parser.value()?);
             }
-            Value(value) => {
-                clijobs.push(value.string()?);
+            Long("idle-exit") => {
+                idle_exit = Some(parser.value()?.parse_with(parse_duration)?);
             }
-            _ => return Err(arg.unexpected()),
-        }
-    }
-
-    Ok(Args {
-        quiet,
-        dryrun,
-        verbose,
-        logfile,
-        halt,
-        threads,
-        shell,
-        file,
-        clijobs,
-    })
-}
-
-fn create_logger(opts: &Args) -> Result<(), std::io::Error> {
-    let level = match (opts.quiet, opts.verbose) {
-        (true, _) => LevelFilter::Error,
-        (_, 0) => LevelFilter::Warn,
-        (_, 1) => LevelFilter::Info,
-        (_, 2) => LevelFilter::Debug,
-        _ => LevelFilter::Trace,
-    };
-
-    let logconfig = ConfigBuilder::new()
-        .set_time_format_rfc3339()
-        .set_time_offset_to_local()
-        .unwrap_or_else(|v| v)
-        .build();
-
-    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
-        level,
-        logconfig.clone(),
-        TerminalMode::Stderr,
+            Long("jobs-from") => {
+                jobs_from = Some(parser.value()?.string()?);
+            }
+            Long("watch") => {
+                watch.push(PathBuf::from(parser.value()?));
+            }
+            Long("watch-changed") => {
+                watch_changed = true;
+            }
+            Long("strict-lines") => {
+                strict_lines = true;
+            }
+            Short('0') | Long("null") => {
+                null = true;
+            }
+            Long("extract-lock") => {
+                extract_lock = Some(parser.value()?.parse()?);
+            }
+            Long("link") => {
+                link = true;
+            }
+            Long("quote") => {
+                quote = true;
+            }
+            Short('a') | Long("arg-file") => {
+                arg_files.push(parser.value()?);
+            }
+            Long("colsep") => {
+                colsep = Some(parser.value()?.parse()?);
+            }
+            Long("header") => {
+                header = true;
+            }
+            Short('N') | Long("max-args") => {
+                max_args = Some(parser.value()?.parse::<usize>()?.max(1));
+            }
+            Long("max-chars") => {
+                max_chars = Some(parser.value()?.parse()?);
+            }
+            Long("env-file") => {
+                env_files.push(parser.value()?.parse()?);
+            }
+            Long("profile") => {
+                parser.value()?;
+            }
+            Long("wd") | Long("cwd") => {
+                wd = Some(parser.value()?.string()?);
+            }
+            Long("env") => {
+                env_vars.push(parser.value()?.string()?);
+            }
+            Long("clean-env") => {
+                clean_env = true;
+            }
+            Long("locale") => {
+                locale = Some(parser.value()?.parse()?);
+            }
+            Long("tz") => {
+                tz = Some(parser.value()?.parse()?);
+            }
+            Long("source-cmd") => {
+                source_cmd = Some(parser.value()?.string()?);
+            }
+            Long("close-stdin") => {
+                stdin = StdinPolicy::Closed;
+            }
+            Long("inherit-stdin") => {
+                stdin = StdinPolicy::Inherit;
+            }
+            Long("stdin") => {
+                stdin = parser.value()?.parse()?;
+            }
+            Long("foreground-job") => {
+                foreground = Some(parser.value()?.parse()?);
+            }
+            Long("umask") => {
+                umask = Some(parser.value()?.parse_with(parse_umask)?);
+            }
+            Long("max-fds") => {
+                max_fds = Some(parser.value()?.parse()?);
+            }
+            Long("max-procs-per-job") => {
+                max_procs = Some(parser.value()?.parse()?);
+            }
+            Long("nice") => {
+                nice = Some(parser.value()?.parse_with(parse_nice)?);
+            }
+            Long("io-class" | "ionice") => {
+                io_class = Some(parser.value()?.parse()?);
+            }
+            Long("pin") => {
+                cpus = (0..num_cpus::get()).collect();
+            }
+            Long("cpuset") => {
+                cpus = parser.value()?.parse_with(affinity::parse_cpuset)?;
+            }
+            Long("window") => {
+                window = Some(parser.value()?.parse()?);
+            }
+            Long("memfree") => {
+                memfree = Some(parser.value()?.parse_with(parse_size)?);
+            }
+            Long("memsuspend") => {
+                memsuspend = Some(parser.value()?.parse_with(parse_size)?);
+            }
+            Long("allow-enqueue") => {
+                allow_enqueue = true;
+            }
+            Long("echo") => {
+                echo = true;
+            }
+            Long("rate") => {
+                rate = Some(parser.value()?.parse_with(parse_rate)?);
+            }
+            Long("delay") => {
+                delay = Some(parser.value()?.parse_with(parse_duration)?);
+            }
+            Long("load") => {
+                load = Some(parser.value()?.parse_with(|s| match s.parse::<f64>() {
+                    Ok(load) if load > 0.0 => Ok(load),
+                    _ => Err(format!("invalid load average '{s}'")),
+                })?);
+            }
+            Long("cpu-quota" | "cgroup-cpu") => {
+                cpu_quota = Some(parser.value()?.parse_with(parse_cpu_quota)?);
+            }
+            Long("container") => {
+                container = Some(parser.value()?.string()?);
+            }
+            Long("container-engine") => {
+                container_engine = Some(parser.value()?.string()?);
+            }
+            Long("volume") => {
+                volumes.push(parser.value()?.string()?);
+            }
+            Long("unshare") => {
+                unshare = Some(parser.value()?.parse()?);
+            }
+            Long("private-tmp") => {
+                private_tmp = true;
+            }
+            Long("cgroup-mem") => {
+                cgroup_memory = Some(parser.value()?.parse_with(parse_size)?);
+            }
+            Long("freeze") => {
+                freeze = true;
+            }
+            Long("shard") => {
+                shard = Some(parser.value()?.parse()?);
+            }
+            Long("skip") => {
+                skip = parser.value()?.parse()?;
+            }
+            Long("head") => {
+                head = Some(parser.value()?.parse()?);
+            }
+            Long("sample") => {
+                sample = Some(parser.value()?.parse()?);
+            }
+            Long("filter") => {
+                filters.push(parser.value()?.parse()?);
+            }
+            Long("filter-not") => {
+                filters_not.push(parser.value()?.parse()?);
+            }
+            Long("dedup") => {
+                dedup = true;
+            }
+            Long("shuf") => {
+                shuffle = true;
+            }
+            Long("schedule") => {
+                schedule = parser.value()?.parse()?;
+            }
+            Long("seed") => {
+                seed = Some(parser.value()?.parse()?);
+            }
+            Long("squash-repeats") => {
+                squash_repeats = true;
+            }
+            Long("tag") => {
+                tag = Some(String::from("{}"));
+            }
+            Long("tagstring") => {
+                tag = Some(parser.value()?.string()?);
+            }
+            Long("timeout") => {
+                timeout = Some(parser.value()?.parse_with(parse_duration)?);
+            }
+            Long("retries") => {
+                retries = parser.value()?.parse()?;
+            }
+            Long("timeout-total") => {
+                timeout_total = Some(parser.value()?.parse_with(parse_duration)?);
+            }
+            Long("bench") => {
+                bench = Some(parser.value()?.parse::<usize>()?.max(1));
+            }
+            Long("warmup") => {
+                warmup = parser.value()?.parse()?;
+            }
+            Long("retry-delay") => {
+                retry_delay = parser.value()?.parse_with(parse_duration)?;
+            }
+            Long("max-total-retries") => {
+                max_total_retries = Some(parser.value()?.parse()?);
+            }
+            Long("max-output") => {
+                max_output = Some(parser.value()?.parse_with(parse_size)?);
+            }
+            Long("flush-interval") => {
+                flush_interval = Some(parser.value()?.parse_with(parse_duration)?);
+            }
+            Long("max-fail-output") => {
+                max_fail_output = Some(parser.value()?.parse()?);
+            }
+            Long("output-encoding" | "encoding") => {
+                encoding = parser.value()?.parse()?;
+            }
+            Long("binary") => {
+                binary = true;
+                encoding = OutputEncoding::Bytes;
+            }
+            Long("group") => {
+                grouping = Grouping::Group;
+            }
+            Short('u') | Long("ungroup") => {
+                grouping = Grouping::Ungroup;
+            }
+            Long("line-buffer") => {
+                grouping = Grouping::LineBuffer;
+            }
+            Long("newline") => {
+                newline = Some(parser.value()?.parse()?);
+            }
+            Long("format") => {
+                format = parser.value()?.parse()?;
+            }
+            Long("output-file") => {
+                output_file = Some(parser.value()?);
+            }
+            Long("output") => {
+                output = Some(parser.value()?.string()?);
+            }
+            Long("output-err") => {
+                output_err = Some(parser.value()?.string()?);
+            }
+            Long("tee-output") => {
+                tee_output = true;
+            }
+            Long("pipe") => {
+                pipe = true;
+            }
+            Long("pipepart") => {
+                pipepart = Some(parser.value()?);
+            }
+            Long("block") => match parser.value()? {
+                value if value == "auto" => block_auto = true,
+                value => block_size = value.parse_with(parse_size)?.max(1),
+            },
+            Short('S') | Long("sshlogin") => {
+                sshlogins.extend(parser.value()?.parse_with(ssh::parse_logins)?);
+            }
+            Long("sshloginfile") => {
+                sshloginfile = Some(parser.value()?);
+            }
+            Long("host-check") => {
+                host_check = Some(parser.value()?.string()?);
+            }
+            Long("host-interval") => {
+                host_check_interval = parser.value()?.parse_with(parse_duration)?;
+            }
+            Long("host-timeout") => {
+                host_check_timeout = parser.value()?.parse_with(parse_duration)?;
+            }
+            Long("transfer") => {
+                transfer = true;
+            }
+            Long("return") => {
+                returns.push(parser.value()?.string()?);
+            }
+            Long("cleanup") => {
+                cleanup = true;
+            }
+            Long("bar") => {
+                bar = true;
+            }
+            Long("status-port") => {
+                status_port = Some(parser.value()?.parse_with(status::parse_address)?);
+            }
+            Long("tui") => {
+                tui = true;
+            }
+            Long("eta") => {
+                eta = true;
+            }
+            Long("cache") => {
+                cache = Some(parser.value()?);
+            }
+            Long("cache-inputs") => {
+                cache_inputs = true;
+            }
+            Long("cache-key") => {
+                cache_key = Some(parser.value()?.string()?);
+            }
+            Long("manifest") => {
+                manifest = Some(parser.value()?);
+            }
+            Long("joblog") => {
+                joblog = Some(parser.value()?);
+            }
+            Long("resume") | Long("retry-failed") => {
+                resume = true;
+            }
+            Long("results") => {
+                results = Some(parser.value()?);
+            }
+            Long("results-db") => {
+                results_db = Some(parser.value()?);
+            }
+            Long("on-result") => {
+                on_result = Some(parser.value()?.string()?);
+            }
+            Long("pre") => {
+                pre = Some(parser.value()?.string()?);
+            }
+            Long("post") => {
+                post = Some(parser.value()?.string()?);
+            }
+            Long("script") => {
+                script = Some(parser.value()?);
+            }
+            Long("after-tag") => {
+                after_tag = Some(parser.value()?.string()?);
+            }
+            Long("label-sep") => {
+                label_sep = Some(parser.value()?.string()?);
+            }
+            Long("otel-endpoint") => {
+                otel_endpoint = Some(parser.value()?.string()?);
+            }
+            Short('h') | Long("help") => {
+                println!("{HELP}");
+                process::exit(0);
+            }
+            Short('V') | Long("version") => {
+                println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"));
+                process::exit(0);
+            }
+            Value(value) if value.to_str().is_some_and(template::is_source_sep) => {
+                // Everything after the first ::: is an argument
+                clijobs.push(value.string()?);
+                for arg in parser.raw_args()? {
+                    clijobs.push(arg.string()?);
+                }
+            }
+            Value(value) => {
+                clijobs.push(value.string()?);
+            }
+            _ => return Err(arg.unexpected()),
+        }
+    }
+
+    Ok(Args {
+        quiet,
+        dryrun,
+        interactive,
+        verbose,
+        logfile,
+        log_required,
+        halt,
+        exit_status,
+        halt_on_spawn_error,
+        keep_order,
+        threads,
+        sem,
+        jobs_file,
+        shell,
+        shell_args,
+        shell_no_profile,
+        prologue,
+        files,
+        listen,
+        jobs_from,
+        watch,
+        watch_changed,
+        follow,
+        idle_exit,
+        null,
+        strict_lines,
+        colsep,
+        header,
+        link,
+        quote,
+        arg_files,
+        extract_lock,
+        max_args,
+        max_chars,
+        env_files,
+        wd,
+        env_vars,
+        clean_env,
+        locale,
+        tz,
+        source_cmd,
+        stdin,
+        foreground,
+        umask,
+        max_fds,
+        max_procs,
+        cpu_quota,
+        cgroup_memory,
+        freeze,
+        unshare,
+        container,
+        container_engine,
+        volumes,
+        private_tmp,
+        nice,
+        io_class,
+        cpus,
+        window,
+        delay,
+        rate,
+        load,
+        memfree,
+        memsuspend,
+        allow_enqueue,
+        echo,
+        shard,
+        skip,
+        head,
+        sample,
+        shuffle,
+        schedule,
+        dedup,
+        filters,
+        filters_not,
+        seed,
+        squash_repeats,
+        tag,
+        max_output,
+        timeout,
+        retries,
+        timeout_total,
+        bench,
+        warmup,
+        retry_delay,
+        max_total_retries,
+        flush_interval,
+        max_fail_output,
+        encoding,
+        binary,
+        grouping,
+        newline,
+        format,
+        output_file,
+        output,
+        output_err,
+        tee_output,
+        pipe,
+        pipepart,
+        block_size,
+        block_auto,
+        sshlogins,
+        sshloginfile,
+        host_check,
+        host_check_interval,
+        host_check_timeout,
+        transfer,
+        returns,
+        cleanup,
+        bar,
+        tui,
+        status_port,
+        eta,
+        on_result,
+        pre,
+        post,
+        manifest,
+        cache,
+        cache_inputs,
+        cache_key,
+        joblog,
+        resume,
+        results,
+        results_db,
+        script,
+        otel_endpoint,
+        label_sep,
+        after_tag,
+        clijobs,
+    })
+}
+
+fn create_logger(opts: &Args) -> Result<(), std::io::Error> {
+    let level = match (opts.quiet, opts.verbose) {
+        (true, _) => LevelFilter::Error,
+        (_, 0) => LevelFilter::Warn,
+        (_, 1) => LevelFilter::Info,
+        (_, 2) => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    // The dashboard shows what happens, only warnings and errors are logged
+    let term_level = if tui_enabled(opts) {
+        level.min(LevelFilter::Warn)
+    } else {
+        level
+    };
+
+    let logconfig = ConfigBuilder::new()
+        .set_time_format_rfc3339()
+        .set_time_offset_to_local()
+        .unwrap_or_else(|v| v)
+        .build();
+
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![output::Serialized::new(TermLogger::new(
+        term_level,
+        logconfig.clone(),
+        TerminalMode::Stderr,
         ColorChoice::Auto,
-    )];
+    ))];
+
+    if let Some(file) = opts.logfile.clone().map(PathBuf::from) {
+        loggers.push(WriteLogger::new(
+            level,
+            logconfig,
+            output::LogFile::open(file, opts.log_required)?,
+        ));
+    }
+
+    if CombinedLogger::init(loggers).is_err() {
+        error!("Could not initialize logger.");
+    };
+
+    Ok(())
+}
+
+// --tui falls back to the plain output unless both stdout and stderr are a
+// terminal
+fn tui_enabled(opts: &Args) -> bool {
+    opts.tui && !opts.dryrun && io::stdout().is_terminal() && io::stderr().is_terminal()
+}
+
+fn sample_jobs<'a, T: 'a>(
+    jobs: Box<dyn Iterator<Item = T> + 'a>,
+    sample: Sample,
+    seed: u64,
+) -> Box<dyn Iterator<Item = T> + 'a> {
+    debug!("Sampling jobs using seed {}", seed);
+    let mut rng = Rng::new(seed);
+    match sample {
+        Sample::Percent(p) => Box::new(jobs.filter(move |_| rng.fraction() * 100.0 < p)),
+        Sample::Count(n) => {
+            // Reservoir sampling, but keep the jobs in input order
+            let mut reservoir: Vec<(usize, T)> = Vec::with_capacity(n);
+            for (i, job) in jobs.enumerate() {
+                if i < n {
+                    reservoir.push((i, job));
+                } else {
+                    let j = rng.below(i + 1);
+                    if j < n {
+                        reservoir[j] = (i, job);
+                    }
+                }
+            }
+            reservoir.sort_unstable_by_key(|(i, _)| *i);
+            Box::new(reservoir.into_iter().map(|(_, job)| job))
+        }
+    }
+}
+
+// All jobs are read before the first one is started
+fn shuffle_jobs<'a, T: 'a>(
+    jobs: Box<dyn Iterator<Item = T> + 'a>,
+    seed: u64,
+) -> Box<dyn Iterator<Item = T> + 'a> {
+    debug!("Shuffling jobs using seed {}", seed);
+    let mut rng = Rng::new(seed.rotate_left(1));
+    let mut jobs: Vec<T> = jobs.collect();
+    // Fisher-Yates
+    for i in (1..jobs.len()).rev() {
+        jobs.swap(i, rng.below(i + 1));
+    }
+    Box::new(jobs.into_iter())
+}
+
+// Turn `CMD ::: A B ::: C D` into an argument set for the returned template
+// for every combination. Without `:::` the commands are returned as they are,
+// with the template for lines from --file or stdin if there is one
+#[allow(clippy::too_many_arguments)]
+fn expand_inputs(
+    clijobs: Vec<String>,
+    arg_files: &[OsString],
+    shell: Option<&std::ffi::OsStr>,
+    chunked: bool,
+    link: bool,
+    null: bool,
+    quote: bool,
+    header: bool,
+) -> (Vec<Vec<String>>, Option<Template>) {
+    let append_args = |mut command: Vec<String>| {
+        // Every word stays one word for the shell (--quote)
+        if quote {
+            if !command.iter().any(|word| Template::is_template(word)) {
+                command.push(String::from("{}"));
+            }
+            return Template::quoted(command, shell);
+        }
+        let mut command = command.join(" ");
+        if !Template::is_template(&command) {
+            command.push_str(" {}");
+        }
+        Template::new(command, shell)
+    };
+    let (mut command, sources) = template::split_sources(clijobs);
+    // The files of --arg-file come first, options are given before any :::
+    let arg_files = arg_files.iter().map(|file| template::InputSource {
+        args: vec![file.to_string_lossy().into_owned()],
+        linked: false,
+        files: true,
+    });
+    let mut sources: Vec<_> = arg_files
+        .chain(sources)
+        .flat_map(|source| {
+            if !source.files {
+                return vec![source];
+            }
+            let linked = source.linked;
+            source
+                .args
+                .into_iter()
+                .enumerate()
+                .map(|(i, file)| template::InputSource {
+                    args: read_arg_file(Path::new(&file), null),
+                    linked: linked && i == 0,
+                    files: false,
+                })
+                .collect()
+        })
+        .collect();
+    if link {
+        sources
+            .iter_mut()
+            .skip(1)
+            .for_each(|source| source.linked = true);
+    }
+    // With -N the input lines are arguments for the command
+    if sources.is_empty() && chunked && !command.is_empty() {
+        return (vec![], Some(append_args(command)));
+    }
+    if sources.is_empty() {
+        // With --header `{name}` placeholders are only known once it is read
+        let template = (command.len() == 1 && (header || Template::is_template(&command[0])))
+            .then(|| Template::new(command.remove(0), shell));
+        return (command.into_iter().map(|cmd| vec![cmd]).collect(), template);
+    }
+    if let Some(i) = sources.iter().position(|source| source.args.is_empty()) {
+        error!("Input source {} is empty", i + 1);
+        jobctl::exit(1);
+    }
+    let template = if command.is_empty() {
+        // The arguments are the commands
+        Template::raw(String::from("{}"))
+    } else {
+        append_args(command)
+    };
+    (template::product(&sources), Some(template))
+}
+
+// An input source with an argument per line (--arg-file, ::::), `-` is stdin
+fn read_arg_file(file: &Path, null: bool) -> Vec<String> {
+    let input: io::Result<Box<dyn BufRead>> = if file == Path::new("-") {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        decompress::open(file)
+    };
+    match input {
+        Ok(input) => records(input, null).collect(),
+        Err(e) => {
+            error!("Could not read arguments from '{}': {}", file.display(), e);
+            jobctl::exit(1);
+        }
+    }
+}
+
+// Where the job lines come from without jobs on the command line
+#[derive(Debug)]
+enum Lines {
+    Stdin,
+    // Read one after the other, `-` is stdin
+    Files(Vec<PathBuf>),
+    Follow(listen::Follow),
+    Listen(listen::Listener),
+    // The output of --jobs-from
+    Command(process::Child),
+}
+
+// Commands read like a shell script: empty lines and `#` comments are
+// skipped, a line ending with a backslash continues on the next one
+fn script_lines<'a>(
+    mut lines: Box<dyn Iterator<Item = String> + 'a>,
+) -> Box<dyn Iterator<Item = String> + 'a> {
+    Box::new(std::iter::from_fn(move || loop {
+        let mut line = lines.next()?;
+        let start = line.trim_start();
+        if start.is_empty() || (start.starts_with('#') && !start.starts_with("#[")) {
+            continue;
+        }
+        // An even number of backslashes are escaped backslashes
+        while line.bytes().rev().take_while(|&b| b == b'\\').count() % 2 == 1 {
+            line.pop();
+            match lines.next() {
+                Some(next) => line.push_str(&next),
+                None => break,
+            }
+        }
+        return Some(line);
+    }))
+}
+
+// `@high: CMD`, `@low: CMD` or `@N: CMD` with N a number set the priority
+// (0 otherwise), `@lock=KEY: CMD` the lock, e.g. `@high: @lock=db1: CMD`
+fn split_prefixes(mut line: String) -> (i64, Option<String>, String) {
+    let mut priority = 0;
+    let mut lock = None;
+    while let Some((prefix, command)) = line.strip_prefix('@').and_then(|rest| rest.split_once(':'))
+    {
+        match prefix {
+            "high" => priority = 1,
+            "low" => priority = -1,
+            _ => match (prefix.strip_prefix("lock="), prefix.parse()) {
+                (Some(key), _) if !key.is_empty() => lock = Some(key.to_string()),
+                (_, Ok(n)) => priority = n,
+                _ => break,
+            },
+        }
+        line = command.trim_start().to_string();
+    }
+    (priority, lock, line)
+}
+
+// `#[timeout=60,retries=2,shell=bash] CMD` overrides the global options for
+// this job, as well as cpu, memory, nice and umask, with the values of the
+// options
+fn split_directives(line: &str) -> Result<(Limits, &str), String> {
+    let mut limits = Limits::default();
+    let Some((directives, command)) = line
+        .strip_prefix("#[")
+        .and_then(|rest| rest.split_once(']'))
+    else {
+        return Ok((limits, line));
+    };
+    for directive in directives.split(',').map(str::trim) {
+        let Some((key, value)) = directive.split_once('=') else {
+            return Err(format!(
+                "invalid directive '{directive}', expected KEY=VALUE"
+            ));
+        };
+        let value = value.trim();
+        match key.trim() {
+            "timeout" => limits.timeout = Some(parse_duration(value)?),
+            "retries" => {
+                limits.retries = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid number of retries '{value}'"))?,
+                );
+            }
+            "shell" => limits.shell = Some(OsString::from(value)),
+            "cpu" => limits.cpu = Some(parse_cpu_quota(value)?),
+            "memory" => limits.memory = Some(parse_size(value)?),
+            "nice" => limits.nice = Some(parse_nice(value)?),
+            "umask" => limits.umask = Some(parse_umask(value)?),
+            key => {
+                return Err(format!(
+                "unknown directive '{key}', expected timeout, retries, shell, cpu, memory, nice or umask"
+            ))
+            }
+        }
+    }
+    Ok((limits, command.trim_start()))
+}
+
+// Lines, or NUL terminated records with -0. A record that is not UTF-8 is
+// skipped with a warning, rather than run with its bytes replaced.
+fn records<'a>(input: impl BufRead + 'a, null: bool) -> Box<dyn Iterator<Item = String> + 'a> {
+    let delimiter = if null { b'\0' } else { b'\n' };
+    Box::new(
+        input
+            .split(delimiter)
+            .map_while(Result::ok)
+            .filter_map(move |mut record| {
+                if !null && record.last() == Some(&b'\r') {
+                    record.pop();
+                }
+                String::from_utf8(record)
+                    .map_err(|e| {
+                        warn!(
+                            "Skipping input that is not UTF-8: {}",
+                            String::from_utf8_lossy(e.as_bytes())
+                        );
+                    })
+                    .ok()
+            }),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn add_jobs(
+    clijobs: Vec<Vec<String>>,
+    lines: Lines,
+    source: Option<&Source>,
+    selection: &Selection,
+    script: Option<&Script>,
+    label_sep: Option<&str>,
+    null: bool,
+    strict_lines: bool,
+    colsep: Option<&Regex>,
+    header: bool,
+    chunking: Option<Chunking>,
+    template: Option<&Template>,
+    extract_lock: Option<&Regex>,
+    dag: &dag::Dag,
+    tx: SyncSender<Job>,
+) -> Result<usize, std::io::Error> {
+    let start_job = |(
+        seq,
+        JobSpec {
+            cmd,
+            label,
+            after,
+            limits,
+            args,
+            priority,
+            deps,
+            lock,
+            lock_limit,
+            template,
+        },
+    )| {
+        debug!("Starting job '{}'", &cmd);
+        dag.add(label.as_deref());
+        if let (Some(key), Some(limit)) = (&lock, lock_limit) {
+            dag.limit(key, limit);
+        }
+        let lock = lock.or_else(|| {
+            let re = extract_lock?;
+            let found = re.captures(&cmd)?;
+            Some(found.get(1).or(found.get(0))?.as_str().to_string())
+        });
+        let job = Job {
+            seq,
+            cmd,
+            label,
+            after,
+            limits,
+            args,
+            input: None,
+            priority,
+            deps,
+            lock,
+            template,
+            attempt: 1,
+        };
+        if job.deps.is_empty() && job.lock.is_none() {
+            tx.send(job)
+                .unwrap_or_else(|e| error!("Could not add job: {}", e));
+        } else {
+            dag.hold(job);
+        }
+    };
+    // The template with the column names of --header filled in, once the
+    // first line is read
+    let named: OnceCell<Arc<Template>> = OnceCell::new();
+    let named = &named;
+    let template = template.map(|template| Arc::new(template.clone()));
+    let template = template.as_ref();
+    // With a template every input line is an argument for it, or its columns
+    // are the arguments with --colsep
+    let parse = move |line| {
+        let mut job = JobSpec::parse(line, label_sep);
+        if let Some(template) = named.get().or(template) {
+            job.args = match colsep {
+                Some(colsep) => colsep.split(&job.cmd).map(str::to_string).collect(),
+                None => vec![std::mem::take(&mut job.cmd)],
+            };
+            job.cmd = template.expand(&job.args);
+            job.template = Some(Arc::clone(template));
+        }
+        job
+    };
+    // Several arguments come from ::: input sources, one for each
+    let parse_args = move |mut args: Vec<String>| match template {
+        Some(template) if args.len() > 1 => JobSpec {
+            cmd: template.expand(&args),
+            label: None,
+            after: None,
+            limits: Limits::default(),
+            args,
+            priority: 0,
+            deps: vec![],
+            lock: None,
+            lock_limit: None,
+            template: Some(Arc::clone(template)),
+        },
+        _ => parse(args.remove(0)),
+    };
+    // The jobs of --file and --jobs-from run after the ones on the command line
+    let files = matches!(lines, Lines::Files(_) | Lines::Command(_));
+    let open = || -> io::Result<Box<dyn Iterator<Item = String>>> {
+        Ok(match lines {
+            Lines::Stdin => records(io::stdin().lock(), null),
+            Lines::Files(files) => {
+                // All of them are opened first, a missing file is not noticed
+                // after the jobs of the others have run
+                let mut inputs: Vec<Box<dyn BufRead>> = vec![];
+                for file in files {
+                    if file == Path::new("-") {
+                        inputs.push(Box::new(io::stdin().lock()));
+                    } else {
+                        let input = decompress::open(&file).map_err(|e| {
+                            io::Error::new(e.kind(), format!("'{}': {}", file.display(), e))
+                        })?;
+                        inputs.push(input);
+                    }
+                }
+                Box::new(
+                    inputs
+                        .into_iter()
+                        .flat_map(move |input| records(input, null)),
+                )
+            }
+            Lines::Follow(follow) => Box::new(follow),
+            Lines::Listen(listener) => Box::new(listener),
+            Lines::Command(mut child) => {
+                let stdout = child.stdout.take().map(BufReader::new);
+                // Waited for once its output ends
+                let exited = std::iter::once_with(move || {
+                    match child.wait() {
+                        Ok(status) if !status.success() => warn!("--jobs-from command {}", status),
+                        Ok(_) => {}
+                        Err(e) => warn!("Could not wait for --jobs-from command: {}", e),
+                    }
+                    None
+                });
+                Box::new(
+                    stdout
+                        .into_iter()
+                        .flat_map(move |stdout| records(stdout, null))
+                        .chain(exited.flatten()),
+                )
+            }
+        })
+    };
+    // With --header the first line names the columns, `{name}` in the
+    // template is the column of that name
+    let lines = || -> io::Result<Box<dyn Iterator<Item = String>>> {
+        let mut lines = open()?;
+        if let Some(template) = template.filter(|_| header) {
+            let names: Vec<String> = match (lines.next(), colsep) {
+                (Some(line), Some(colsep)) => colsep.split(&line).map(str::to_string).collect(),
+                (Some(line), None) => vec![line],
+                (None, _) => vec![],
+            };
+            debug!("Columns: {}", names.join(", "));
+            let _ = named.set(Arc::new(template.with_columns(&names)));
+        }
+        Ok(lines)
+    };
+    let line_jobs =
+        |lines: Box<dyn Iterator<Item = String>>| -> Box<dyn Iterator<Item = JobSpec> + '_> {
+            if strict_lines || template.is_some() {
+                // Arguments for a template are taken as they are
+                Box::new(lines.map(parse))
+            } else {
+                Box::new(script_lines(lines).filter_map(move |line| {
+                    let (limits, line) = match split_directives(&line) {
+                        Ok((limits, command)) => (limits, command.to_string()),
+                        Err(e) => {
+                            warn!("Ignoring job '{}': {}", line, e);
+                            return None;
+                        }
+                    };
+                    let (priority, lock, line) = split_prefixes(line);
+                    let (name, deps, line) = dag::split(line);
+                    let job = parse(line);
+                    Some(JobSpec {
+                        label: name.or(job.label),
+                        limits,
+                        priority,
+                        deps,
+                        lock,
+                        ..job
+                    })
+                }))
+            }
+        };
+    let duplicates = Cell::new(0);
+    let finished = Cell::new(0);
+    let mut jobs: Box<dyn Iterator<Item = JobSpec> + '_> = match (chunking, template) {
+        // Several arguments per job with -N/--max-chars
+        (Some(chunking), Some(template)) if source.is_none() || !clijobs.is_empty() => {
+            let args: Box<dyn Iterator<Item = String>> = if clijobs.is_empty() {
+                lines()?
+            } else if files {
+                Box::new(clijobs.into_iter().flatten().chain(lines()?))
+            } else {
+                Box::new(clijobs.into_iter().flatten())
+            };
+            let template = named.get().unwrap_or(template);
+            Box::new(chunking.chunks(args, template).map(|args| JobSpec {
+                cmd: template.expand(&args),
+                label: None,
+                after: None,
+                limits: Limits::default(),
+                args,
+                priority: 0,
+                deps: vec![],
+                lock: None,
+                lock_limit: None,
+                template: Some(Arc::clone(template)),
+            }))
+        }
+        _ if !clijobs.is_empty() && files => Box::new(
+            clijobs
+                .into_iter()
+                .map(parse_args)
+                .chain(line_jobs(lines()?)),
+        ),
+        // preferred
+        _ if !clijobs.is_empty() => Box::new(clijobs.into_iter().map(parse_args)),
+        _ => match source {
+            Some(source) => Box::new(source.jobs()),
+            None => line_jobs(lines()?),
+        },
+    };
+
+    if !selection.filters.is_empty() || !selection.filters_not.is_empty() {
+        jobs = Box::new(jobs.filter(|job| {
+            selection.filters.iter().all(|re| re.is_match(&job.cmd))
+                && !selection.filters_not.iter().any(|re| re.is_match(&job.cmd))
+        }));
+    }
+    // Jobs without arguments cannot be told apart and always run
+    if let Some(changed) = &selection.changed {
+        jobs = Box::new(jobs.filter(move |job| {
+            job.args.is_empty()
+                || job
+                    .args
+                    .iter()
+                    .any(|arg| changed.contains(&watch::absolute(Path::new(arg))))
+        }));
+    }
+    if !selection.finished.is_empty() {
+        let finished = &finished;
+        jobs = Box::new(jobs.filter(move |job| {
+            let done = selection.finished.contains(&job.cmd);
+            if done {
+                finished.set(finished.get() + 1);
+            }
+            !done
+        }));
+    }
+    // The first of them is kept
+    if selection.dedup {
+        let mut seen = HashSet::new();
+        let duplicates = &duplicates;
+        jobs = Box::new(jobs.filter(move |job| {
+            let first = seen.insert(job.cmd.clone());
+            if !first {
+                duplicates.set(duplicates.get() + 1);
+            }
+            first
+        }));
+    }
+    if let Some(shard) = selection.shard {
+        jobs = Box::new(jobs.filter(move |job| shard.contains(&job.cmd)));
+    }
+    if selection.skip > 0 {
+        jobs = Box::new(jobs.skip(selection.skip));
+    }
+    if let Some(head) = selection.head {
+        jobs = Box::new(jobs.take(head));
+    }
+    if let Some(sample) = selection.sample {
+        jobs = sample_jobs(jobs, sample, selection.seed);
+    }
+    if selection.shuffle {
+        jobs = shuffle_jobs(jobs, selection.seed);
+    }
+    if let Some(schedule) = &selection.schedule {
+        let mut all: Vec<_> = jobs.collect();
+        schedule.sort(&mut all, |job| &job.cmd);
+        jobs = Box::new(all.into_iter());
+    }
+
+    if let Some(script) = script {
+        jobs = Box::new(jobs.filter(|job| {
+            let accepted = script.accept(&job.cmd);
+            if !accepted {
+                debug!("Script rejected job '{}'", job.cmd);
+            }
+            accepted
+        }));
+        if script.has_priority() {
+            let mut prioritized: Vec<_> =
+                jobs.map(|job| (script.priority(&job.cmd), job)).collect();
+            prioritized.sort_by_key(|(priority, _)| std::cmp::Reverse(*priority));
+            jobs = Box::new(prioritized.into_iter().map(|(_, job)| job));
+        }
+    }
+
+    // All jobs have been queued now, so this is the total number of jobs
+    let total = jobs.map(|job| (dag.next_seq(), job)).map(start_job).count();
+    dag.input_done();
+    if duplicates.get() > 0 {
+        warn!("Skipped {} duplicate job(s)", duplicates.get());
+    }
+    if finished.get() > 0 {
+        info!("Skipped {} job(s) of the job log", finished.get());
+    }
+    Ok(total)
+}
+
+// `{#}` and `{%}` in the command of --pipe and --pipepart jobs
+fn pipe_template(command: &str) -> Option<Arc<Template>> {
+    Template::is_template(command).then(|| Arc::new(Template::raw(command.to_string())))
+}
+
+// Every block of stdin is a job running `command` (--pipe)
+fn add_blocks(
+    command: &str,
+    block_size: &pipe::BlockSize,
+    backlog: &pipe::Backlog,
+    tx: &SyncSender<Job>,
+) -> io::Result<usize> {
+    let mut stdin = io::stdin().lock();
+    let template = pipe_template(command);
+    let mut seq = 0;
+    while let Some(block) = pipe::read_block(&mut stdin, block_size.get())? {
+        backlog.push();
+        seq += 1;
+        debug!(
+            "Starting job '{}' with {} bytes of input",
+            command,
+            block.len()
+        );
+        tx.send(Job {
+            seq,
+            cmd: command.to_string(),
+            label: None,
+            after: None,
+            limits: Limits::default(),
+            args: vec![],
+            input: Some(pipe::Input::Block(block.into())),
+            priority: 0,
+            deps: vec![],
+            lock: None,
+            template: template.clone(),
+            attempt: 1,
+        })
+        .unwrap_or_else(|e| error!("Could not add job: {}", e));
+    }
+    Ok(seq)
+}
+
+// Every part of `path` is a job running `command` (--pipepart)
+fn add_parts(
+    command: &str,
+    path: &Path,
+    block_size: &pipe::BlockSize,
+    backlog: &pipe::Backlog,
+    tx: &SyncSender<Job>,
+) -> io::Result<usize> {
+    let file = File::open(path)?;
+    let path: Arc<Path> = path.into();
+    let template = pipe_template(command);
+    let (mut seq, mut start) = (0, 0);
+    while let Some(len) = pipe::next_part(&file, start, block_size.get())? {
+        backlog.push();
+        seq += 1;
+        debug!(
+            "Starting job '{}' with bytes {}..{} of '{}'",
+            command,
+            start,
+            start + len,
+            path.display()
+        );
+        tx.send(Job {
+            seq,
+            cmd: command.to_string(),
+            label: None,
+            after: None,
+            limits: Limits::default(),
+            args: vec![],
+            input: Some(pipe::Input::Part {
+                file: path.clone(),
+                start,
+                len,
+            }),
+            priority: 0,
+            deps: vec![],
+            lock: None,
+            template: template.clone(),
+            attempt: 1,
+        })
+        .unwrap_or_else(|e| error!("Could not add job: {}", e));
+        start += len;
+    }
+    Ok(seq)
+}
+
+#[cfg(target_os = "windows")]
+fn is_cmd_exe(shell: &std::ffi::OsStr) -> bool {
+    shell_name(shell).as_deref() == Some("cmd")
+}
+
+fn shell_command(
+    command: &str,
+    shell: &Option<OsString>,
+    shell_args: &[OsString],
+    no_profile: bool,
+) -> process::Command {
+    let mut cmd = build_command(command, shell, shell_args, no_profile);
+    jobctl::unblock_signals(&mut cmd);
+    cmd
+}
+
+// `bash` for `/bin/bash`, `pwsh` for `C:\...\pwsh.exe`
+fn shell_name(shell: &std::ffi::OsStr) -> Option<String> {
+    Path::new(shell)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_ascii_lowercase())
+}
+
+// The flag the command follows, PowerShell only knows -c as an abbreviation
+// of -Command
+fn command_flag(shell: &std::ffi::OsStr) -> &'static str {
+    match shell_name(shell).as_deref() {
+        Some("pwsh" | "powershell") => "-Command",
+        _ => "-c",
+    }
+}
+
+// Keep a shell from reading its startup files
+fn skip_profile(cmd: &mut process::Command, shell: &std::ffi::OsStr) {
+    match shell_name(shell).as_deref() {
+        Some("bash") => {
+            // Sourced by every non-interactive bash otherwise
+            cmd.args(["--noprofile", "--norc"]).env_remove("BASH_ENV");
+        }
+        Some("zsh") => {
+            cmd.args(["--no-rcs", "--no-globalrcs"]);
+        }
+        Some("fish") => {
+            cmd.arg("--no-config");
+        }
+        Some("pwsh" | "powershell") => {
+            cmd.args(["-NoProfile", "-NonInteractive"]);
+        }
+        _ => {}
+    }
+}
+
+fn build_command(
+    command: &str,
+    shell: &Option<OsString>,
+    shell_args: &[OsString],
+    no_profile: bool,
+) -> process::Command {
+    if let Some(s) = shell {
+        let mut shell = process::Command::new(s);
+        if no_profile {
+            skip_profile(&mut shell, s);
+        }
+        // Instead of -c (--shell-arg)
+        if !shell_args.is_empty() {
+            shell.args(shell_args).arg(command);
+            return shell;
+        }
+        #[cfg(target_os = "windows")]
+        if is_cmd_exe(s) {
+            use std::os::windows::process::CommandExt;
+            // cmd.exe does not follow the MSVCRT quoting rules, with /S it strips
+            // the outer quotes and runs everything in between as written
+            shell.raw_arg(format!("/D /S /C \"{command}\""));
+        } else {
+            shell.arg(command_flag(s)).arg(command);
+        }
+        #[cfg(not(target_os = "windows"))]
+        shell.arg(command_flag(s)).arg(command);
+        shell
+    } else {
+        let words = template::split_words(command);
+        // An empty command fails to start like any other that does not exist
+        let (program, args) = words
+            .split_first()
+            .map_or(("", &[][..]), |(program, args)| (program.as_str(), args));
+        let mut command = process::Command::new(program);
+        command.args(args);
+        command
+    }
+}
+
+// Where --echo prints the jobs, the log if stdout holds --format records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Echo {
+    Stdout,
+    Log,
+}
+
+// Settings shared by all workers when spawning jobs
+#[derive(Debug)]
+struct RunOptions {
+    dry_run: bool,
+    shell: Option<OsString>,
+    shell_args: Vec<OsString>,
+    shell_no_profile: bool,
+    prologue: Option<String>,
+    // Working directory of the jobs, filled in like the command
+    wd: Option<Template>,
+    // Where the output goes instead of being printed (--output, --output-err)
+    output: Option<Template>,
+    output_err: Option<Template>,
+    // Captured and written to the files once the job is done (--tee-output)
+    tee_output: bool,
+    // Without the environment of parallel-sh (--env, --clean-env)
+    clean_env: bool,
+    env: Vec<(String, String)>,
+    // --env-file paths with placeholders, read when a job is started
+    env_files: Vec<Template>,
+    stdin: StdinPolicy,
+    foreground: Option<Regex>,
+    foreground_taken: AtomicBool,
+    window: Option<Window>,
+    progress: Option<Arc<Progress>>,
+    // For --status-port and SIGUSR1
+    status: Arc<status::Status>,
+    confirm: Option<confirm::Confirm>,
+    delay: Option<Delay>,
+    rate: Option<Rate>,
+    load: Option<f64>,
+    memfree: Option<usize>,
+    // Suspend jobs instead of killing them (--memsuspend)
+    memsuspend: Option<usize>,
+    // Jobs may add jobs with ENQUEUE_PREFIX lines (--allow-enqueue)
+    allow_enqueue: bool,
+    // Print every job as it is started (--echo)
+    echo: Option<Echo>,
+    manifest: Option<Mutex<File>>,
+    // Told how long the --pipe jobs took (--block auto)
+    auto_block: Option<Arc<pipe::AutoBlock>>,
+    pre: Option<String>,
+    post: Option<String>,
+    cache: Option<cache::Cache>,
+    after_tag: Option<String>,
+    keep_order: bool,
+    max_output: Option<usize>,
+    // The output is passed on while the jobs run instead of captured
+    // (--ungroup, --line-buffer)
+    grouping: Grouping,
+    timeout: Option<Duration>,
+    retries: u32,
+    bench: Option<bench::Bench>,
+    retry_delay: Duration,
+    // Retries left for all jobs together (--max-total-retries)
+    retry_budget: Option<AtomicU64>,
+    // Failed jobs that were not retried because the budget was used up
+    retries_denied: AtomicUsize,
+    // Labels of the jobs that failed so far
+    failed_labels: Mutex<HashSet<String>>,
+    dag: Arc<dag::Dag>,
+    #[cfg_attr(target_os = "windows", allow(dead_code))]
+    umask: Option<u32>,
+    #[cfg_attr(target_os = "windows", allow(dead_code))]
+    max_fds: Option<u64>,
+    #[cfg_attr(target_os = "windows", allow(dead_code))]
+    max_procs: Option<u64>,
+    nice: Option<i32>,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    io_class: Option<IoClass>,
+    // The CPUs of the slots (--pin, --cpuset)
+    cpus: Vec<usize>,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    sandbox: Option<sandbox::Sandbox>,
+    container: Option<container::Container>,
+    // Hosts to run the jobs on (--sshlogin), otherwise they run locally
+    hosts: Option<Arc<ssh::Hosts>>,
+    transfer: bool,
+    // Files copied back from remote hosts, filled in like the command
+    returns: Vec<Template>,
+    cleanup: bool,
+    #[cfg(target_os = "linux")]
+    cgroups: Option<cgroup::Root>,
+    // Files written to each job's cgroup
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    cgroup_settings: Vec<(&'static str, String)>,
+    // Every job gets a cgroup, to be frozen (--freeze)
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    freeze_cgroups: bool,
+}
+
+impl RunOptions {
+    // Whether a failed job may be retried once more, counted against
+    // --max-total-retries
+    fn take_retry(&self) -> bool {
+        let Some(budget) = &self.retry_budget else {
+            return true;
+        };
+        let taken = budget
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok();
+        if !taken {
+            self.retries_denied.fetch_add(1, Ordering::SeqCst);
+        }
+        taken
+    }
+}
+
+// A temporary working directory (--wd ...), removed once dropped
+#[derive(Debug)]
+struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    fn create(seq: usize) -> io::Result<Self> {
+        let dir = std::env::temp_dir().join(format!("parallel-sh-{}-{}", process::id(), seq));
+        std::fs::create_dir(&dir)?;
+        Ok(ScratchDir(dir))
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.0) {
+            warn!("Could not remove '{}': {}", self.0.display(), e);
+        }
+    }
+}
+
+// Err if the job could not be started, otherwise its output and the timeout
+// it was stopped after
+fn run(
+    job: &Job,
+    slot: usize,
+    host: Option<&ssh::Slot>,
+    opts: &RunOptions,
+) -> io::Result<(Output, Option<Duration>)> {
+    let (command, limits) = (job.cmd.as_str(), &job.limits);
+    if opts.dry_run {
+        let output = Output {
+            status: ExitStatus::default(),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        };
+        return Ok((output, None));
+    };
+
+    let script = match &opts.prologue {
+        Some(prologue) => Cow::Owned(format!("{prologue}\n{command}")),
+        None => Cow::Borrowed(command),
+    };
+    let wd = opts
+        .wd
+        .as_ref()
+        .map(|wd| wd.expand_job(&job.args, job.seq, slot, job.attempt));
+    // Removed again when the job is done
+    let scratch = match wd.as_deref() {
+        Some(SCRATCH_DIR) => Some(ScratchDir::create(job.seq)?),
+        _ => None,
+    };
+    let wd = match (&scratch, wd) {
+        (Some(scratch), _) => Some(scratch.0.clone()),
+        (None, Some(wd)) => Some(PathBuf::from(wd)),
+        (None, None) => None,
+    };
+    let job_env = job_env(job, slot, opts)?;
+    // A shell of the job's own does not get the arguments of --shell
+    let (shell, shell_args) = match &limits.shell {
+        Some(shell) => (Some(shell.clone()), &[][..]),
+        None => (opts.shell.clone(), &opts.shell_args[..]),
+    };
+    let mut cmd = match (host.filter(|host| !host.is_local()), &opts.container) {
+        (Some(host), _) => {
+            let mut cmd = host.command(&script);
+            jobctl::unblock_signals(&mut cmd);
+            cmd
+        }
+        (None, Some(container)) => {
+            let shell = shell.as_ref().map(|shell| {
+                let mut words = vec![shell.clone()];
+                if shell_args.is_empty() {
+                    words.push(OsString::from(command_flag(shell)));
+                }
+                words.extend(shell_args.iter().cloned());
+                words
+            });
+            let vars = opts.env.iter().chain(&job_env).map(|(var, _)| var.as_str());
+            let mut cmd = container.command(&script, shell.as_deref(), wd.as_deref(), vars);
+            jobctl::unblock_signals(&mut cmd);
+            cmd
+        }
+        (None, None) => shell_command(&script, &shell, shell_args, opts.shell_no_profile),
+    };
+
+    // The container only gets the variables passed on explicitly, the engine
+    // needs the environment
+    if opts.clean_env && opts.container.is_none() {
+        cmd.env_clear();
+    }
+    if let Some(wd) = &wd {
+        if !wd.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no directory '{}'", wd.display()),
+            ));
+        }
+        cmd.current_dir(wd);
+    }
+    cmd.envs(opts.env.iter().map(|(k, v)| (k, v)));
+    cmd.envs(job_env);
+    cmd.env("PARALLEL_SEQ", job.seq.to_string())
+        .env("PARALLEL_SLOT", slot.to_string())
+        .env("PARALLEL_SH_ATTEMPT", job.attempt.to_string());
+
+    let foreground = opts.foreground.as_ref().is_some_and(|re| {
+        re.is_match(command) && !opts.foreground_taken.swap(true, Ordering::SeqCst)
+    });
+
+    if foreground {
+        debug!("Connecting '{}' to the terminal", command);
+        cmd.stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit());
+    } else if job.input.is_some() {
+        cmd.stdin(Stdio::piped());
+    } else {
+        match &opts.stdin {
+            StdinPolicy::Null | StdinPolicy::Closed => cmd.stdin(Stdio::null()),
+            StdinPolicy::Inherit => cmd.stdin(Stdio::inherit()),
+            StdinPolicy::File(path) => cmd.stdin(File::open(path)?),
+        };
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    if opts.stdin == StdinPolicy::Closed && !foreground && job.input.is_none() {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: close(2) is async-signal-safe
+        unsafe {
+            cmd.pre_exec(|| {
+                libc::close(0);
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    if let Some(mask) = limits.umask.or(opts.umask) {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: umask(2) is async-signal-safe and cannot fail
+        unsafe {
+            cmd.pre_exec(move || {
+                libc::umask(mask as libc::mode_t);
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    for (resource, limit) in [
+        (libc::RLIMIT_NOFILE, opts.max_fds),
+        (libc::RLIMIT_NPROC, opts.max_procs),
+    ] {
+        use std::os::unix::process::CommandExt;
+        let Some(limit) = limit else {
+            continue;
+        };
+        // SAFETY: getrlimit(2) and setrlimit(2) are plain syscalls on a local
+        unsafe {
+            cmd.pre_exec(move || {
+                let mut rlimit = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                if libc::getrlimit(resource, &mut rlimit) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Only root may raise the hard limit
+                let limit = (limit as libc::rlim_t).min(rlimit.rlim_max);
+                rlimit.rlim_cur = limit;
+                rlimit.rlim_max = limit;
+                if libc::setrlimit(resource, &rlimit) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    // A job's own niceness wins over --nice
+    let nice = limits.nice.or(opts.nice);
+    #[cfg(not(target_os = "windows"))]
+    if let Some(nice) = nice {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: setpriority(2) is a plain syscall without memory access
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::setpriority(libc::PRIO_PROCESS, 0, nice) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    #[cfg(target_os = "windows")]
+    if nice.is_some() || limits.cpu.is_some() || limits.memory.is_some() {
+        warn!(
+            "nice, cpu and memory limits are not supported on this platform, ignoring them for '{}'",
+            command
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(class) = opts.io_class {
+        use std::os::unix::process::CommandExt;
+        let ioprio = class.ioprio();
+        // SAFETY: ioprio_set(2) is a plain syscall without memory access
+        unsafe {
+            cmd.pre_exec(move || {
+                const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+                if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    let cpu = affinity::cpu(&opts.cpus, slot);
+    if let Some(cpu) = cpu {
+        affinity::pin(&mut cmd, cpu);
+    }
+
+    // Lives until the job has finished
+    #[cfg(target_os = "linux")]
+    let _cgroup = job_cgroup(&mut cmd, command, limits, opts);
+    #[cfg(target_os = "macos")]
+    if limits.cpu.is_some() || limits.memory.is_some() {
+        warn!(
+            "cpu and memory limits are not supported on this platform, ignoring them for '{}'",
+            command
+        );
+    }
+
+    if !foreground {
+        let (stdout, stderr) = output_files(job, slot, opts)?;
+        cmd.stdout(stdout).stderr(stderr);
+    }
+    // Jobs sharing the terminal have to stay in its foreground process group
+    let group = !foreground && opts.stdin != StdinPolicy::Inherit;
+    jobctl::configure(&mut cmd, group);
+    if let Some(sandbox) = opts
+        .sandbox
+        .filter(|_| host.is_none_or(ssh::Slot::is_local))
+    {
+        sandbox.apply(&mut cmd);
+    }
+
+    let timeout = limits.timeout.or(opts.timeout);
+    let (output, timed_out) = loop {
+        let mut child = cmd.spawn()?;
+        if let Some(cpu) = cpu {
+            affinity::pin_child(&child, cpu);
+        }
+        let pid = child.id();
+        if let (Some(input), Some(mut stdin)) = (job.input.clone(), child.stdin.take()) {
+            // Jobs may exit without reading all of it
+            thread::spawn(move || {
+                let _ = input.write_to(&mut stdin);
+            });
+        }
+        jobctl::register(&child, group);
+        let watchdog = timeout.map(|timeout| jobctl::Watchdog::start(pid, group, timeout));
+        let output = wait_with_output(child, opts.max_output, opts.grouping);
+        let timed_out = if watchdog.is_some_and(jobctl::Watchdog::finish) {
+            timeout
+        } else {
+            None
+        };
+        jobctl::unregister(pid);
+        // Killed to free memory (--memfree), started again once there is enough
+        if !jobctl::evicted(pid) || jobctl::stopping() {
+            break (output, timed_out);
+        }
+        warn!(
+            "'{}' was killed to free memory, it is run again later",
+            command
+        );
+        if let Some(memfree) = opts.memfree {
+            load::wait_for_memory(memfree);
+        }
+    };
+
+    let mut output = output.unwrap_or_else(|e| {
+        error!("Could not wait for '{}': {}", command, e);
+        Output {
+            status: exit_status(1),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }
+    });
+    if timed_out.is_some() {
+        output.status = exit_status(TIMED_OUT);
+    }
+    if opts.tee_output && !foreground {
+        if let Err(e) = tee_to_files(job, slot, opts, &output) {
+            warn!("Could not write the output of '{}': {}", command, e);
+        }
+    }
+    Ok((output, timed_out))
+}
+
+// The variables of the --env-file paths with placeholders, filled in for the
+// job, e.g. `envs/{1}.env`
+fn job_env(job: &Job, slot: usize, opts: &RunOptions) -> io::Result<Vec<(String, String)>> {
+    let mut vars = vec![];
+    for file in &opts.env_files {
+        let path = PathBuf::from(file.expand_job(&job.args, job.seq, slot, job.attempt));
+        vars.extend(env::read_file(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("could not read env file '{}': {}", path.display(), e),
+            )
+        })?);
+    }
+    Ok(vars)
+}
+
+// A file of --output or --output-err, filled in for the job
+fn output_file(template: &Template, job: &Job, slot: usize) -> io::Result<File> {
+    let path = PathBuf::from(template.expand_job(&job.args, job.seq, slot, job.attempt));
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    File::create(&path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("could not create '{}': {}", path.display(), e),
+        )
+    })
+}
+
+// The job's output goes to the files of --output and --output-err, stderr
+// along with stdout without --output-err, and is captured otherwise (and with
+// --tee-output)
+fn output_files(job: &Job, slot: usize, opts: &RunOptions) -> io::Result<(Stdio, Stdio)> {
+    if opts.tee_output {
+        return Ok((Stdio::piped(), Stdio::piped()));
+    }
+    let create = |template: &Template| output_file(template, job, slot);
+    let stdout = opts.output.as_ref().map(create).transpose()?;
+    let stderr = match (&opts.output_err, &stdout) {
+        (Some(output_err), _) => Some(create(output_err)?),
+        (None, Some(stdout)) => Some(stdout.try_clone()?),
+        (None, None) => None,
+    };
+    Ok((
+        stdout.map_or_else(Stdio::piped, Stdio::from),
+        stderr.map_or_else(Stdio::piped, Stdio::from),
+    ))
+}
+
+// The captured output of a job that is printed as well (--tee-output), stderr
+// follows stdout without --output-err
+fn tee_to_files(job: &Job, slot: usize, opts: &RunOptions, output: &Output) -> io::Result<()> {
+    let Some(template) = &opts.output else {
+        return Ok(());
+    };
+    let mut stdout = output_file(template, job, slot)?;
+    stdout.write_all(&output.stdout)?;
+    match &opts.output_err {
+        Some(template) => output_file(template, job, slot)?.write_all(&output.stderr),
+        None => stdout.write_all(&output.stderr),
+    }
+}
+
+// The arguments of a job which are local files, copied to its host
+fn transfer_inputs(slot: &ssh::Slot, args: &[String]) -> Vec<String> {
+    args.iter()
+        .filter(|arg| Path::new(arg).is_file())
+        .filter(|file| match slot.transfer(file) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Could not transfer '{}' to {}: {}", file, slot.host(), e);
+                false
+            }
+        })
+        .cloned()
+        .collect()
+}
+
+// Fetch the --return files of a remote job, then --cleanup
+fn return_outputs(slot: &ssh::Slot, args: &[String], transferred: Vec<String>, opts: &RunOptions) {
+    let returned: Vec<String> = opts.returns.iter().map(|file| file.expand(args)).collect();
+    for file in &returned {
+        if let Err(e) = slot.fetch(file) {
+            warn!("Could not return '{}' from {}: {}", file, slot.host(), e);
+        }
+    }
+    let mut files = transferred;
+    files.extend(returned);
+    if opts.cleanup && !files.is_empty() {
+        if let Err(e) = slot.remove(&files) {
+            warn!("Could not clean up on {}: {}", slot.host(), e);
+        }
+    }
+}
+
+// A cgroup with the global settings and the job's own CPU and memory limits
+#[cfg(target_os = "linux")]
+fn job_cgroup(
+    cmd: &mut process::Command,
+    command: &str,
+    limits: &Limits,
+    opts: &RunOptions,
+) -> Option<cgroup::Job> {
+    let mut settings = opts.cgroup_settings.clone();
+    if let Some(cpu) = limits.cpu {
+        settings.retain(|(file, _)| *file != "cpu.max");
+        settings.push(("cpu.max", cpu_max(cpu)));
+    }
+    if let Some(memory) = limits.memory {
+        settings.retain(|(file, _)| *file != "memory.max");
+        settings.push(("memory.max", memory.to_string()));
+    }
+    if settings.is_empty() && !opts.freeze_cgroups {
+        return None;
+    }
+    let root = opts.cgroups.as_ref().filter(|root| {
+        settings
+            .iter()
+            .all(|(file, _)| file.split('.').next().is_some_and(|c| root.enable(c)))
+    });
+    let Some(root) = root else {
+        warn!(
+            "cgroups are not available, ignoring the cpu and memory limits of '{}'",
+            command
+        );
+        return None;
+    };
+    match root.create(&settings) {
+        Ok(cgroup) => {
+            cgroup.attach(cmd);
+            Some(cgroup)
+        }
+        Err(e) => {
+            warn!("Could not create cgroup for '{}': {}", command, e);
+            None
+        }
+    }
+}
+
+// Like `Child::wait_with_output`, but with a bounded amount of output kept
+fn wait_with_output(
+    mut child: process::Child,
+    limit: Option<usize>,
+    grouping: Grouping,
+) -> io::Result<Output> {
+    // Nothing is left to print once the job is done
+    let read = move |stream: &mut dyn io::Read, stderr: bool| match grouping {
+        Grouping::Group => output::capture(stream, limit),
+        _ => output::forward(stream, stderr, grouping).map(|()| Vec::new()),
+    };
+    let stdout = child
+        .stdout
+        .take()
+        .map(|mut stdout| {
+            thread::Builder::new()
+                .stack_size(WORKER_STACK_SIZE)
+                .spawn(move || read(&mut stdout, false))
+        })
+        .transpose()?;
+    let stderr = match child.stderr.take() {
+        Some(mut stderr) => read(&mut stderr, true)?,
+        None => Vec::new(),
+    };
+    let status = child.wait()?;
+    let stdout = match stdout.map(thread::JoinHandle::join) {
+        Some(Ok(stdout)) => stdout?,
+        Some(Err(_)) => return Err(io::Error::other("output reader panicked")),
+        None => Vec::new(),
+    };
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+#[cfg(not(target_os = "windows"))]
+fn exit_status(code: i32) -> ExitStatus {
+    ExitStatus::from_raw(code << 8)
+}
+
+#[cfg(target_os = "windows")]
+#[allow(clippy::cast_sign_loss)]
+fn exit_status(code: i32) -> ExitStatus {
+    ExitStatus::from_raw(code as u32)
+}
+
+// Exit code reported for a job that could not be started, as a shell would
+fn spawn_error_code(error: &io::Error) -> i32 {
+    match error.kind() {
+        io::ErrorKind::NotFound => 127,
+        _ => 126,
+    }
+}
+
+fn spawn_error_kind(error: &io::Error) -> &'static str {
+    match error.kind() {
+        io::ErrorKind::NotFound => "not_found",
+        io::ErrorKind::PermissionDenied => "permission_denied",
+        _ => "other",
+    }
+}
+
+// Jobs that fit into the open file limit: each one keeps its stdout and
+// stderr pipes open, and needs a few more files while it is started
+#[cfg(not(target_os = "windows"))]
+fn max_parallel_jobs() -> Option<usize> {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit(2) only writes to the local
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) } < 0
+        || rlimit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    let files = usize::try_from(rlimit.rlim_cur).ok()?;
+    Some((files.saturating_sub(RESERVED_FILES) / FILES_PER_JOB).max(1))
+}
+
+// Workers are only added while all others are busy, so a large -j costs
+// threads for the jobs running at the same time, not for every slot
+#[derive(Debug)]
+struct Pool {
+    // Can change while running (--jobs-file)
+    max: AtomicUsize,
+    started: AtomicUsize,
+    idle: AtomicUsize,
+    // Slot numbers of the jobs running (from 1), workers beyond a lowered
+    // max wait for a free slot
+    running: Mutex<BTreeSet<usize>>,
+    slot_freed: Condvar,
+}
+
+impl Pool {
+    // Called by a worker taking a job, true if another worker should be added
+    fn take(&self) -> bool {
+        self.idle.fetch_sub(1, Ordering::SeqCst) == 1 && self.grow()
+    }
+
+    // True if another worker should be added, unless there are enough
+    fn grow(&self) -> bool {
+        self.started
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < self.max.load(Ordering::SeqCst)).then_some(n + 1)
+            })
+            .is_ok()
+    }
+
+    // Wait for a slot to run a job in, the lowest free one
+    fn acquire(&self) -> Running<'_> {
+        let mut running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        while running.len() >= self.max.load(Ordering::SeqCst) {
+            running = self
+                .slot_freed
+                .wait(running)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        let slot = (1..).find(|slot| !running.contains(slot)).unwrap_or(1);
+        running.insert(slot);
+        Running(self, slot)
+    }
+
+    fn resize(&self, max: usize) {
+        let _running = self.running.lock().unwrap_or_else(PoisonError::into_inner);
+        self.max.store(max.max(1), Ordering::SeqCst);
+        self.slot_freed.notify_all();
+    }
+}
+
+// Frees the slot once dropped
+struct Running<'a>(&'a Pool, usize);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        let mut running = self
+            .0
+            .running
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        running.remove(&self.1);
+        self.0.slot_freed.notify_one();
+    }
+}
+
+// Marks the worker as idle again once dropped
+struct Busy<'a>(&'a Pool);
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.0.idle.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+fn start_workers(
+    threads: usize,
+    jobs: &SharedReceiver<Job>,
+    results: Sender<JobResult>,
+    opts: &Arc<RunOptions>,
+    jobs_file: Option<PathBuf>,
+) {
+    if opts.dry_run {
+        debug!("Perform a trial run with no changes made");
+    }
+    debug!("Running up to {} jobs at a time", threads);
+    let pool = Arc::new(Pool {
+        max: AtomicUsize::new(threads.max(1)),
+        started: AtomicUsize::new(1),
+        idle: AtomicUsize::new(0),
+        running: Mutex::new(BTreeSet::new()),
+        slot_freed: Condvar::new(),
+    });
+    let results = Arc::new(results);
+    if let Some(path) = jobs_file {
+        watch_jobs_file(path, jobs, Arc::downgrade(&results), opts, &pool);
+    }
+    spawn_worker(jobs, &results, opts, &pool);
+}
+
+// Re-read the number of jobs from --jobs-file every second. The results
+// channel closes once the last worker is done, which ends the watch.
+fn watch_jobs_file(
+    path: PathBuf,
+    jobs: &SharedReceiver<Job>,
+    results: Weak<Sender<JobResult>>,
+    opts: &Arc<RunOptions>,
+    pool: &Arc<Pool>,
+) {
+    let jobs = jobs.clone();
+    let opts = Arc::clone(opts);
+    let pool = Arc::clone(pool);
+    thread::spawn(move || {
+        let mut current = String::new();
+        while let Some(results) = results.upgrade() {
+            let content = std::fs::read_to_string(&path).unwrap_or_default();
+            let content = content.trim();
+            if !content.is_empty() && content != current {
+                current = content.to_string();
+                match parse_jobs(content) {
+                    Ok(max) => {
+                        #[cfg(not(target_os = "windows"))]
+                        let max = max_parallel_jobs().map_or(max, |files| max.min(files));
+                        info!("Running up to {} jobs at a time", max);
+                        pool.resize(max);
+                        // Busy workers would only add more once they are done
+                        if pool.idle.load(Ordering::SeqCst) == 0 && pool.grow() {
+                            spawn_worker(&jobs, &results, &opts, &pool);
+                        }
+                    }
+                    Err(e) => warn!("Ignoring {}: {}", path.display(), e),
+                }
+            }
+            drop(results);
+            thread::sleep(Duration::from_secs(1));
+        }
+    });
+}
+
+// Like run(), with a job that could not be started as a failed one
+fn run_once(
+    job: &Job,
+    slot: usize,
+    host: Option<&ssh::Slot>,
+    opts: &RunOptions,
+) -> (Output, Option<Duration>, Option<io::Error>) {
+    match run(job, slot, host, opts) {
+        Ok((output, timed_out)) => (output, timed_out, None),
+        Err(e) => (
+            Output {
+                status: exit_status(spawn_error_code(&e)),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            },
+            None,
+            Some(e),
+        ),
+    }
+}
+
+// Lines of a job's stdout starting with ENQUEUE_PREFIX are jobs to run as
+// well, they are taken out of its output
+fn enqueue_follow_ups(output: &mut Output, opts: &RunOptions) {
+    if !output
+        .stdout
+        .windows(ENQUEUE_PREFIX.len())
+        .any(|w| w == ENQUEUE_PREFIX.as_bytes())
+    {
+        return;
+    }
+    let mut stdout = Vec::with_capacity(output.stdout.len());
+    let mut added = 0;
+    for line in output.stdout.split_inclusive(|&b| b == b'\n') {
+        let Some(cmd) = line.strip_prefix(ENQUEUE_PREFIX.as_bytes()) else {
+            stdout.extend_from_slice(line);
+            continue;
+        };
+        let cmd = String::from_utf8_lossy(cmd).trim().to_string();
+        if cmd.is_empty() {
+            continue;
+        }
+        debug!("Adding follow-up job '{}'", cmd);
+        opts.dag.follow_up(Job {
+            seq: opts.dag.next_seq(),
+            cmd,
+            label: None,
+            after: None,
+            limits: Limits::default(),
+            args: vec![],
+            input: None,
+            priority: 0,
+            deps: vec![],
+            lock: None,
+            template: None,
+            attempt: 1,
+        });
+        added += 1;
+    }
+    output.stdout = stdout;
+    if let Some(progress) = &opts.progress {
+        progress.add_to_total(added);
+    }
+    opts.status.add_to_total(added);
+}
+
+// A job that is not run at all, jobs waiting for it are skipped as well
+// unless it `succeeded` before (--cache)
+fn skip(job: Job, results: &Sender<JobResult>, opts: &RunOptions, succeeded: bool) {
+    opts.dag.finished(&job, succeeded);
+    // Otherwise later results would wait for it forever
+    if opts.keep_order {
+        results
+            .send(JobResult {
+                seq: job.seq,
+                duration: Duration::ZERO,
+                job: job.cmd,
+                label: job.label,
+                output: Output {
+                    status: ExitStatus::default(),
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                },
+                spawn_error: None,
+                skipped: true,
+                timed_out: None,
+                attempts: 0,
+                started: SystemTime::now(),
+                host: None,
+                timings: Vec::new(),
+                args: job.args,
+            })
+            .unwrap_or_else(|e| error!("Could not send job: {}", e));
+    }
+}
+
+fn spawn_worker(
+    jobs: &SharedReceiver<Job>,
+    results: &Arc<Sender<JobResult>>,
+    opts: &Arc<RunOptions>,
+    pool: &Arc<Pool>,
+) {
+    let jobs = jobs.clone();
+    let results = results.clone();
+    let opts = Arc::clone(opts);
+    let pool = Arc::clone(pool);
+    pool.idle.fetch_add(1, Ordering::SeqCst);
+    let worker = thread::Builder::new()
+        .stack_size(WORKER_STACK_SIZE)
+        .spawn(move || {
+            for mut job in jobs.clone() {
+                if pool.take() {
+                    spawn_worker(&jobs, &results, &opts, &pool);
+                }
+                let _busy = Busy(&pool);
+                let running = pool.acquire();
+                if jobctl::stopping() {
+                    break;
+                }
+                // Before the slot number is filled in, it differs from run to run
+                let cached = opts.cache.as_ref().map(|cache| {
+                    let text = cache.key_text(&job.cmd, &job.args);
+                    (cache, cache.key(&text, &job.args), text)
+                });
+                job.fill_in(running.1);
+                // Running jobs are left alone once the window closes
+                if let Some(window) = opts.window {
+                    window.wait();
+                    if jobctl::stopping() {
+                        break;
+                    }
+                }
+                let after = job.after.as_deref().or_else(|| {
+                    opts.after_tag
+                        .as_deref()
+                        .filter(|&tag| job.label.as_deref() != Some(tag))
+                });
+                if let Some(tag) = after {
+                    if opts.failed_labels.lock().unwrap().contains(tag) {
+                        warn!("Skipping '{}', a job labelled '{}' failed", job.cmd, tag);
+                        skip(job, &results, &opts, false);
+                        continue;
+                    }
+                }
+                if let Some(dep) = opts.dag.failed_dependency(&job.deps) {
+                    warn!("Skipping '{}', job '{}' did not succeed", job.cmd, dep);
+                    skip(job, &results, &opts, false);
+                    continue;
+                }
+                if let Some((cache, key, command)) = &cached {
+                    if cache.contains(key, command) {
+                        info!("Skipping '{}', it succeeded before (cached)", job.cmd);
+                        skip(job, &results, &opts, true);
+                        continue;
+                    }
+                }
+                if let Some(confirm) = &opts.confirm {
+                    match confirm.ask(&job.cmd) {
+                        confirm::Answer::Run => {}
+                        confirm::Answer::Skip => {
+                            skip(job, &results, &opts, false);
+                            continue;
+                        }
+                        confirm::Answer::Quit => {
+                            jobctl::stop_starting();
+                            break;
+                        }
+                    }
+                }
+                if let Some(manifest) = &opts.manifest {
+                    let entry = json::Object::new()
+                        .field("seq", job.seq)
+                        .field("cmd", &job.cmd)
+                        .field("label", &job.label)
+                        .build();
+                    if let Err(e) = writeln!(manifest.lock().unwrap(), "{entry}") {
+                        warn!("Could not write to manifest: {}", e);
+                    }
+                }
+                let slot = opts.hosts.as_deref().map(ssh::Hosts::acquire);
+                let remote = slot
+                    .as_ref()
+                    .filter(|slot| !slot.is_local() && !opts.dry_run);
+                if let Some(load) = opts.load.filter(|_| !opts.dry_run) {
+                    load::wait(load);
+                    if jobctl::stopping() {
+                        break;
+                    }
+                }
+                if let Some(memfree) = opts.memfree.filter(|_| !opts.dry_run) {
+                    load::wait_for_memory(memfree);
+                    if jobctl::stopping() {
+                        break;
+                    }
+                }
+                if let Some(memsuspend) = opts.memsuspend.filter(|_| !opts.dry_run) {
+                    load::wait_for_suspended(memsuspend);
+                    if jobctl::stopping() {
+                        break;
+                    }
+                }
+                if let Some(delay) = opts.delay.as_ref().filter(|_| !opts.dry_run) {
+                    delay.wait();
+                }
+                if let Some(rate) = opts.rate.as_ref().filter(|_| !opts.dry_run) {
+                    rate.wait();
+                    if jobctl::stopping() {
+                        break;
+                    }
+                }
+                let transferred = match remote {
+                    Some(slot) if opts.transfer => transfer_inputs(slot, &job.args),
+                    _ => vec![],
+                };
+                if let Some(pre) = opts.pre.as_deref().filter(|_| !opts.dry_run) {
+                    hook("--pre", pre, &job, running.1, None, &opts);
+                }
+                let starttime = Instant::now();
+                let started = SystemTime::now();
+                let retries = job.limits.retries.unwrap_or(opts.retries);
+                let mut attempts = 0;
+                if let Some(progress) = &opts.progress {
+                    progress.job_started(running.1, &job.cmd);
+                }
+                opts.status.job_started(running.1, &job.cmd);
+                match opts.echo {
+                    Some(Echo::Stdout) => {
+                        output::print_stdout(&format!(
+                            "[#{} %{}] {}\n",
+                            job.seq, running.1, job.cmd
+                        ));
+                    }
+                    Some(Echo::Log) => info!(
+                        "Started job {} in slot {}: '{}'",
+                        job.seq, running.1, job.cmd
+                    ),
+                    None => {}
+                }
+                let (mut output, mut timed_out, mut spawn_error) = loop {
+                    attempts += 1;
+                    if attempts > 1 {
+                        job.attempt = attempts;
+                        job.fill_in(running.1);
+                    }
+                    let (output, timed_out, spawn_error) =
+                        run_once(&job, running.1, slot.as_ref(), &opts);
+                    let failed = spawn_error.is_some() || !output.status.success();
+                    if !failed || attempts > retries || jobctl::stopping() {
+                        break (output, timed_out, spawn_error);
+                    }
+                    if !opts.take_retry() {
+                        debug!("'{}' failed, no retries left for the run", job.cmd);
+                        break (output, timed_out, spawn_error);
+                    }
+                    // Doubled after every attempt
+                    let delay = opts
+                        .retry_delay
+                        .saturating_mul(2u32.saturating_pow(attempts - 1));
+                    let reason = match &spawn_error {
+                        Some(e) => format!("could not be started: {e}"),
+                        None if timed_out.is_some() => String::from("timed out"),
+                        None => output.status.to_string(),
+                    };
+                    let wait = if delay.is_zero() {
+                        String::new()
+                    } else {
+                        format!(" in {delay:.1?}")
+                    };
+                    warn!(
+                        "'{}' failed ({}), retrying{} (attempt {} of {})",
+                        job.cmd,
+                        reason,
+                        wait,
+                        attempts + 1,
+                        retries + 1
+                    );
+                    thread::sleep(delay);
+                };
+                // The first run was a warm-up, or the first one measured if
+                // it needed no retries
+                let mut timings = vec![];
+                if let Some(bench) = opts.bench.filter(|_| !opts.dry_run) {
+                    let mut runs = 1;
+                    if bench.warmup == 0 && attempts == 1 {
+                        timings.push(starttime.elapsed());
+                    }
+                    while timings.len() < bench.runs
+                        && spawn_error.is_none()
+                        && output.status.success()
+                        && !jobctl::stopping()
+                    {
+                        let run_started = Instant::now();
+                        (output, timed_out, spawn_error) =
+                            run_once(&job, running.1, slot.as_ref(), &opts);
+                        runs += 1;
+                        if runs > bench.warmup {
+                            timings.push(run_started.elapsed());
+                        }
+                    }
+                }
+                if let Some(progress) = &opts.progress {
+                    progress.job_stopped(running.1);
+                }
+                opts.status.job_stopped(running.1);
+                if let Some(slot) = remote {
+                    return_outputs(slot, &job.args, transferred, &opts);
+                }
+                let duration = starttime.elapsed();
+                if let (Some(auto_block), Some(input)) = (&opts.auto_block, &job.input) {
+                    auto_block.finished(input.len(), duration);
+                }
+                if let Some(post) = opts.post.as_deref().filter(|_| !opts.dry_run) {
+                    hook("--post", post, &job, running.1, output.status.code(), &opts);
+                }
+                // Recorded right away, so the next job started by this worker sees it
+                let failed = spawn_error.is_some() || !output.status.success();
+                if failed {
+                    if let Some(label) = &job.label {
+                        opts.failed_labels.lock().unwrap().insert(label.clone());
+                    }
+                }
+                if opts.allow_enqueue {
+                    enqueue_follow_ups(&mut output, &opts);
+                }
+                opts.dag.finished(&job, !failed);
+                if let Some((cache, key, command)) = &cached {
+                    if !failed && !opts.dry_run {
+                        if let Err(e) = cache.insert(key, command) {
+                            warn!("Could not cache '{}': {}", job.cmd, e);
+                        }
+                    }
+                }
+                results
+                    .send(JobResult {
+                        seq: job.seq,
+                        duration,
+                        job: job.cmd,
+                        label: job.label,
+                        output,
+                        spawn_error,
+                        skipped: false,
+                        timed_out,
+                        attempts,
+                        started,
+                        host: slot
+                            .filter(|slot| !slot.is_local())
+                            .map(|slot| slot.host().to_string()),
+                        timings,
+                        args: job.args,
+                    })
+                    .unwrap_or_else(|e| error!("Could not send job: {}", e));
+            }
+        });
+    // Like thread::spawn, there is no sensible way to go on without it
+    worker.expect("failed to spawn a worker thread");
+}
+
+#[cfg(not(target_os = "windows"))]
+fn signal(status: ExitStatus) -> Option<i32> {
+    status.signal()
+}
+
+#[cfg(target_os = "windows")]
+fn signal(_status: ExitStatus) -> Option<i32> {
+    None
+}
+
+// A finished job for --format json
+fn json_record(result: &JobResult) -> String {
+    let start_time = result
+        .started
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    json::Object::new()
+        .field("job", &result.job)
+        .field("host", &result.host)
+        .field("exit_code", result.output.status.code())
+        .field("attempts", result.attempts)
+        .field(
+            "duration_ms",
+            u64::try_from(result.duration.as_millis()).unwrap_or(u64::MAX),
+        )
+        .field(
+            "stdout",
+            String::from_utf8_lossy(&result.output.stdout).as_ref(),
+        )
+        .field(
+            "stderr",
+            String::from_utf8_lossy(&result.output.stderr).as_ref(),
+        )
+        .field("start_time", start_time)
+        .build()
+}
+
+// A finished job for --format csv and tsv, with the columns of csv::HEADER
+fn csv_record(result: &JobResult, separator: csv::Separator) -> String {
+    csv::record(
+        &[
+            result.seq.to_string(),
+            result.job.clone(),
+            result
+                .output
+                .status
+                .code()
+                .map_or_else(String::new, |code| code.to_string()),
+            result.duration.as_millis().to_string(),
+            csv::cell(&result.output.stdout),
+            csv::cell(&result.output.stderr),
+        ],
+        separator,
+    )
+}
+
+// --pre and --post, failures are only reported
+fn hook(name: &str, hook: &str, job: &Job, slot: usize, exit: Option<i32>, opts: &RunOptions) {
+    let mut cmd = shell_command(
+        &template::fill_in_job(hook, job.seq, slot, job.attempt),
+        &opts.shell,
+        &opts.shell_args,
+        opts.shell_no_profile,
+    );
+    cmd.envs(opts.env.iter().map(|(k, v)| (k, v)))
+        .env("PARALLEL_JOB", &job.cmd)
+        .env("PARALLEL_SEQ", job.seq.to_string())
+        .env("PARALLEL_SLOT", slot.to_string())
+        .env("PARALLEL_SH_ATTEMPT", job.attempt.to_string())
+        .stdin(Stdio::null());
+    if let Some(exit) = exit {
+        cmd.env("PARALLEL_EXIT", exit.to_string());
+    }
+    match cmd.output() {
+        Ok(output) if !output.status.success() => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let stderr = stderr.trim_end();
+            let separator = if stderr.is_empty() { "" } else { ": " };
+            warn!(
+                "{} hook of '{}' {}{}{}",
+                name, job.cmd, output.status, separator, stderr
+            );
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Could not run {} hook '{}': {}", name, hook, e),
+    }
+}
+
+// Hand a JSON description of a finished job to a user supplied command
+fn on_result(callback: &str, opts: &RunOptions, result: &JobResult) {
+    let document = json::Object::new()
+        .field("command", &result.job)
+        .field("label", &result.label)
+        .field("host", &result.host)
+        .field("seq", result.seq)
+        .field("duration", result.duration.as_secs_f64())
+        .field("exit", result.output.status.code())
+        .field("signal", signal(result.output.status))
+        .field(
+            "spawn_error",
+            result.spawn_error.as_ref().map(spawn_error_kind),
+        )
+        .field("timed_out", result.timed_out.is_some())
+        .field("attempts", result.attempts)
+        .field(
+            "stdout",
+            String::from_utf8_lossy(&result.output.stdout).as_ref(),
+        )
+        .field(
+            "stderr",
+            String::from_utf8_lossy(&result.output.stderr).as_ref(),
+        )
+        .build();
+
+    let mut cmd = shell_command(
+        callback,
+        &opts.shell,
+        &opts.shell_args,
+        opts.shell_no_profile,
+    );
+    match cmd.stdin(Stdio::piped()).spawn() {
+        Ok(mut child) => {
+            if let Some(mut stdin) = child.stdin.take() {
+                let _ = writeln!(stdin, "{document}");
+            }
+            match child.wait() {
+                Ok(status) if !status.success() => {
+                    warn!("Result callback for {} {}", result.name(), status);
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Result callback for {} failed: {}", result.name(), e),
+            }
+        }
+        Err(e) => warn!("Could not run result callback '{}': {}", callback, e),
+    }
+}
+
+// Results held back until those of all earlier jobs are out (--keep-order)
+#[derive(Debug)]
+struct Reorder {
+    next: usize,
+    pending: BTreeMap<usize, JobResult>,
+}
+
+impl Reorder {
+    fn new() -> Self {
+        Reorder {
+            next: 1,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    fn pop(&mut self) -> Option<JobResult> {
+        let entry = self.pending.first_entry()?;
+        if *entry.key() != self.next {
+            return None;
+        }
+        self.next += 1;
+        Some(entry.remove())
+    }
+}
+
+// Wait for the next finished job, writing buffered output once it is due
+fn next_result(
+    results: &Receiver<JobResult>,
+    printer: &mut Printer,
+    mut reorder: Option<&mut Reorder>,
+) -> io::Result<Option<JobResult>> {
+    loop {
+        if let Some(result) = reorder.as_mut().and_then(|reorder| reorder.pop()) {
+            return Ok(Some(result));
+        }
+        let result = match printer.due() {
+            None => results.recv().ok(),
+            Some(due) => match results.recv_timeout(due) {
+                Ok(result) => Some(result),
+                Err(RecvTimeoutError::Timeout) => {
+                    printer.flush()?;
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => None,
+            },
+        };
+        match (result, reorder.as_mut()) {
+            (Some(result), Some(reorder)) => {
+                reorder.pending.insert(result.seq, result);
+            }
+            // Jobs which never ran (e.g. after a signal) leave gaps
+            (None, Some(reorder)) => {
+                return Ok(reorder.pending.pop_first().map(|(_, result)| result))
+            }
+            (result, None) => return Ok(result),
+        }
+    }
+}
+
+fn main() {
+    if let Some(exit) = env::subcommand()
+        .or_else(doctor::subcommand)
+        .or_else(report::subcommand)
+    {
+        process::exit(exit);
+    }
+
+    // -j is capped by the open file limit below, or the size of the
+    // semaphore with --sem
+    let mut args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("ERROR: {e}");
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = create_logger(&args) {
+        error!("Could create logger: {}", e);
+        process::exit(1);
+    }
+    if !args.watch.is_empty() && !watch::is_round() {
+        let stdin = if args.files.is_empty() {
+            args.clijobs.is_empty() && args.jobs_from.is_none() && args.listen.is_none()
+        } else {
+            args.files.iter().any(|file| file == "-")
+        };
+        if stdin {
+            error!(
+                "--watch needs the jobs on the command line or in a file, stdin is read only once"
+            );
+            process::exit(1);
+        }
+        watch::run(&args.watch, args.watch_changed);
+    }
+    let tui = tui_enabled(&args);
+    if args.tui && !args.dryrun && !tui {
+        warn!("stdout or stderr is not a terminal, no dashboard is shown");
+    }
+
+    jobctl::install_handlers();
+
+    // Held until parallel-sh exits, -j 0 means there is no limit
+    let _sem = match args.sem.as_deref().filter(|_| !args.dryrun) {
+        Some(name) if args.threads < usize::MAX => match sem::acquire(name, args.threads) {
+            Ok(slot) => Some(slot),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => jobctl::exit(INTERRUPTED),
+            Err(e) => {
+                error!("Could not acquire semaphore '{}': {}", name, e);
+                jobctl::exit(1);
+            }
+        },
+        _ => None,
+    };
+    if args.sem.is_some() {
+        args.threads = 1;
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    if let Some(max) = max_parallel_jobs().filter(|&max| args.threads > max) {
+        // Expected with -j 0
+        if args.threads < usize::MAX {
+            warn!(
+                "Only enough open files for {} jobs at a time (see ulimit -n), the others are queued",
+                max
+            );
+        }
+        args.threads = max;
+    }
+
+    // Jobs are read only a little ahead of the workers, so huge job files
+    // do not end up in memory
+    let (tx, rx) = shared_channel(
+        args.threads
+            .saturating_mul(QUEUED_JOBS_PER_SLOT)
+            .min(MAX_QUEUED_JOBS),
+    );
+
+    // return channel
+    let (rtx, rrx) = channel();
+
+    let shell = if let Some(s) = args.shell {
+        debug!("Using shell: '{}'", s.to_string_lossy());
+        Some(s)
+    } else {
+        debug!("Running command without shell");
+        None
+    };
+
+    // Only the variables passed on with --env, if any
+    let clean_env = args.clean_env || !args.env_vars.is_empty();
+    let mut env: Vec<(String, String)> = args
+        .env_vars
+        .iter()
+        .filter_map(|var| Some((var.clone(), std::env::var(var).ok()?)))
+        .collect();
+    // The same for every job, so they are read once
+    let (env_files, fixed_env_files): (Vec<_>, Vec<_>) = args
+        .env_files
+        .iter()
+        .map(|file| file.to_string_lossy().into_owned())
+        .partition(|file| Template::is_template(file));
+    let env_files: Vec<Template> = env_files.into_iter().map(Template::raw).collect();
+    for envfile in fixed_env_files.iter().map(PathBuf::from) {
+        match env::read_file(&envfile) {
+            Ok(vars) => env.extend(vars),
+            Err(e) => {
+                error!("Could not read env file '{}': {}", envfile.display(), e);
+                jobctl::exit(1);
+            }
+        }
+    }
+    if let Some(locale) = args.locale {
+        env.push((String::from("LANG"), locale.clone()));
+        env.push((String::from("LC_ALL"), locale));
+    }
+    if let Some(tz) = args.tz {
+        env.push((String::from("TZ"), tz));
+    }
+
+    let container = match args.container.filter(|_| !args.dryrun) {
+        Some(image) => {
+            match container::Container::new(image, args.container_engine.as_deref(), args.volumes) {
+                Ok(container) => Some(container),
+                Err(e) => {
+                    error!("Could not run jobs in a container: {}", e);
+                    jobctl::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let prologue = match args.prologue.as_ref().map(std::fs::read_to_string) {
+        Some(Ok(_)) if shell.is_none() => {
+            warn!("--prologue requires a shell and will be ignored");
+            None
+        }
+        Some(Ok(prologue)) => Some(prologue),
+        Some(Err(e)) => {
+            error!("Could not read prologue: {}", e);
+            jobctl::exit(1);
+        }
+        None => None,
+    };
+
+    // Read before the job log and the results database are written again
+    let schedule = (args.schedule != history::Order::Fifo).then(|| {
+        history::Schedule::load(
+            args.schedule,
+            args.joblog.as_deref().map(Path::new),
+            args.results_db.as_deref().map(Path::new),
+        )
+    });
+
+    if args.resume && args.joblog.is_none() {
+        error!("--resume and --retry-failed need the --joblog of the earlier run");
+        jobctl::exit(1);
+    }
+    let finished = match args
+        .joblog
+        .as_ref()
+        .filter(|path| args.resume && Path::new(path).exists())
+    {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(log) => joblog::succeeded(&log).collect(),
+            Err(e) => {
+                error!("Could not read job log: {}", e);
+                jobctl::exit(1);
+            }
+        },
+        None => HashSet::new(),
+    };
+
+    let manifest = match args.manifest.as_ref().map(File::create) {
+        Some(Ok(file)) => Some(Mutex::new(file)),
+        Some(Err(e)) => {
+            error!("Could not create manifest: {}", e);
+            jobctl::exit(1);
+        }
+        None => None,
+    };
+
+    let open_joblog = if args.resume {
+        JobLog::append
+    } else {
+        JobLog::create
+    };
+    let mut joblog = match args
+        .joblog
+        .as_ref()
+        .map(|path| open_joblog(Path::new(path)))
+    {
+        Some(Ok(joblog)) => Some(joblog),
+        Some(Err(e)) => {
+            error!("Could not create job log: {}", e);
+            jobctl::exit(1);
+        }
+        None => None,
+    };
+
+    let cache_key = args.cache_key.clone().map(Template::raw);
+    let cache = match args
+        .cache
+        .as_ref()
+        .map(|dir| cache::Cache::create(Path::new(dir), args.cache_inputs, cache_key.clone()))
+    {
+        Some(Ok(cache)) => Some(cache),
+        Some(Err(e)) => {
+            error!("Could not create cache directory: {}", e);
+            jobctl::exit(1);
+        }
+        None => None,
+    };
+
+    let results = match args.results.as_ref().map(|dir| {
+        let by_host = !args.sshlogins.is_empty() || args.sshloginfile.is_some();
+        Results::create(Path::new(dir), by_host)
+    }) {
+        Some(Ok(results)) => Some(results),
+        Some(Err(e)) => {
+            error!("Could not create results directory: {}", e);
+            jobctl::exit(1);
+        }
+        None => None,
+    };
+
+    let mut results_db = match args
+        .results_db
+        .as_ref()
+        .map(|path| ResultsDb::open(Path::new(path)))
+    {
+        Some(Ok(db)) => Some(db),
+        Some(Err(e)) => {
+            error!("Could not open results database: {}", e);
+            jobctl::exit(1);
+        }
+        None => None,
+    };
+
+    let tracer = match args.otel_endpoint.as_deref().map(Tracer::new) {
+        Some(Ok(tracer)) => {
+            env.push((String::from("TRACEPARENT"), tracer.traceparent()));
+            Some(tracer)
+        }
+        Some(Err(e)) => {
+            error!("Could not set up trace export: {}", e);
+            jobctl::exit(1);
+        }
+        None => None,
+    };
+
+    #[cfg(target_os = "windows")]
+    if args.umask.is_some() {
+        warn!("--umask is not supported on this platform and will be ignored");
+    }
+    #[cfg(target_os = "windows")]
+    if args.max_fds.is_some() || args.max_procs.is_some() {
+        warn!("--max-fds and --max-procs-per-job are not supported on this platform and will be ignored");
+    }
+    #[cfg(target_os = "windows")]
+    if args.load.is_some() {
+        warn!("--load is not supported on this platform and will be ignored");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.memfree.is_some() {
+        warn!("--memfree is not supported on this platform and will be ignored");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.memsuspend.is_some() {
+        warn!("--memsuspend is not supported on this platform and will be ignored");
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    if !args.cpus.is_empty() {
+        warn!("--pin and --cpuset are not supported on this platform and will be ignored");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.io_class.is_some() {
+        warn!("--io-class is not supported on this platform and will be ignored");
+    }
+    #[cfg(target_os = "windows")]
+    if args.stdin == StdinPolicy::Closed {
+        warn!("--close-stdin is not supported on this platform, using a null device instead");
+    }
+    if let StdinPolicy::File(path) = &args.stdin {
+        if let Err(e) = File::open(path) {
+            error!(
+                "Could not open '{}' for the jobs' stdin: {}",
+                path.display(),
+                e
+            );
+            jobctl::exit(1);
+        }
+    }
+    if args.binary {
+        let altering = [
+            (args.tag.is_some(), "--tag"),
+            (args.newline.is_some(), "--newline"),
+            (args.squash_repeats, "--squash-repeats"),
+            (args.max_output.is_some(), "--max-output"),
+            (args.format != Format::Text, "--format"),
+        ];
+        for (_, option) in altering.iter().filter(|(given, _)| *given) {
+            warn!(
+                "{} changes the output, it is not passed on byte for byte with --binary",
+                option
+            );
+        }
+    }
+    if args.grouping != Grouping::Group {
+        let captured = [
+            (args.keep_order, "--keep-order"),
+            (args.tag.is_some(), "--tag"),
+            (args.newline.is_some(), "--newline"),
+            (args.squash_repeats, "--squash-repeats"),
+            (args.max_output.is_some(), "--max-output"),
+            (
+                !matches!(args.encoding, OutputEncoding::Utf8 | OutputEncoding::Bytes),
+                "--output-encoding",
+            ),
+            (args.format != Format::Text, "--format"),
+            (args.results.is_some(), "--results"),
+            (args.tee_output, "--tee-output"),
+            (args.results_db.is_some(), "--results-db"),
+            (args.allow_enqueue, "--allow-enqueue"),
+        ];
+        for (_, option) in captured.iter().filter(|(given, _)| *given) {
+            warn!(
+                "{} needs the output of the jobs, which is passed on as it is written with --ungroup and --line-buffer",
+                option
+            );
+        }
+    }
+    if args.block_auto && !args.pipe && args.pipepart.is_none() {
+        warn!("--block auto only applies to --pipe and --pipepart and will be ignored");
+    }
+    if args.tee_output && args.output.is_none() && args.results.is_none() {
+        warn!("--tee-output only applies to --output and --results and will be ignored");
+    }
+    if args.cache_key.is_some() && args.cache.is_none() {
+        warn!("--cache-key only applies to --cache and will be ignored");
+    }
+    if args.idle_exit.is_some() && !args.follow && args.listen.is_none() {
+        warn!("--idle-exit only applies to --listen and --follow and will be ignored");
+    }
+    if args.follow && args.files.is_empty() {
+        warn!("--follow only applies to --file and will be ignored");
+    }
+    if args.follow && args.files.len() > 1 {
+        error!("--follow needs a single --file");
+        jobctl::exit(1);
+    }
+    if (args.stdin == StdinPolicy::Inherit || args.foreground.is_some())
+        && ((args.clijobs.is_empty() && args.files.is_empty() && args.listen.is_none())
+            || args.files.iter().any(|file| file == "-"))
+    {
+        warn!("Jobs are read from stdin, so there is nothing left on stdin for the jobs to read");
+    }
 
-    if let Some(file) = opts.logfile.clone().map(PathBuf::from) {
-        loggers.push(WriteLogger::new(
-            level,
-            logconfig,
-            OpenOptions::new().append(true).create(true).open(file)?,
-        ));
+    // Also used for the cpu and memory limits of --source-cmd jobs
+    #[cfg(target_os = "linux")]
+    let cgroups = if args.dryrun {
+        None
+    } else {
+        cgroup::Root::detect()
+    };
+    #[cfg(target_os = "linux")]
+    let limited =
+        args.cpu_quota.is_some() && cgroups.as_ref().is_some_and(|root| root.enable("cpu"));
+    #[cfg(not(target_os = "linux"))]
+    let limited = false;
+    let mut cgroup_settings = vec![];
+    if let Some(quota) = args.cpu_quota.filter(|_| limited) {
+        cgroup_settings.push(("cpu.max", cpu_max(quota)));
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(memory) = args.cgroup_memory.filter(|_| !args.dryrun) {
+        if cgroups.as_ref().is_some_and(|root| root.enable("memory")) {
+            cgroup_settings.push(("memory.max", memory.to_string()));
+        } else {
+            warn!("cgroups with the memory controller are not available, ignoring --cgroup-mem");
+        }
     }
+    #[cfg(not(target_os = "linux"))]
+    if args.unshare.is_some() || args.private_tmp {
+        warn!("--unshare and --private-tmp are not supported on this platform and will be ignored");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.cgroup_memory.is_some() {
+        warn!("--cgroup-mem is not supported on this platform and will be ignored");
+    }
+    #[cfg(target_os = "linux")]
+    let freeze_cgroups = args.freeze && cgroups.is_some();
+    #[cfg(not(target_os = "linux"))]
+    let freeze_cgroups = false;
+    if args.freeze && !args.dryrun {
+        #[cfg(target_os = "linux")]
+        if freeze_cgroups {
+            jobctl::on_stop(cgroup::freeze_all);
+        }
+        #[cfg(not(target_os = "windows"))]
+        if !freeze_cgroups {
+            info!("cgroups are not available, stopping jobs with SIGSTOP/SIGCONT instead");
+            jobctl::on_stop(jobctl::pause_all);
+        }
+        #[cfg(target_os = "windows")]
+        warn!("--freeze is not supported on this platform and will be ignored");
+    }
+    if let Some(quota) = args.cpu_quota.filter(|_| !limited && !args.dryrun) {
+        #[cfg(not(target_os = "windows"))]
+        {
+            info!("cgroups are not available, throttling jobs with SIGSTOP/SIGCONT instead");
+            jobctl::throttle(quota);
+        }
+        #[cfg(target_os = "windows")]
+        {
+            let _ = quota;
+            warn!("--cpu-quota is not supported on this platform and will be ignored");
+        }
+    }
+    if let Some(memfree) = args.memfree.filter(|_| !args.dryrun) {
+        load::watch_memory(memfree);
+    }
+    #[cfg(not(target_os = "windows"))]
+    if let Some(memsuspend) = args.memsuspend.filter(|_| !args.dryrun) {
+        load::suspend_on_low_memory(memsuspend);
+    }
+    let confirm = match args.interactive && !args.dryrun {
+        true => match confirm::Confirm::open() {
+            Ok(confirm) => Some(confirm),
+            Err(e) => {
+                error!("--interactive needs a terminal: {}", e);
+                jobctl::exit(1);
+            }
+        },
+        false => None,
+    };
 
-    if CombinedLogger::init(loggers).is_err() {
-        error!("Could not initialize logger.");
+    let mut logins = args.sshlogins;
+    if let Some(file) = &args.sshloginfile {
+        match ssh::read_logins(Path::new(file)) {
+            Ok(file_logins) => logins.extend(file_logins),
+            Err(e) => {
+                error!("{}", e);
+                jobctl::exit(1);
+            }
+        }
+    }
+    if args.host_check.is_some() && logins.is_empty() {
+        warn!("--host-check only applies to --sshlogin and will be ignored");
+    }
+    let hosts = if logins.is_empty() {
+        None
+    } else {
+        Some(Arc::new(ssh::Hosts::new(logins, args.threads)))
     };
+    if let (Some(hosts), Some(command)) = (&hosts, args.host_check.filter(|_| !args.dryrun)) {
+        let check = ssh::HostCheck {
+            command,
+            timeout: args.host_check_timeout,
+            interval: args.host_check_interval,
+        };
+        if hosts.check(&check) == 0 {
+            error!("No host passed the host check");
+            jobctl::exit(1);
+        }
+        hosts.watch(check);
+    }
 
-    Ok(())
-}
+    let progress = if tui {
+        Some(Progress::start(args.bar, true))
+    } else if !(args.bar || args.eta) || args.dryrun {
+        None
+    } else if io::stderr().is_terminal() {
+        Some(Progress::start(args.bar, false))
+    } else {
+        warn!("stderr is not a terminal, no progress is shown");
+        None
+    };
 
-#[allow(clippy::needless_pass_by_value)]
-fn add_jobs(
-    clijobs: Vec<String>,
-    jobsfile: Option<PathBuf>,
-    tx: Sender<String>,
-) -> Result<(), std::io::Error> {
-    let start_job = |job| {
-        debug!("Starting job '{}'", &job);
-        tx.send(job)
-            .unwrap_or_else(|e| error!("Could not add job: {}", e));
-    };
-    if clijobs.is_empty() {
-        if let Some(jobsfile) = jobsfile {
-            let file = File::open(jobsfile)?;
-            BufReader::new(file)
-                .lines()
-                .map_while(Result::ok)
-                .for_each(start_job);
-        } else {
-            let stdin = io::stdin();
-            let handle = stdin.lock();
-            BufReader::new(handle)
-                .lines()
-                .map_while(Result::ok)
-                .for_each(start_job);
+    let status = Arc::new(status::Status::new());
+    if let Some(address) = args.status_port {
+        if let Err(e) = status::serve(address, Arc::clone(&status)) {
+            error!("Could not serve status on {}: {}", address, e);
+            jobctl::exit(1);
         }
-    } else {
-        // preferred
-        clijobs.into_iter().for_each(start_job);
     }
+    let report = Arc::clone(&status);
+    jobctl::on_report(move || output::print_stderr(&report.report()));
 
-    Ok(())
-}
+    let auto_block = (args.block_auto && (args.pipe || args.pipepart.is_some()))
+        .then(|| Arc::new(pipe::AutoBlock::default()));
+    let opts = RunOptions {
+        dry_run: args.dryrun,
+        shell,
+        shell_args: args.shell_args,
+        shell_no_profile: args.shell_no_profile,
+        prologue,
+        wd: args.wd.map(Template::raw),
+        output: args.output.map(Template::raw),
+        output_err: args.output_err.map(Template::raw),
+        tee_output: args.tee_output,
+        clean_env,
+        env,
+        env_files,
+        stdin: args.stdin,
+        foreground: args.foreground,
+        foreground_taken: AtomicBool::new(false),
+        window: args.window,
+        progress: progress.clone(),
+        status: Arc::clone(&status),
+        confirm,
+        delay: args.delay.filter(|delay| !delay.is_zero()).map(Delay::new),
+        rate: args.rate.map(|(jobs, period)| Rate::new(jobs, period)),
+        load: args.load,
+        memfree: args.memfree,
+        memsuspend: args.memsuspend,
+        allow_enqueue: args.allow_enqueue,
+        echo: args.echo.then_some(
+            if args.format != Format::Text && args.output_file.is_none() {
+                Echo::Log
+            } else {
+                Echo::Stdout
+            },
+        ),
+        manifest,
+        auto_block: auto_block.clone(),
+        cache,
+        pre: args.pre,
+        post: args.post,
+        after_tag: args.after_tag,
+        keep_order: args.keep_order || args.dryrun,
+        max_output: args.max_output,
+        grouping: args.grouping,
+        timeout: args.timeout,
+        retries: args.retries,
+        bench: args.bench.map(|runs| bench::Bench {
+            runs,
+            warmup: args.warmup,
+        }),
+        retry_delay: args.retry_delay,
+        retry_budget: args.max_total_retries.map(AtomicU64::new),
+        retries_denied: AtomicUsize::new(0),
+        failed_labels: Mutex::new(HashSet::new()),
+        dag: Arc::default(),
+        umask: args.umask,
+        max_fds: args.max_fds,
+        max_procs: args.max_procs,
+        nice: args.nice,
+        io_class: args.io_class,
+        cpus: args.cpus,
+        container,
+        sandbox: match (args.unshare, args.private_tmp) {
+            (None, false) => None,
+            (unshare, false) => unshare,
+            (unshare, true) => Some(unshare.unwrap_or_default().with_private_tmp()),
+        },
+        hosts,
+        transfer: args.transfer,
+        returns: args.returns.into_iter().map(Template::raw).collect(),
+        cleanup: args.cleanup,
+        #[cfg(target_os = "linux")]
+        cgroups,
+        cgroup_settings,
+        freeze_cgroups,
+    };
 
-fn run(dry_run: bool, command: &str, shell: &Option<OsString>) -> Output {
-    if dry_run {
-        return Output {
-            status: ExitStatus::default(),
-            stdout: Vec::new(),
-            stderr: Vec::new(),
-        };
+    let opts = Arc::new(opts);
+    let threads = opts
+        .hosts
+        .as_deref()
+        .map_or(args.threads, ssh::Hosts::slots);
+    start_workers(threads, &rx, rtx, &opts, args.jobs_file.map(PathBuf::from));
+
+    let delimiter = if args.null { b'\0' } else { b'\n' };
+    let lines = match (args.files.as_slice(), &args.listen) {
+        ([], None) if args.jobs_from.is_some() => {
+            let mut cmd = shell_command(
+                args.jobs_from.as_deref().unwrap_or_default(),
+                &opts.shell,
+                &opts.shell_args,
+                opts.shell_no_profile,
+            );
+            match cmd.stdin(Stdio::null()).stdout(Stdio::piped()).spawn() {
+                Ok(child) => Lines::Command(child),
+                Err(e) => {
+                    error!("Could not start --jobs-from command: {}", e);
+                    jobctl::exit(1);
+                }
+            }
+        }
+        (_, _) if args.jobs_from.is_some() => {
+            error!("--jobs-from cannot be combined with --file or --listen");
+            jobctl::exit(1);
+        }
+        ([file], _) if args.follow => {
+            match listen::Follow::open(Path::new(file), delimiter, args.idle_exit) {
+                Ok(follow) => Lines::Follow(follow),
+                Err(e) => {
+                    error!("Could not open '{}': {}", file.to_string_lossy(), e);
+                    jobctl::exit(1);
+                }
+            }
+        }
+        ([], Some(path)) => {
+            match listen::Listener::bind(Path::new(path), delimiter, args.idle_exit) {
+                Ok(listener) => Lines::Listen(listener),
+                Err(e) => {
+                    error!("Could not listen on '{}': {}", path.to_string_lossy(), e);
+                    jobctl::exit(1);
+                }
+            }
+        }
+        ([], None) => Lines::Stdin,
+        (files, _) => Lines::Files(files.iter().map(PathBuf::from).collect()),
     };
 
-    if let Some(s) = shell {
-        let mut shell = std::process::Command::new(s);
+    let selection = Selection {
+        dedup: args.dedup,
+        filters: args.filters,
+        filters_not: args.filters_not,
+        shard: args.shard,
+        skip: args.skip,
+        head: args.head,
+        sample: args.sample,
+        shuffle: args.shuffle,
+        seed: args.seed.unwrap_or_else(default_seed),
+        changed: watch::changed(),
+        finished,
+        schedule,
+    };
 
-        match shell.arg("-c").arg(command).output() {
-            Ok(s) => s,
-            Err(_) => Output {
-                status: ExitStatus::from_raw(1),
-                stdout: Vec::new(),
-                stderr: Vec::new(),
-            },
+    let script = match args
+        .script
+        .as_ref()
+        .map(|path| Script::load(Path::new(path)))
+    {
+        Some(Ok(script)) => Some(Arc::new(script)),
+        Some(Err(e)) => {
+            error!("Could not load script: {}", e);
+            jobctl::exit(1);
         }
-    } else {
-        let cmd: Vec<_> = command.split(' ').collect();
-        let mut command = std::process::Command::new(cmd[0]);
+        None => None,
+    };
 
-        match command.args(&cmd[1..]).output() {
-            Ok(c) => c,
-            Err(_) => Output {
-                status: ExitStatus::from_raw(1),
-                stdout: Vec::new(),
-                stderr: Vec::new(),
-            },
+    let source = match args.source_cmd.as_ref().map(|cmd| {
+        Source::spawn(shell_command(
+            cmd,
+            &opts.shell,
+            &opts.shell_args,
+            opts.shell_no_profile,
+        ))
+    }) {
+        Some(Ok(source)) => Some(Arc::new(source)),
+        Some(Err(e)) => {
+            error!("Could not start source command: {}", e);
+            jobctl::exit(1);
         }
-    }
-}
+        None => None,
+    };
 
-#[allow(clippy::needless_pass_by_value)]
-fn start_workers(
-    threads: usize,
-    dry_run: bool,
-    jobs: &SharedReceiver<String>,
-    results: Sender<JobResult>,
-    shell: &Option<OsString>,
-) {
-    if dry_run {
-        debug!("Perform a trial run with no changes made");
-    }
-    debug!("Starting {} worker threads", threads);
-    for _seq in 0..threads {
-        let jobs = jobs.clone();
-        let results = results.clone();
-        let shell = shell.clone();
+    let backlog = (args.pipe || args.pipepart.is_some())
+        .then(|| Arc::new(pipe::Backlog::new(threads.saturating_mul(2))));
+    if let Some(backlog) = backlog.clone() {
+        if args.clijobs.is_empty() || !matches!(lines, Lines::Stdin) || source.is_some() {
+            error!("--pipe needs a command, and the jobs cannot be read from elsewhere");
+            jobctl::exit(1);
+        }
+        let command = args.clijobs.join(" ");
+        let block_size = match auto_block {
+            Some(auto_block) => pipe::BlockSize::Auto(auto_block),
+            None => pipe::BlockSize::Fixed(args.block_size),
+        };
+        let pipepart = args.pipepart.map(PathBuf::from);
+        let progress = progress.clone();
+        let status = Arc::clone(&status);
         thread::spawn(move || {
-            for job in jobs {
-                let starttime = Instant::now();
-                let output = run(dry_run, &job, &shell);
-                let duration = starttime.elapsed();
-                results
-                    .send(JobResult {
-                        duration,
-                        job,
-                        output,
-                    })
-                    .unwrap_or_else(|e| error!("Could not send job: {}", e));
+            let added = match &pipepart {
+                Some(file) => add_parts(&command, file, &block_size, &backlog, &tx),
+                None => add_blocks(&command, &block_size, &backlog, &tx),
+            };
+            match added {
+                Ok(total) => {
+                    if let Some(progress) = &progress {
+                        progress.set_total(total);
+                    }
+                    status.set_total(total);
+                }
+                Err(e) => {
+                    error!("Could not read job input: {}", e);
+                    jobctl::exit(1);
+                }
             }
         });
-    }
-}
-
-fn main() {
-    let args = match parse_args() {
-        Ok(args) => args,
-        Err(e) => {
-            eprintln!("ERROR: {e}");
-            process::exit(1);
+    } else {
+        let chunking = (args.max_args.is_some() || args.max_chars.is_some()).then(|| Chunking {
+            max_args: args.max_args.unwrap_or(usize::MAX),
+            max_chars: args.max_chars.unwrap_or(usize::MAX),
+        });
+        let (clijobs, template) = expand_inputs(
+            args.clijobs,
+            &args.arg_files,
+            opts.shell.as_deref(),
+            chunking.is_some(),
+            args.link,
+            args.null,
+            args.quote,
+            args.header,
+        );
+        if chunking.is_some() && template.is_none() {
+            error!("-N/--max-args and --max-chars need a command to pass the arguments to");
+            jobctl::exit(1);
         }
-    };
-
-    if let Err(e) = create_logger(&args) {
-        error!("Could create logger: {}", e);
-        process::exit(1);
+        if template.is_some() && clijobs.is_empty() && source.is_some() {
+            warn!("Jobs from --source-cmd are complete commands, the template will be ignored");
+        }
+        let source = source.clone();
+        let script = script.clone();
+        let label_sep = args.label_sep;
+        let null = args.null;
+        let strict_lines = args.strict_lines;
+        let colsep = args.colsep;
+        if colsep.is_some() && template.is_none() {
+            warn!("--colsep needs a command template, input lines are commands of their own");
+        }
+        let header = args.header;
+        if header && template.is_none() {
+            warn!("--header needs a command template and will be ignored");
+        }
+        let progress = progress.clone();
+        let status = Arc::clone(&status);
+        let extract_lock = args.extract_lock;
+        let dag = Arc::clone(&opts.dag);
+        if args.allow_enqueue {
+            dag.allow_follow_ups();
+        }
+        dag.dispatch(tx.clone());
+        thread::spawn(move || {
+            match add_jobs(
+                clijobs,
+                lines,
+                source.as_deref(),
+                &selection,
+                script.as_deref(),
+                label_sep.as_deref(),
+                null,
+                strict_lines,
+                colsep.as_ref(),
+                header,
+                chunking,
+                template.as_ref(),
+                extract_lock.as_ref(),
+                &dag,
+                tx,
+            ) {
+                Ok(total) => {
+                    if let Some(progress) = &progress {
+                        progress.set_total(total);
+                    }
+                    status.set_total(total);
+                }
+                Err(e) => {
+                    error!("Could not start jobs: {}", e);
+                    jobctl::exit(1);
+                }
+            }
+        });
     }
 
-    let (tx, rx) = shared_channel();
-
-    // return channel
-    let (rtx, rrx) = channel();
+    let mut printer = Printer::new(
+        args.encoding,
+        args.newline,
+        args.squash_repeats,
+        args.flush_interval,
+    );
 
-    let shell = if let Some(s) = args.shell {
-        debug!("Using shell: '{}'", s.to_string_lossy());
-        Some(s)
-    } else {
-        debug!("Running command without shell");
-        None
+    if args.output_file.is_some() && args.format == Format::Text {
+        warn!("--output-file only applies to --format json, csv and tsv and will be ignored");
+    }
+    if let Some(path) = args
+        .output_file
+        .as_ref()
+        .filter(|_| args.format != Format::Text)
+    {
+        match File::create(path) {
+            Ok(file) => printer.set_output_file(file),
+            Err(e) => {
+                error!(
+                    "Could not create output file '{}': {}",
+                    path.to_string_lossy(),
+                    e
+                );
+                jobctl::exit(1);
+            }
+        }
+    }
+    let separator = match args.format {
+        Format::Csv => Some(csv::Separator::Comma),
+        Format::Tsv => Some(csv::Separator::Tab),
+        Format::Text | Format::Json => None,
     };
-
-    start_workers(args.threads, args.dryrun, &rx, rtx, &shell);
-
-    let jobsfile = args.file.map(PathBuf::from);
-
-    if let Err(e) = add_jobs(args.clijobs, jobsfile, tx) {
-        error!("Could not start jobs: {}", e);
-        std::process::exit(1);
+    if let Some(separator) = separator.filter(|_| results.is_none() && !args.dryrun) {
+        let _ = printer.print_result(&csv::record(&csv::HEADER, separator));
     }
 
     let mut exit = 0;
-    for result in rrx {
+    let mut first_exit = None;
+    let mut output_closed = false;
+    let mut halted = false;
+    // Exit code once a --halt threshold was reached
+    let mut halt_exit = None;
+    let mut failures = 0;
+    let mut suppressed = FailureSummary::default();
+    let mut reorder = (args.keep_order || args.dryrun).then(Reorder::new);
+    let mut jobs = 0;
+    let mut exit_codes = ExitCodes::default();
+    let mut timings = vec![];
+    let deadline_reached = Arc::new(AtomicBool::new(false));
+    if let Some(total) = args.timeout_total.filter(|_| !args.dryrun) {
+        let reached = Arc::clone(&deadline_reached);
+        thread::spawn(move || {
+            thread::sleep(total);
+            reached.store(true, Ordering::SeqCst);
+            warn!(
+                "The run took {:.1?} (--timeout-total), stopping all jobs",
+                total
+            );
+            jobctl::terminate_all();
+        });
+    }
+    loop {
+        let result = match next_result(&rrx, &mut printer, reorder.as_mut()) {
+            Ok(Some(result)) if result.skipped => continue,
+            Ok(Some(result)) => result,
+            Ok(None) => break,
+            Err(_) => {
+                debug!("Output was closed, not starting any more jobs");
+                output_closed = true;
+                break;
+            }
+        };
+        if let Some(backlog) = &backlog {
+            backlog.pop();
+        }
+        jobs += 1;
         if !args.dryrun {
+            let attempts = if result.attempts > 1 {
+                format!(" ({} attempts)", result.attempts)
+            } else {
+                String::new()
+            };
             info!(
-                "'{}' took {}.{}s",
-                &result.job,
+                "{} took {}.{}s{}",
+                result.name(),
                 &result.duration.as_secs(),
-                &result.duration.subsec_nanos()
+                &result.duration.subsec_nanos(),
+                attempts
             );
-            if result.output.status.success() {
-                print!("{}", String::from_utf8_lossy(&result.output.stdout));
-                eprint!("{}", String::from_utf8_lossy(&result.output.stderr));
+            let success = result.spawn_error.is_none()
+                && script
+                    .as_ref()
+                    .and_then(|script| script.classify(&result.job, result.output.status.code()))
+                    .unwrap_or_else(|| result.output.status.success());
+            if !success {
+                failures += 1;
+            }
+            if let Some(progress) = &progress {
+                progress.job_finished(&result.job, result.duration, success);
+            }
+            opts.status.job_finished(success);
+            exit_codes.add(result.output.status.code(), signal(result.output.status));
+            if args.max_fail_output.is_some_and(|max| failures > max) && !success {
+                suppressed.add(&result.output);
             } else {
-                warn!("'{}' {}", &result.job, &result.output.status);
-                print!("{}", String::from_utf8_lossy(&result.output.stdout));
-                eprint!("{}", String::from_utf8_lossy(&result.output.stderr));
-
-                if args.halt {
-                    std::process::exit(1);
+                if let Some(e) = &result.spawn_error {
+                    warn!("{} could not be started: {}{}", result.name(), e, attempts);
+                } else if let Some(timeout) = result.timed_out {
+                    warn!(
+                        "{} timed out after {:.1?}{}",
+                        result.name(),
+                        timeout,
+                        attempts
+                    );
+                } else if !success {
+                    warn!("{} {}{}", result.name(), &result.output.status, attempts);
+                }
+                let tag = args.tag.as_ref().map(|tag| {
+                    tag.replace("{#}", &result.seq.to_string())
+                        .replace("{}", &result.job)
+                });
+                let printed = match args.format {
+                    _ if results.is_some() && !args.tee_output => Ok(()),
+                    Format::Text => printer.print(&result.output, tag.as_deref()),
+                    Format::Json => printer.print_result(&json_record(&result)),
+                    Format::Csv => {
+                        printer.print_result(&csv_record(&result, csv::Separator::Comma))
+                    }
+                    Format::Tsv => printer.print_result(&csv_record(&result, csv::Separator::Tab)),
+                };
+                if printed.is_err() {
+                    debug!("Output was closed, not starting any more jobs");
+                    output_closed = true;
+                }
+            }
+            if let Some(results) = &results {
+                if let Err(e) = results.write(&result) {
+                    warn!("Could not write results of {}: {}", result.name(), e);
+                }
+            }
+            if let Some(db) = &mut results_db {
+                if let Err(e) = db.insert(&result) {
+                    warn!(
+                        "Could not add {} to the results database: {}",
+                        result.name(),
+                        e
+                    );
+                }
+            }
+            if let Some(tracer) = &tracer {
+                tracer.job(&result, success);
+            }
+            // Failed jobs would not compare well
+            if args.bench.is_some() && success && !result.timings.is_empty() {
+                timings.push((result.job.clone(), result.timings.clone()));
+            }
+            if let Some(joblog) = joblog.as_mut() {
+                if let Err(e) = joblog.write(&result) {
+                    warn!("Could not write to job log: {}", e);
+                }
+            }
+            if let Some(callback) = &args.on_result {
+                on_result(callback, &opts, &result);
+            }
+            if let Some(source) = &source {
+                source.done(
+                    &result.job,
+                    result.seq,
+                    result.output.status.code(),
+                    signal(result.output.status),
+                    result.duration.as_secs_f64(),
+                );
+            }
+            if !success {
+                if args.halt_on_spawn_error && result.spawn_error.is_some() {
+                    exit = result.output.status.code().unwrap_or(1);
+                    first_exit.get_or_insert(exit);
+                    halted = true;
+                    break;
+                } else {
+                    exit = match result.output.status.code() {
+                        Some(0) => 1,
+                        code => code.unwrap_or(127),
+                    };
+                    first_exit.get_or_insert(exit);
+                }
+            }
+            if let Some(halt) = args.halt.filter(|_| halt_exit.is_none()) {
+                let (count, what) = if halt.on_success {
+                    (jobs - failures, "succeeded")
                 } else {
-                    exit = result.output.status.code().unwrap_or(127);
+                    (failures, "failed")
+                };
+                if halt.threshold.reached(count, jobs) {
+                    halt_exit = Some(i32::from(!halt.on_success));
+                    if halt.when == HaltWhen::Now {
+                        warn!("{} job(s) {}, stopping the running jobs", count, what);
+                        halted = true;
+                        break;
+                    }
+                    warn!("{} job(s) {}, not starting any more jobs", count, what);
+                    jobctl::stop_starting();
                 }
             }
+            if output_closed {
+                break;
+            }
+        } else if printer.print_record(&result.job).is_err() {
+            debug!("Output was closed, not starting any more jobs");
+            output_closed = true;
+            break;
+        }
+    }
+    if let Some(progress) = &progress {
+        progress.finish();
+    }
+    if !timings.is_empty() && !output_closed {
+        let _ = printer.print_record(bench::report(&timings).trim_end());
+    }
+    printer.finish();
+    if let Some(Err(e)) = results_db.map(ResultsDb::finish) {
+        warn!("Could not write results database: {}", e);
+    }
+    suppressed.log();
+    exit_codes.log();
+    let retries_denied = opts.retries_denied.load(Ordering::SeqCst);
+    if retries_denied > 0 {
+        warn!(
+            "All {} retries of --max-total-retries were used, {} failed job(s) were not retried",
+            args.max_total_retries.unwrap_or_default(),
+            retries_denied
+        );
+    }
+    if let Some(cache) = &opts.cache {
+        if cache.hits() > 0 {
+            warn!(
+                "Skipped {} job(s) that succeeded before (cached)",
+                cache.hits()
+            );
+        }
+    }
+    exit = match args.exit_status {
+        ExitPolicy::FirstFail => first_exit.unwrap_or(exit),
+        ExitPolicy::LastFail => exit,
+        #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+        ExitPolicy::Count => failures.min(MAX_FAILED_EXIT) as i32,
+        ExitPolicy::Any => i32::from(failures > 0),
+    };
+    if let Some(code) = halt_exit {
+        exit = code;
+    }
+    if jobctl::aborted() {
+        warn!(
+            "Aborted, {} job(s) finished ({} failed), no more were started",
+            jobs, failures
+        );
+        exit = jobctl::ABORTED;
+    } else if jobctl::interrupted() {
+        warn!(
+            "Interrupted, {} job(s) finished ({} failed), no more were started",
+            jobs, failures
+        );
+        exit = INTERRUPTED;
+    } else if deadline_reached.load(Ordering::SeqCst) {
+        let never_ran = rx.drain();
+        warn!(
+            "{} job(s) finished ({} failed), {} queued job(s) never ran{}",
+            jobs,
+            failures,
+            never_ran.len(),
+            if never_ran.is_empty() { "" } else { ":" }
+        );
+        for job in never_ran.iter().take(MAX_LISTED_JOBS) {
+            warn!("  {}", job.cmd);
+        }
+        if never_ran.len() > MAX_LISTED_JOBS {
+            warn!("  and {} more", never_ran.len() - MAX_LISTED_JOBS);
+        }
+        exit = TIMED_OUT;
+    }
+    if args.dryrun && !output_closed {
+        eprintln!(
+            "{} job(s) would be run, {} at a time",
+            jobs,
+            threads.min(jobs).max(1)
+        );
+    }
+    if output::dropped_log_records() > 0 {
+        warn!(
+            "{} log record(s) could not be written to the log file",
+            output::dropped_log_records()
+        );
+    }
+    // Nobody is reading the output of the remaining jobs anymore
+    if output_closed || halted {
+        jobctl::terminate_all();
+    } else if let Some(source) = &source {
+        source.finish(&exit_codes);
+    }
+    if let Some(tracer) = &tracer {
+        tracer.finish(exit);
+    }
+    jobctl::exit(exit);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    // Removed again when the test is done
+    struct TempFile(String);
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    impl std::ops::Deref for TempFile {
+        type Target = str;
+
+        fn deref(&self) -> &str {
+            &self.0
+        }
+    }
+
+    fn script(name: &str, text: &str) -> TempFile {
+        let path = std::env::temp_dir().join(format!("parallel-sh-{}-{name}", process::id()));
+        std::fs::write(&path, text).unwrap();
+        TempFile(path.to_string_lossy().into_owned())
+    }
+
+    fn job(cmd: &str, template: Option<Template>, args: &[&str]) -> Job {
+        Job {
+            seq: 3,
+            cmd: cmd.to_string(),
+            label: None,
+            after: None,
+            limits: Limits::default(),
+            args: args.iter().map(|arg| (*arg).to_string()).collect(),
+            input: None,
+            priority: 0,
+            deps: vec![],
+            lock: None,
+            template: template.map(Arc::new),
+            attempt: 1,
+        }
+    }
+
+    #[test]
+    fn job_numbers_only_in_templates() {
+        let mut plain = job(r#"bash -c "set -- x y; echo ${#}""#, None, &[]);
+        plain.fill_in(2);
+        assert_eq!(plain.cmd, r#"bash -c "set -- x y; echo ${#}""#);
+        let template = Template::new(String::from("echo {} {#} {%}"), Some("sh".as_ref()));
+        let mut filled = job("echo 'a{#}' {#} {%}", Some(template), &["a{#}"]);
+        filled.fill_in(2);
+        assert_eq!(filled.cmd, "echo 'a{#}' 3 2");
+    }
+
+    #[test]
+    fn shebang_file_option() {
+        let file = script("file", "#!/usr/bin/env parallel-sh\necho one\n");
+        let args = os_args(&["-f", &file]);
+        assert_eq!(shebang_args(args.clone()), args);
+        let args = os_args(&["-j2", "-f", &file]);
+        assert_eq!(shebang_args(args.clone()), args);
+    }
+
+    #[test]
+    fn shebang_script() {
+        let file = script(
+            "script",
+            "#!/usr/bin/parallel-sh -j8 --halt now,fail=1\necho one\n",
+        );
+        assert_eq!(
+            shebang_args(os_args(&["-j8 --halt now,fail=1", &file, "x"])),
+            os_args(&["-j8", "--halt", "now,fail=1", "--file", &file, "x"])
+        );
+        let file = script("plain", "#!/usr/bin/env parallel-sh\necho one\n");
+        assert_eq!(shebang_args(os_args(&[&file])), os_args(&["--file", &file]));
+    }
+
+    #[test]
+    fn halt_policies() {
+        assert!(parse_halt("never").unwrap().is_none());
+        let halt = parse_halt("now,fail=1").unwrap().unwrap();
+        assert_eq!(halt.when, HaltWhen::Now);
+        assert!(!halt.on_success);
+        assert!(matches!(halt.threshold, Threshold::Count(1)));
+        let halt = parse_halt("soon,success=20%").unwrap().unwrap();
+        assert_eq!(halt.when, HaltWhen::Soon);
+        assert!(halt.on_success);
+        assert!(matches!(halt.threshold, Threshold::Percent(p) if p == 20.0));
+        for invalid in [
+            "now",
+            "later,fail=1",
+            "now,fail=0",
+            "now,fail=101%",
+            "now,done=1",
+        ] {
+            assert!(parse_halt(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn jobs_relative_to_cpus() {
+        let cpus = num_cpus::get();
+        assert_eq!(parse_jobs("4"), Ok(4));
+        assert_eq!(parse_jobs("0"), Ok(usize::MAX));
+        assert_eq!(parse_jobs("+2"), Ok(cpus + 2));
+        assert_eq!(parse_jobs("-1"), Ok(cpus.saturating_sub(1).max(1)));
+        assert_eq!(parse_jobs(&format!("-{}", cpus + 5)), Ok(1));
+        assert_eq!(parse_jobs("200%"), Ok(cpus * 2));
+        assert_eq!(parse_jobs("1%"), Ok((cpus / 100).max(1)));
+        assert!(parse_jobs("many").is_err());
+        assert!(parse_jobs("+x").is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("10m"), Ok(10 << 20));
+        assert_eq!(parse_size("1GiB"), Ok(1 << 30));
+        assert_eq!(parse_size("2kb"), Ok(2 << 10));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("-1K").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert!(parse_duration("5d").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+
+    #[test]
+    fn rates() {
+        assert_eq!(parse_rate("10/1m"), Ok((10, Duration::from_secs(60))));
+        assert_eq!(parse_rate("5/s"), Ok((5, Duration::from_secs(1))));
+        assert_eq!(parse_rate("3/500ms"), Ok((3, Duration::from_millis(500))));
+        for invalid in ["10", "0/1s", "10/0s", "x/1s", "10/1d"] {
+            assert!(parse_rate(invalid).is_err(), "{invalid}");
         }
     }
-    std::process::exit(exit);
+
+    #[test]
+    fn shards() {
+        let shards: Vec<Shard> = ["1/3", "2/3", "3/3"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        for i in 0..100 {
+            let job = format!("echo {i}");
+            let owners = shards.iter().filter(|shard| shard.contains(&job)).count();
+            assert_eq!(owners, 1, "{job}");
+        }
+        // FNV-1a of "a" is 0xaf63dc4c8601ec8c, the same on every machine
+        let shard: Shard = format!("{}/7", 0xaf63_dc4c_8601_ec8c_u64 % 7 + 1)
+            .parse()
+            .unwrap();
+        assert!(shard.contains("a"));
+        for invalid in ["0/3", "4/3", "1", "a/3", "1/0"] {
+            assert!(invalid.parse::<Shard>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn samples() {
+        assert!(matches!("25%".parse(), Ok(Sample::Percent(p)) if p == 25.0));
+        assert!(matches!("10".parse(), Ok(Sample::Count(10))));
+        for invalid in ["101%", "-1%", "x%", "-3", "ten"] {
+            assert!(invalid.parse::<Sample>().is_err(), "{invalid}");
+        }
+        let sample =
+            |sample, seed| -> Vec<usize> { sample_jobs(Box::new(0..100), sample, seed).collect() };
+        let jobs = sample(Sample::Count(10), 7);
+        assert_eq!(jobs.len(), 10);
+        assert!(jobs.is_sorted());
+        assert_eq!(jobs, sample(Sample::Count(10), 7));
+        assert_eq!(sample(Sample::Count(200), 7), (0..100).collect::<Vec<_>>());
+        assert!(sample(Sample::Percent(0.0), 7).is_empty());
+        assert_eq!(sample(Sample::Percent(100.0), 7).len(), 100);
+    }
+
+    #[test]
+    fn script_like_lines() {
+        let lines = [
+            "# a comment",
+            "",
+            "   ",
+            "echo one \\",
+            "two",
+            "  # indented comment",
+            "#[timeout=1] sleep 5",
+            "echo 'a\\\\'",
+            "echo last \\",
+        ];
+        let lines: Vec<String> =
+            script_lines(Box::new(lines.iter().map(|line| (*line).to_string()))).collect();
+        assert_eq!(
+            lines,
+            [
+                "echo one two",
+                "#[timeout=1] sleep 5",
+                "echo 'a\\\\'",
+                "echo last "
+            ]
+        );
+    }
+
+    #[test]
+    fn prefixes() {
+        let split = |line: &str| split_prefixes(line.to_string());
+        assert_eq!(split("echo a"), (0, None, String::from("echo a")));
+        assert_eq!(split("@high: echo a"), (1, None, String::from("echo a")));
+        assert_eq!(split("@-5: echo a"), (-5, None, String::from("echo a")));
+        assert_eq!(
+            split("@low: @lock=db1: echo a"),
+            (-1, Some(String::from("db1")), String::from("echo a"))
+        );
+        // Not a prefix, e.g. an email address
+        assert_eq!(
+            split("@someone: hello"),
+            (0, None, String::from("@someone: hello"))
+        );
+        assert_eq!(split("@lock=: x"), (0, None, String::from("@lock=: x")));
+    }
+
+    #[test]
+    fn directives() {
+        let (limits, command) =
+            split_directives("#[timeout=1m, retries=2,shell=bash,cpu=50%,memory=1G,nice=5] make")
+                .unwrap();
+        assert_eq!(command, "make");
+        assert_eq!(limits.timeout, Some(Duration::from_secs(60)));
+        assert_eq!(limits.retries, Some(2));
+        assert_eq!(limits.shell, Some(OsString::from("bash")));
+        assert_eq!(limits.cpu, Some(50.0));
+        assert_eq!(limits.memory, Some(1 << 30));
+        assert_eq!(limits.nice, Some(5));
+        let (limits, command) = split_directives("echo #[timeout=1]").unwrap();
+        assert_eq!(command, "echo #[timeout=1]");
+        assert!(limits.timeout.is_none());
+        let (limits, _) = split_directives("#[umask=077] x").unwrap();
+        assert_eq!(limits.umask, Some(0o77));
+        let (limits, _) = split_directives("#[nice=-20] x").unwrap();
+        assert_eq!(limits.nice, Some(-20));
+        for invalid in [
+            "#[timeout] x",
+            "#[retries=-1] x",
+            "#[color=red] x",
+            "#[umask=999] x",
+            "#[nice=20] x",
+            "#[nice=-21] x",
+        ] {
+            assert!(split_directives(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn records_not_utf8() {
+        let input: &[u8] = b"one\0b\xffd\0three\0";
+        let nul: Vec<String> = records(input, true).collect();
+        assert_eq!(nul, ["one", "three"]);
+        let input: &[u8] = b"one\r\nb\xffd\nthree";
+        let lines: Vec<String> = records(input, false).collect();
+        assert_eq!(lines, ["one", "three"]);
+    }
 }
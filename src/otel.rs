@@ -0,0 +1,271 @@
+// OpenTelemetry trace export (OTLP over HTTP with JSON encoding)
+//
+// The whole run is the root span and every finished job one of its child
+// spans. Spans are sent in batches to e.g. http://localhost:4318/v1/traces,
+// only plain HTTP is supported.
+use crate::{default_seed, json::Object, signal, JobResult, Rng};
+use log::{debug, warn};
+use std::{
+    fmt::Write as _,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Job spans sent at once
+const BATCH_SIZE: usize = 256;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const STATUS_OK: u32 = 1;
+const STATUS_ERROR: u32 = 2;
+
+#[derive(Debug)]
+pub struct Tracer {
+    host: String,
+    port: u16,
+    path: String,
+    hostname: String,
+    trace_id: String,
+    root_id: String,
+    start: SystemTime,
+    rng: Mutex<Rng>,
+    spans: Mutex<Vec<Object>>,
+}
+
+impl Tracer {
+    pub fn new(endpoint: &str) -> Result<Self, String> {
+        let Some(rest) = endpoint.strip_prefix("http://") else {
+            return Err(format!(
+                "unsupported endpoint '{endpoint}', expected http://HOST:PORT[/PATH]"
+            ));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/v1/traces"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|e| format!("invalid port '{port}': {e}"))?,
+            ),
+            None => (authority, 4318),
+        };
+        let mut rng = Rng::new(default_seed());
+        Ok(Tracer {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            hostname: hostname(),
+            trace_id: hex(&mut rng, 2),
+            root_id: hex(&mut rng, 1),
+            start: SystemTime::now(),
+            rng: Mutex::new(rng),
+            spans: Mutex::new(vec![]),
+        })
+    }
+
+    // W3C trace context of the run, handed to the jobs as TRACEPARENT
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-01", self.trace_id, self.root_id)
+    }
+
+    pub fn job(&self, result: &JobResult, success: bool) {
+        let end = SystemTime::now();
+        let span_id = hex(&mut self.rng.lock().unwrap(), 1);
+        let mut attributes = vec![
+            attribute("process.command_line", Value::String(&result.job)),
+            attribute("parallel_sh.seq", Value::Int(result.seq as i64)),
+            attribute("host.name", Value::String(&self.hostname)),
+        ];
+        if let Some(label) = &result.label {
+            attributes.push(attribute("parallel_sh.label", Value::String(label)));
+        }
+        if let Some(e) = &result.spawn_error {
+            attributes.push(attribute(
+                "parallel_sh.spawn_error",
+                Value::String(&e.to_string()),
+            ));
+        }
+        if let Some(code) = result.output.status.code() {
+            attributes.push(attribute("process.exit.code", Value::Int(code.into())));
+        }
+        if let Some(sig) = signal(result.output.status) {
+            attributes.push(attribute("parallel_sh.signal", Value::Int(sig.into())));
+        }
+        let span = span(
+            &self.trace_id,
+            &span_id,
+            Some(&self.root_id),
+            &result.job,
+            end - result.duration,
+            end,
+            attributes,
+            success,
+        );
+        let batch = {
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(span);
+            if spans.len() < BATCH_SIZE {
+                return;
+            }
+            std::mem::take(&mut *spans)
+        };
+        self.export(batch);
+    }
+
+    // Send the root span along with all remaining job spans
+    pub fn finish(&self, exit: i32) {
+        let mut spans = std::mem::take(&mut *self.spans.lock().unwrap());
+        spans.push(span(
+            &self.trace_id,
+            &self.root_id,
+            None,
+            env!("CARGO_PKG_NAME"),
+            self.start,
+            SystemTime::now(),
+            vec![
+                attribute("host.name", Value::String(&self.hostname)),
+                attribute("process.exit.code", Value::Int(exit.into())),
+            ],
+            exit == 0,
+        ));
+        self.export(spans);
+    }
+
+    fn export(&self, spans: Vec<Object>) {
+        let count = spans.len();
+        let resource = Object::new().field(
+            "attributes",
+            vec![attribute(
+                "service.name",
+                Value::String(env!("CARGO_PKG_NAME")),
+            )],
+        );
+        let scope = Object::new()
+            .field("name", env!("CARGO_PKG_NAME"))
+            .field("version", env!("CARGO_PKG_VERSION"));
+        let body = Object::new()
+            .field(
+                "resourceSpans",
+                vec![Object::new().field("resource", resource).field(
+                    "scopeSpans",
+                    vec![Object::new().field("scope", scope).field("spans", spans)],
+                )],
+            )
+            .build();
+        match self.post(&body) {
+            Ok(()) => debug!("Exported {} span(s)", count),
+            Err(e) => warn!("Could not export {} span(s): {}", count, e),
+        }
+    }
+
+    fn post(&self, body: &str) -> Result<(), String> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|e| e.to_string())?
+            .next()
+            .ok_or_else(|| format!("could not resolve '{}'", self.host))?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .and_then(|()| stream.set_write_timeout(Some(TIMEOUT)))
+            .map_err(|e| e.to_string())?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            self.port,
+            body.len(),
+            body
+        )
+        .map_err(|e| e.to_string())?;
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .map_err(|e| e.to_string())?;
+        let status = response.lines().next().unwrap_or_default();
+        if status
+            .split_whitespace()
+            .nth(1)
+            .is_some_and(|code| code.starts_with('2'))
+        {
+            Ok(())
+        } else {
+            Err(format!("collector responded with '{status}'"))
+        }
+    }
+}
+
+enum Value<'a> {
+    String(&'a str),
+    Int(i64),
+}
+
+fn attribute(key: &str, value: Value) -> Object {
+    let value = match value {
+        Value::String(s) => Object::new().field("stringValue", s),
+        // 64 bit integers are strings in OTLP/JSON
+        Value::Int(i) => Object::new().field("intValue", i.to_string()),
+    };
+    Object::new().field("key", key).field("value", value)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn span(
+    trace_id: &str,
+    span_id: &str,
+    parent_id: Option<&str>,
+    name: &str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<Object>,
+    success: bool,
+) -> Object {
+    Object::new()
+        .field("traceId", trace_id)
+        .field("spanId", span_id)
+        .field("parentSpanId", parent_id.unwrap_or_default())
+        .field("name", name)
+        // SPAN_KIND_INTERNAL
+        .field("kind", 1)
+        .field("startTimeUnixNano", nanos(start))
+        .field("endTimeUnixNano", nanos(end))
+        .field("attributes", attributes)
+        .field(
+            "status",
+            Object::new().field("code", if success { STATUS_OK } else { STATUS_ERROR }),
+        )
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos())
+        .to_string()
+}
+
+// Random id of `words` times 64 bits, as lowercase hex
+fn hex(rng: &mut Rng, words: usize) -> String {
+    (0..words).fold(String::new(), |mut id, _| {
+        let _ = write!(id, "{:016x}", rng.next_u64());
+        id
+    })
+}
+
+#[cfg(not(target_os = "windows"))]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: buf is valid for buf.len() bytes
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return String::new();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[cfg(target_os = "windows")]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
@@ -0,0 +1,824 @@
+// Everything parallel-sh prints to the terminal goes through here, so a job's
+// stdout and stderr stay adjacent and log lines never end up in between
+use crate::json::Object;
+use log::{info, warn, Log, Metadata, Record};
+use simplelog::{Config, LevelFilter, SharedLogger};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::PathBuf,
+    process::Output,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+// Holds the status (--bar, --tui) shown below everything else, if any
+static TERMINAL: Mutex<Option<String>> = Mutex::new(None);
+
+// Access to the terminal, the status is taken down meanwhile and drawn again
+// once dropped
+struct Terminal(MutexGuard<'static, Option<String>>);
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if let Some(status) = self.0.as_deref() {
+            let mut err = io::stderr().lock();
+            let _ = err.write_all(status.as_bytes()).and(err.flush());
+        }
+    }
+}
+
+fn lock() -> Terminal {
+    let status = TERMINAL.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(status) = status.as_deref() {
+        // Back to the first line of the status, then clear the rest
+        let up = "\x1b[A".repeat(status.matches('\n').count());
+        let _ = io::stderr()
+            .lock()
+            .write_all(format!("\r{up}\x1b[J").as_bytes());
+    }
+    Terminal(status)
+}
+
+// Show `status` in the last lines of the terminal, or remove it
+pub fn set_status(status: Option<String>) {
+    let mut terminal = lock();
+    *terminal.0 = status;
+}
+
+// A line of its own on stdout between the jobs' output (--echo)
+pub fn print_stdout(text: &str) {
+    let _terminal = lock();
+    let mut out = io::stdout().lock();
+    let _ = out.write_all(text.as_bytes()).and(out.flush());
+}
+
+// Text of its own on stderr, e.g. a report asked for with SIGUSR1
+pub fn print_stderr(text: &str) {
+    let _terminal = lock();
+    let mut err = io::stderr().lock();
+    let _ = err.write_all(text.as_bytes()).and(err.flush());
+}
+
+// Wraps a logger writing to the terminal, so it takes the terminal lock
+pub struct Serialized(Box<dyn SharedLogger>);
+
+impl Serialized {
+    pub fn new(logger: Box<dyn SharedLogger>) -> Box<Self> {
+        Box::new(Serialized(logger))
+    }
+}
+
+impl Log for Serialized {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let _terminal = lock();
+            self.0.log(record);
+            self.0.flush();
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+impl SharedLogger for Serialized {
+    fn level(&self) -> LevelFilter {
+        self.0.level()
+    }
+
+    fn config(&self) -> Option<&Config> {
+        self.0.config()
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        self
+    }
+}
+
+// How often an unwritable log file is tried again
+const REOPEN_INTERVAL: Duration = Duration::from_secs(30);
+
+// Log records that could not be written to the log file
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn dropped_log_records() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+// The --log file. Once it cannot be written to anymore (e.g. the disk is
+// full), only the terminal gets log messages until it can be reopened, or
+// parallel-sh stops with --log-required.
+#[derive(Debug)]
+pub struct LogFile {
+    path: PathBuf,
+    file: Option<File>,
+    required: bool,
+    failed_at: Option<Instant>,
+}
+
+impl LogFile {
+    pub fn open(path: PathBuf, required: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        Ok(LogFile {
+            path,
+            file: Some(file),
+            required,
+            failed_at: None,
+        })
+    }
+
+    fn fail(&mut self, e: &io::Error) {
+        let _terminal = lock();
+        if self.required {
+            eprintln!(
+                "ERROR: Could not write to log file '{}': {}",
+                self.path.display(),
+                e
+            );
+            drop(_terminal);
+            crate::jobctl::exit(1);
+        }
+        if self.file.take().is_some() {
+            eprintln!(
+                "WARNING: Could not write to log file '{}': {}, logging to the terminal only",
+                self.path.display(),
+                e
+            );
+        }
+        self.failed_at = Some(Instant::now());
+    }
+
+    fn reopen(&mut self) {
+        if self
+            .failed_at
+            .is_none_or(|failed_at| failed_at.elapsed() < REOPEN_INTERVAL)
+        {
+            return;
+        }
+        match OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+        {
+            Ok(file) => {
+                let _terminal = lock();
+                eprintln!(
+                    "WARNING: Writing to log file '{}' again, {} record(s) are missing",
+                    self.path.display(),
+                    dropped_log_records()
+                );
+                self.file = Some(file);
+                self.failed_at = None;
+            }
+            Err(_) => self.failed_at = Some(Instant::now()),
+        }
+    }
+}
+
+impl Write for LogFile {
+    // A record is written in several pieces, but after the first failed one
+    // the rest is skipped, so every failure is one record
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.file.is_none() {
+            self.reopen();
+        }
+        let result = match self.file.as_mut() {
+            Some(file) => file.write(buf),
+            None => Err(io::Error::other("log file is not writable")),
+        };
+        if let Err(e) = &result {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            self.fail(e);
+        }
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().map_or(Ok(()), Write::flush)
+    }
+}
+
+// Collapses consecutive identical lines of an output stream
+#[derive(Debug, Default)]
+pub struct Squasher {
+    last: Option<Vec<u8>>,
+    count: usize,
+}
+
+impl Squasher {
+    fn write(&mut self, output: &[u8], out: &mut impl Write) -> io::Result<()> {
+        for line in output.split_inclusive(|&b| b == b'\n') {
+            if self.last.as_deref() == Some(line) {
+                self.count += 1;
+            } else {
+                self.flush(out)?;
+                self.last = Some(line.to_vec());
+                self.count = 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, out: &mut impl Write) -> io::Result<()> {
+        if let Some(last) = self.last.take() {
+            let len = last
+                .strip_suffix(b"\r\n")
+                .or_else(|| last.strip_suffix(b"\n"))
+                .map_or(last.len(), <[u8]>::len);
+            out.write_all(&last[..len])?;
+            if self.count > 1 {
+                write!(out, " (x{})", self.count)?;
+            }
+            if len == last.len() {
+                out.write_all(b"\n")?;
+            } else {
+                out.write_all(&last[len..])?;
+            }
+        }
+        self.count = 0;
+        Ok(())
+    }
+}
+
+// How finished jobs are printed: their plain output, or one JSON object or
+// CSV/TSV row each
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+    Tsv,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            _ => Err(format!(
+                "unknown format '{s}', expected text, json, csv or tsv"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Newline {
+    Lf,
+    Crlf,
+}
+
+impl std::str::FromStr for Newline {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lf" => Ok(Newline::Lf),
+            "crlf" => Ok(Newline::Crlf),
+            "native" if cfg!(target_os = "windows") => Ok(Newline::Crlf),
+            "native" => Ok(Newline::Lf),
+            _ => Err(format!(
+                "unknown newline '{s}', expected one of lf, crlf, native"
+            )),
+        }
+    }
+}
+
+impl Newline {
+    pub fn normalize(self, output: &[u8]) -> Vec<u8> {
+        let mut normalized = Vec::with_capacity(output.len());
+        for line in output.split_inclusive(|&b| b == b'\n') {
+            let Some(body) = line.strip_suffix(b"\n") else {
+                normalized.extend_from_slice(line);
+                break;
+            };
+            normalized.extend_from_slice(body.strip_suffix(b"\r").unwrap_or(body));
+            normalized.extend_from_slice(match self {
+                Newline::Lf => b"\n",
+                Newline::Crlf => b"\r\n",
+            });
+        }
+        normalized
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OutputEncoding {
+    Utf8,
+    // Little endian unless there is a byte order mark
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+    Windows1252,
+    Bytes,
+    // Decided by a byte order mark, UTF-8 without one
+    Auto,
+}
+
+// Windows-1252 is Latin-1 except for 0x80 to 0x9F, the unassigned ones are
+// taken as they are
+const WINDOWS_1252: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+const UTF8_BOM: [u8; 3] = [0xef, 0xbb, 0xbf];
+const UTF16_LE_BOM: [u8; 2] = [0xff, 0xfe];
+const UTF16_BE_BOM: [u8; 2] = [0xfe, 0xff];
+
+fn utf16(output: &[u8], unit: fn([u8; 2]) -> u16) -> Vec<u8> {
+    let units = output.chunks_exact(2).map(|pair| unit([pair[0], pair[1]]));
+    let mut text: String = char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect();
+    if output.len() % 2 == 1 {
+        text.push(char::REPLACEMENT_CHARACTER);
+    }
+    text.into_bytes()
+}
+
+impl std::str::FromStr for OutputEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "utf8" | "utf-8" => Ok(OutputEncoding::Utf8),
+            "utf16" | "utf-16" | "utf16le" | "utf-16le" => Ok(OutputEncoding::Utf16Le),
+            "utf16be" | "utf-16be" => Ok(OutputEncoding::Utf16Be),
+            "latin1" | "iso-8859-1" => Ok(OutputEncoding::Latin1),
+            "cp1252" | "windows-1252" => Ok(OutputEncoding::Windows1252),
+            "bytes" => Ok(OutputEncoding::Bytes),
+            "auto" => Ok(OutputEncoding::Auto),
+            _ => Err(format!(
+                "unknown encoding '{s}', expected one of utf8, utf16, utf16be, latin1, cp1252, bytes, auto"
+            )),
+        }
+    }
+}
+
+impl OutputEncoding {
+    // Convert captured output to what is written to the terminal
+    pub fn decode(self, output: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            OutputEncoding::Utf8 => match String::from_utf8_lossy(output) {
+                Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+                Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+            },
+            OutputEncoding::Utf16Le => match output.strip_prefix(&UTF16_BE_BOM) {
+                Some(output) => Cow::Owned(utf16(output, u16::from_be_bytes)),
+                None => Cow::Owned(utf16(
+                    output.strip_prefix(&UTF16_LE_BOM).unwrap_or(output),
+                    u16::from_le_bytes,
+                )),
+            },
+            OutputEncoding::Utf16Be => Cow::Owned(utf16(
+                output.strip_prefix(&UTF16_BE_BOM).unwrap_or(output),
+                u16::from_be_bytes,
+            )),
+            OutputEncoding::Latin1 => Cow::Owned(
+                output
+                    .iter()
+                    .map(|&b| char::from(b))
+                    .collect::<String>()
+                    .into_bytes(),
+            ),
+            OutputEncoding::Windows1252 => Cow::Owned(
+                output
+                    .iter()
+                    .map(|&b| match b {
+                        0x80..=0x9f => WINDOWS_1252[usize::from(b - 0x80)],
+                        b => char::from(b),
+                    })
+                    .collect::<String>()
+                    .into_bytes(),
+            ),
+            OutputEncoding::Bytes => Cow::Borrowed(output),
+            OutputEncoding::Auto => {
+                if let Some(output) = output.strip_prefix(&UTF8_BOM) {
+                    OutputEncoding::Utf8.decode(output)
+                } else if output.starts_with(&UTF16_LE_BOM) {
+                    OutputEncoding::Utf16Le.decode(output)
+                } else if output.starts_with(&UTF16_BE_BOM) {
+                    OutputEncoding::Utf16Be.decode(output)
+                } else {
+                    OutputEncoding::Utf8.decode(output)
+                }
+            }
+        }
+    }
+}
+
+// How the output of a job reaches the terminal: all of it at once when the
+// job is done (--group), as it is written (--ungroup), or as whole lines
+// (--line-buffer) so lines of different jobs are not mixed up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Grouping {
+    Group,
+    Ungroup,
+    LineBuffer,
+}
+
+// Pass a job's output stream on to stdout or stderr while it is written,
+// instead of capturing it
+pub fn forward(mut stream: impl Read, stderr: bool, grouping: Grouping) -> io::Result<()> {
+    let write = |bytes: &[u8]| {
+        let _terminal = lock();
+        let result = if stderr {
+            let mut err = io::stderr().lock();
+            err.write_all(bytes).and(err.flush())
+        } else {
+            let mut out = io::stdout().lock();
+            out.write_all(bytes).and(out.flush())
+        };
+        broken_pipe(result)
+    };
+    let mut pending = Vec::new();
+    let mut chunk = vec![0; 64 * 1024];
+    loop {
+        let n = match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if grouping != Grouping::LineBuffer {
+            write(&chunk[..n])?;
+            continue;
+        }
+        pending.extend_from_slice(&chunk[..n]);
+        if let Some(i) = pending.iter().rposition(|&b| b == b'\n') {
+            write(&pending[..=i])?;
+            pending.drain(..=i);
+        }
+    }
+    // A last line without a newline
+    if pending.is_empty() {
+        Ok(())
+    } else {
+        write(&pending)
+    }
+}
+
+// Read a job's output stream until it is closed, keeping only the first and
+// last `limit / 2` bytes (--max-output) so a chatty job cannot use up all
+// memory
+pub fn capture(mut stream: impl Read, limit: Option<usize>) -> io::Result<Vec<u8>> {
+    let mut head = None;
+    let mut output = Vec::new();
+    let mut chunk = vec![0; 64 * 1024];
+    let mut omitted = 0;
+    loop {
+        let n = match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        output.extend_from_slice(&chunk[..n]);
+        // Trimmed once twice the limit is reached, not on every read
+        if let Some(limit) = limit.filter(|&limit| output.len() > 2 * limit) {
+            omitted += trim(&mut head, &mut output, limit);
+        }
+    }
+    if let Some(limit) = limit.filter(|&limit| head.is_some() || output.len() > limit) {
+        omitted += trim(&mut head, &mut output, limit);
+    }
+    let Some(mut head) = head else {
+        return Ok(output);
+    };
+    // Cut at complete lines
+    if let Some(i) = head.iter().rposition(|&b| b == b'\n') {
+        omitted += head.len() - i - 1;
+        head.truncate(i + 1);
+    }
+    if let Some(i) = output.iter().position(|&b| b == b'\n') {
+        omitted += i + 1;
+        output.drain(..=i);
+    }
+    if !head.is_empty() && !head.ends_with(b"\n") {
+        head.push(b'\n');
+    }
+    head.extend_from_slice(format!("[{omitted} bytes of output omitted]\n").as_bytes());
+    head.append(&mut output);
+    Ok(head)
+}
+
+// Keep the first half of `limit` in `head` and the rest of it at the end of
+// `output`, returns the number of bytes dropped
+fn trim(head: &mut Option<Vec<u8>>, output: &mut Vec<u8>, limit: usize) -> usize {
+    let head = head.get_or_insert_with(|| output.drain(..(limit / 2).min(output.len())).collect());
+    let dropped = output.len().saturating_sub(limit - head.len());
+    output.drain(..dropped);
+    dropped
+}
+
+// Output collected with --flush-interval, written once it is due or too large
+#[derive(Debug)]
+struct Buffer {
+    interval: Duration,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    since: Option<Instant>,
+}
+
+const MAX_BUFFERED: usize = 64 * 1024;
+
+// Only a closed stdout or stderr is worth stopping for
+fn broken_pipe(result: io::Result<()>) -> io::Result<()> {
+    result.or_else(|e| {
+        if e.kind() == io::ErrorKind::BrokenPipe {
+            Err(e)
+        } else {
+            Ok(())
+        }
+    })
+}
+
+// Prefix every line with `tag` and a tab (--tag)
+fn tag_lines(tag: &str, output: &[u8]) -> Vec<u8> {
+    let mut tagged = Vec::with_capacity(output.len());
+    for line in output.split_inclusive(|&b| b == b'\n') {
+        tagged.extend_from_slice(tag.as_bytes());
+        tagged.push(b'\t');
+        tagged.extend_from_slice(line);
+    }
+    tagged
+}
+
+// Prints the output of finished jobs
+#[derive(Debug)]
+pub struct Printer {
+    encoding: OutputEncoding,
+    newline: Option<Newline>,
+    squash: Option<(Squasher, Squasher)>,
+    buffer: Option<Buffer>,
+    // The records of finished jobs go here instead of stdout (--output-file)
+    results: Option<io::BufWriter<File>>,
+}
+
+impl Printer {
+    pub fn new(
+        encoding: OutputEncoding,
+        newline: Option<Newline>,
+        squash_repeats: bool,
+        flush_interval: Option<Duration>,
+    ) -> Self {
+        Printer {
+            encoding,
+            newline,
+            squash: squash_repeats.then(|| (Squasher::default(), Squasher::default())),
+            buffer: flush_interval.map(|interval| Buffer {
+                interval,
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+                since: None,
+            }),
+            results: None,
+        }
+    }
+
+    pub fn set_output_file(&mut self, file: File) {
+        self.results = Some(io::BufWriter::new(file));
+    }
+
+    fn convert<'a>(&self, output: &'a [u8], tag: Option<&str>) -> Cow<'a, [u8]> {
+        let output = self.encoding.decode(output);
+        let output = match self.newline {
+            Some(newline) => Cow::Owned(newline.normalize(&output)),
+            None => output,
+        };
+        match tag {
+            Some(tag) => Cow::Owned(tag_lines(tag, &output)),
+            None => output,
+        }
+    }
+
+    fn write(
+        squash: Option<&mut (Squasher, Squasher)>,
+        stdout: &[u8],
+        stderr: &[u8],
+        out: &mut impl Write,
+        err: &mut impl Write,
+    ) -> io::Result<()> {
+        if let Some((squash_out, squash_err)) = squash {
+            let out_result = squash_out.write(stdout, out);
+            out_result.and(squash_err.write(stderr, err))
+        } else {
+            let out_result = out.write_all(stdout);
+            out_result.and(err.write_all(stderr))
+        }
+    }
+
+    // Write a job's stdout and stderr in one go, fails once either has been
+    // closed by the reader
+    pub fn print(&mut self, output: &Output, tag: Option<&str>) -> io::Result<()> {
+        let stdout = self.convert(&output.stdout, tag);
+        let stderr = self.convert(&output.stderr, tag);
+        self.emit(&stdout, &stderr, true)
+    }
+
+    // Write a line of its own to stdout (--format json), unconverted
+    pub fn print_record(&mut self, record: &str) -> io::Result<()> {
+        self.emit(format!("{record}\n").as_bytes(), &[], false)
+    }
+
+    // A finished job as a JSON object or CSV row, or the CSV header
+    pub fn print_result(&mut self, record: &str) -> io::Result<()> {
+        match self.results.as_mut() {
+            Some(file) => writeln!(file, "{record}"),
+            None => self.print_record(record),
+        }
+    }
+
+    fn emit(&mut self, stdout: &[u8], stderr: &[u8], squash: bool) -> io::Result<()> {
+        let squash = if squash { self.squash.as_mut() } else { None };
+        if let Some(buffer) = self.buffer.as_mut() {
+            let _ = Printer::write(
+                squash,
+                stdout,
+                stderr,
+                &mut buffer.stdout,
+                &mut buffer.stderr,
+            );
+            let since = *buffer.since.get_or_insert_with(Instant::now);
+            if buffer.stdout.len() + buffer.stderr.len() < MAX_BUFFERED
+                && since.elapsed() < buffer.interval
+            {
+                return Ok(());
+            }
+            return self.flush();
+        }
+        let _terminal = lock();
+        let (mut out, mut err) = (io::stdout().lock(), io::stderr().lock());
+        let result = Printer::write(squash, stdout, stderr, &mut out, &mut err);
+        broken_pipe(result.and(out.flush()).and(err.flush()))
+    }
+
+    // Write buffered output
+    pub fn flush(&mut self) -> io::Result<()> {
+        let Some(buffer) = self.buffer.as_mut() else {
+            return Ok(());
+        };
+        buffer.since = None;
+        if buffer.stdout.is_empty() && buffer.stderr.is_empty() {
+            return Ok(());
+        }
+        let _terminal = lock();
+        let (mut out, mut err) = (io::stdout().lock(), io::stderr().lock());
+        let result = out
+            .write_all(&buffer.stdout)
+            .and(err.write_all(&buffer.stderr))
+            .and(out.flush())
+            .and(err.flush());
+        buffer.stdout.clear();
+        buffer.stderr.clear();
+        broken_pipe(result)
+    }
+
+    // Time left until buffered output has to be written, None if there is none
+    pub fn due(&self) -> Option<Duration> {
+        let buffer = self.buffer.as_ref()?;
+        buffer
+            .since
+            .map(|since| buffer.interval.saturating_sub(since.elapsed()))
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(file) = self.results.as_mut() {
+            if let Err(e) = file.flush() {
+                warn!("Could not write output file: {}", e);
+            }
+        }
+        if let Some((squash_out, squash_err)) = self.squash.as_mut() {
+            if let Some(buffer) = self.buffer.as_mut() {
+                let _ = squash_out.flush(&mut buffer.stdout);
+                let _ = squash_err.flush(&mut buffer.stderr);
+            } else {
+                let _terminal = lock();
+                let _ = squash_out.flush(&mut io::stdout());
+                let _ = squash_err.flush(&mut io::stderr());
+            }
+        }
+        let _ = self.flush();
+    }
+}
+
+// Failed jobs whose output was not printed (--max-fail-output), counted by
+// exit code and first line of stderr
+#[derive(Debug, Default)]
+pub struct FailureSummary(BTreeMap<(Option<i32>, String), usize>);
+
+impl FailureSummary {
+    pub fn add(&mut self, output: &Output) {
+        let first_line = String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .unwrap_or_default()
+            .to_string();
+        *self
+            .0
+            .entry((output.status.code(), first_line))
+            .or_default() += 1;
+    }
+
+    pub fn log(&self) {
+        let total: usize = self.0.values().sum();
+        if total == 0 {
+            return;
+        }
+        warn!("Output of {} more failed job(s) was suppressed:", total);
+        for ((code, first_line), count) in &self.0 {
+            let code = code.map_or_else(|| String::from("none"), |code| code.to_string());
+            if first_line.is_empty() {
+                warn!("  {} job(s) with exit code {}", count, code);
+            } else {
+                warn!("  {} job(s) with exit code {}: {}", count, code, first_line);
+            }
+        }
+    }
+}
+
+// Finished jobs counted by exit code, and by signal for killed ones
+#[derive(Debug, Default)]
+pub struct ExitCodes {
+    codes: BTreeMap<i32, usize>,
+    signals: BTreeMap<i32, usize>,
+}
+
+impl ExitCodes {
+    pub fn add(&mut self, code: Option<i32>, signal: Option<i32>) {
+        match (code, signal) {
+            (_, Some(signal)) => *self.signals.entry(signal).or_default() += 1,
+            (Some(code), None) => *self.codes.entry(code).or_default() += 1,
+            (None, None) => {}
+        }
+    }
+
+    // e.g. "0: 9,854, 1: 120, SIG9: 3"
+    fn histogram(&self) -> String {
+        let codes = self
+            .codes
+            .iter()
+            .map(|(code, count)| format!("{code}: {}", thousands(*count)));
+        let signals = self
+            .signals
+            .iter()
+            .map(|(signal, count)| format!("SIG{signal}: {}", thousands(*count)));
+        codes.chain(signals).collect::<Vec<_>>().join(", ")
+    }
+
+    // A warning if any job failed
+    pub fn log(&self) {
+        if self.codes.is_empty() && self.signals.is_empty() {
+            return;
+        }
+        if self.signals.is_empty() && self.codes.keys().all(|&code| code == 0) {
+            info!("Exit codes: {}", self.histogram());
+        } else {
+            warn!("Exit codes: {}", self.histogram());
+        }
+    }
+
+    // Add the counts to a JSON object, keyed by exit code and signal
+    pub fn add_to(&self, object: Object) -> Object {
+        let counts = |map: &BTreeMap<i32, usize>| {
+            map.iter().fold(Object::new(), |object, (key, count)| {
+                object.field(&key.to_string(), *count)
+            })
+        };
+        object
+            .field("exit_codes", counts(&self.codes))
+            .field("signals", counts(&self.signals))
+    }
+}
+
+// 9854 as "9,854"
+fn thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
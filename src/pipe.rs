@@ -0,0 +1,214 @@
+// --pipe: stdin is cut into blocks of whole lines, and each block becomes the
+// stdin of a job of its own, all running the same command
+//
+// --pipepart does the same for a file, but only finds the line boundaries
+// up front; every job reads its own part of the file while it runs.
+//
+// With --block auto the blocks start at DEFAULT_BLOCK_SIZE, and then follow
+// the throughput of the jobs finished so far, so that a job takes about
+// TARGET_RUNTIME: many tiny jobs spend their time starting, a few huge ones
+// leave the other slots idle at the end.
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{Arc, Condvar, Mutex, PoisonError},
+    time::Duration,
+};
+
+#[derive(Debug, Clone)]
+pub enum Input {
+    Block(Arc<[u8]>),
+    Part {
+        file: Arc<Path>,
+        start: u64,
+        len: u64,
+    },
+}
+
+impl Input {
+    pub fn len(&self) -> u64 {
+        match self {
+            Input::Block(block) => block.len() as u64,
+            Input::Part { len, .. } => *len,
+        }
+    }
+
+    pub fn write_to(&self, mut stdin: impl Write) -> io::Result<()> {
+        match self {
+            Input::Block(block) => stdin.write_all(block),
+            Input::Part { file, start, len } => {
+                let mut file = File::open(file)?;
+                file.seek(SeekFrom::Start(*start))?;
+                io::copy(&mut file.take(*len), &mut stdin).map(drop)
+            }
+        }
+    }
+}
+
+pub const DEFAULT_BLOCK_SIZE: usize = 1 << 20;
+
+const TARGET_RUNTIME: Duration = Duration::from_secs(5);
+const MIN_AUTO_BLOCK: usize = 64 << 10;
+// Blocks are held in memory, a few per slot
+const MAX_AUTO_BLOCK: usize = 256 << 20;
+// The most a block grows or shrinks from one to the next, a single job may
+// be much faster or slower than the others
+const MAX_STEP: usize = 4;
+
+#[derive(Debug)]
+pub enum BlockSize {
+    Fixed(usize),
+    Auto(Arc<AutoBlock>),
+}
+
+impl BlockSize {
+    pub fn get(&self) -> usize {
+        match self {
+            BlockSize::Fixed(size) => *size,
+            BlockSize::Auto(auto) => auto.next(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Throughput {
+    bytes: u64,
+    time: Duration,
+    // The size of the last block
+    last: usize,
+}
+
+// The throughput of the jobs finished so far (--block auto)
+#[derive(Debug, Default)]
+pub struct AutoBlock(Mutex<Throughput>);
+
+impl AutoBlock {
+    pub fn finished(&self, bytes: u64, time: Duration) {
+        let mut throughput = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        throughput.bytes += bytes;
+        throughput.time += time;
+    }
+
+    fn next(&self) -> usize {
+        let mut throughput = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let size = if throughput.bytes == 0 || throughput.time.is_zero() {
+            DEFAULT_BLOCK_SIZE
+        } else {
+            #[allow(
+                clippy::cast_possible_truncation,
+                clippy::cast_precision_loss,
+                clippy::cast_sign_loss
+            )]
+            let size = (throughput.bytes as f64 / throughput.time.as_secs_f64()
+                * TARGET_RUNTIME.as_secs_f64()) as usize;
+            size.clamp(
+                throughput.last / MAX_STEP,
+                throughput.last.saturating_mul(MAX_STEP),
+            )
+            .clamp(MIN_AUTO_BLOCK, MAX_AUTO_BLOCK)
+        };
+        throughput.last = size;
+        size
+    }
+}
+
+// At least `size` bytes unless the input ends, up to the end of a line
+pub fn read_block(input: &mut impl BufRead, size: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut block = Vec::with_capacity(size);
+    while block.len() < size {
+        if input.read_until(b'\n', &mut block)? == 0 {
+            break;
+        }
+    }
+    Ok((!block.is_empty()).then_some(block))
+}
+
+// The part of `file` starting at `start`, at least `size` bytes unless the
+// file ends, up to the end of a line
+pub fn next_part(file: &File, start: u64, size: usize) -> io::Result<Option<u64>> {
+    let end = file.metadata()?.len();
+    if start >= end {
+        return Ok(None);
+    }
+    let last = start.saturating_add(size as u64).min(end) - 1;
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(last))?;
+    let mut line = vec![];
+    let len = reader.read_until(b'\n', &mut line)? as u64;
+    Ok(Some(last + len.max(1) - start))
+}
+
+// Blocks read but not finished yet, so stdin is not read into memory as a
+// whole while the jobs are slower than its producer
+#[derive(Debug)]
+pub struct Backlog {
+    blocks: Mutex<usize>,
+    finished: Condvar,
+    max: usize,
+}
+
+impl Backlog {
+    pub fn new(max: usize) -> Self {
+        Backlog {
+            blocks: Mutex::new(0),
+            finished: Condvar::new(),
+            max: max.max(1),
+        }
+    }
+
+    // Waits while there are too many blocks already
+    pub fn push(&self) {
+        let mut blocks = self.blocks.lock().unwrap_or_else(PoisonError::into_inner);
+        while *blocks >= self.max {
+            blocks = self
+                .finished
+                .wait(blocks)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        *blocks += 1;
+    }
+
+    pub fn pop(&self) {
+        let mut blocks = self.blocks.lock().unwrap_or_else(PoisonError::into_inner);
+        *blocks = blocks.saturating_sub(1);
+        self.finished.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_end_with_lines() {
+        let mut input = io::Cursor::new(b"one\ntwo\nthree\nfour".to_vec());
+        let mut blocks = vec![];
+        while let Some(block) = read_block(&mut input, 5).unwrap() {
+            blocks.push(String::from_utf8(block).unwrap());
+        }
+        assert_eq!(blocks, ["one\ntwo\n", "three\n", "four"]);
+    }
+
+    #[test]
+    fn auto_block_follows_throughput() {
+        let auto = AutoBlock::default();
+        assert_eq!(auto.next(), DEFAULT_BLOCK_SIZE);
+        // 1M in 100ms is 50M in 5s, but a block grows 4 times at most
+        auto.finished(1 << 20, Duration::from_millis(100));
+        assert_eq!(auto.next(), 4 << 20);
+        assert_eq!(auto.next(), 16 << 20);
+        assert_eq!(auto.next(), 50 << 20);
+        // Now 51M took 20.1s, about 12.7M in 5s
+        auto.finished(50 << 20, Duration::from_secs(20));
+        let size = auto.next();
+        assert!((12 << 20..13 << 20).contains(&size), "{size}");
+        // Down to about 74K, 51M in an hour
+        auto.finished(0, Duration::from_secs(3580));
+        assert_eq!(auto.next(), size / 4);
+        assert_eq!(auto.next(), size / 16);
+        assert_eq!(auto.next(), size / 64);
+        let size = auto.next();
+        assert!((MIN_AUTO_BLOCK..80 << 10).contains(&size), "{size}");
+    }
+}
@@ -0,0 +1,213 @@
+// Live progress on stderr (--bar, --eta): finished and failed jobs and, once
+// all jobs are known, how long the rest will take at the current pace
+//
+// --tui adds a dashboard above it, with the job running in every slot and
+// the jobs finished last.
+use crate::output;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const REDRAW_INTERVAL: Duration = Duration::from_secs(1);
+const BAR_WIDTH: usize = 30;
+// Slots shown on the dashboard, the others are only counted
+const MAX_SLOTS: usize = 20;
+const RECENT: usize = 5;
+const DEFAULT_WIDTH: usize = 80;
+
+#[derive(Debug, Default)]
+struct Dashboard {
+    // The command running in every busy slot, and since when
+    slots: Mutex<BTreeMap<usize, (String, Instant)>>,
+    recent: Mutex<VecDeque<String>>,
+}
+
+#[derive(Debug)]
+pub struct Progress {
+    bar: bool,
+    started: Instant,
+    finished: AtomicUsize,
+    failed: AtomicUsize,
+    // Set once every job has been queued
+    total: AtomicUsize,
+    total_known: AtomicBool,
+    done: AtomicBool,
+    dashboard: Option<Dashboard>,
+}
+
+impl Progress {
+    // Redrawn every second, so the ETA also moves while no job finishes
+    pub fn start(bar: bool, tui: bool) -> Arc<Self> {
+        let progress = Arc::new(Progress {
+            bar: bar || tui,
+            started: Instant::now(),
+            finished: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            total_known: AtomicBool::new(false),
+            done: AtomicBool::new(false),
+            dashboard: tui.then(Dashboard::default),
+        });
+        let ticker = Arc::clone(&progress);
+        thread::spawn(move || {
+            while !ticker.done.load(Ordering::SeqCst) {
+                ticker.draw();
+                thread::sleep(REDRAW_INTERVAL);
+            }
+        });
+        progress
+    }
+
+    // Follow-up jobs (--allow-enqueue) may have been added already
+    pub fn set_total(&self, total: usize) {
+        self.total.fetch_add(total, Ordering::SeqCst);
+        self.total_known.store(true, Ordering::SeqCst);
+        self.draw();
+    }
+
+    pub fn add_to_total(&self, jobs: usize) {
+        self.total.fetch_add(jobs, Ordering::SeqCst);
+    }
+
+    pub fn job_started(&self, slot: usize, command: &str) {
+        if let Some(dashboard) = &self.dashboard {
+            lock(&dashboard.slots).insert(slot, (command.to_string(), Instant::now()));
+            self.draw();
+        }
+    }
+
+    pub fn job_stopped(&self, slot: usize) {
+        if let Some(dashboard) = &self.dashboard {
+            lock(&dashboard.slots).remove(&slot);
+        }
+    }
+
+    pub fn job_finished(&self, job: &str, runtime: Duration, success: bool) {
+        self.finished.fetch_add(1, Ordering::SeqCst);
+        if !success {
+            self.failed.fetch_add(1, Ordering::SeqCst);
+        }
+        if let Some(dashboard) = &self.dashboard {
+            let mut recent = lock(&dashboard.recent);
+            if recent.len() == RECENT {
+                recent.pop_front();
+            }
+            let outcome = if success { "done" } else { "FAIL" };
+            recent.push_back(format!("{outcome} {:>7} {job}", clock(runtime)));
+        }
+        self.draw();
+    }
+
+    // Remove the status line for good
+    pub fn finish(&self) {
+        self.done.store(true, Ordering::SeqCst);
+        output::set_status(None);
+    }
+
+    fn draw(&self) {
+        if self.done.load(Ordering::SeqCst) {
+            return;
+        }
+        let status = match &self.dashboard {
+            Some(dashboard) => format!("{}{}", dashboard.lines(width()), self.status()),
+            None => self.status(),
+        };
+        output::set_status(Some(status));
+    }
+
+    fn status(&self) -> String {
+        let finished = self.finished.load(Ordering::SeqCst);
+        let failed = self.failed.load(Ordering::SeqCst);
+        let failed = if failed > 0 {
+            format!(" ({failed} failed)")
+        } else {
+            String::new()
+        };
+        if !self.total_known.load(Ordering::SeqCst) {
+            return format!("{finished}/? jobs done{failed}");
+        }
+        let total = self.total.load(Ordering::SeqCst).max(finished);
+        let eta = match finished {
+            0 => String::from("?"),
+            _ => {
+                #[allow(clippy::cast_precision_loss)]
+                let pace = (total - finished) as f64 / finished as f64;
+                clock(self.started.elapsed().mul_f64(pace))
+            }
+        };
+        let percent = (finished * 100).checked_div(total).unwrap_or(100);
+        if !self.bar {
+            return format!("{finished}/{total} jobs done{failed}, {percent}%, ETA {eta}");
+        }
+        let filled = (finished * BAR_WIDTH)
+            .checked_div(total)
+            .unwrap_or(BAR_WIDTH);
+        format!(
+            "[{}{}] {finished}/{total}{failed} {percent}% ETA {eta}",
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled)
+        )
+    }
+}
+
+impl Dashboard {
+    // Every line is cut to the width of the terminal, wrapped lines could not
+    // be taken down again
+    fn lines(&self, width: usize) -> String {
+        let mut lines = String::new();
+        let mut push = |line: String| {
+            lines.extend(line.chars().take(width.saturating_sub(1)));
+            lines.push('\n');
+        };
+        let slots = lock(&self.slots);
+        for (slot, (command, since)) in slots.iter().take(MAX_SLOTS) {
+            push(format!("{slot:>4} {:>7} {command}", clock(since.elapsed())));
+        }
+        if slots.len() > MAX_SLOTS {
+            push(format!("     and {} more running", slots.len() - MAX_SLOTS));
+        }
+        drop(slots);
+        push("-".repeat(width.min(BAR_WIDTH * 2)));
+        for line in lock(&self.recent).iter() {
+            push(line.clone());
+        }
+        lines
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Columns of the terminal on stderr
+#[cfg(not(target_os = "windows"))]
+fn width() -> usize {
+    // SAFETY: TIOCGWINSZ only writes to the winsize given
+    unsafe {
+        let mut size: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0 {
+            return usize::from(size.ws_col);
+        }
+    }
+    DEFAULT_WIDTH
+}
+
+#[cfg(target_os = "windows")]
+fn width() -> usize {
+    DEFAULT_WIDTH
+}
+
+// 1:02:03, or 2:03 below an hour
+pub fn clock(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, m, s) => format!("{m}:{s:02}"),
+        (h, m, s) => format!("{h}:{m:02}:{s:02}"),
+    }
+}
@@ -0,0 +1,177 @@
+// `parallel-sh report --gantt JOBLOG`, a timeline of a past run as SVG
+//
+// Every job of a --joblog is drawn as a bar on the first slot that was free
+// when it started, so gaps show where slots were idle and long bars the jobs
+// that held up the run.
+use std::{ffi::OsString, fmt::Write, fs};
+
+const WIDTH: f64 = 1200.0;
+const MARGIN: f64 = 10.0;
+const ROW: f64 = 18.0;
+const HEADER: f64 = 40.0;
+const TICKS: u32 = 10;
+
+#[derive(Debug)]
+struct Entry {
+    seq: String,
+    start: f64,
+    runtime: f64,
+    exit: i32,
+    signal: i32,
+    command: String,
+}
+
+pub fn subcommand() -> Option<i32> {
+    let mut args = std::env::args_os().skip(1);
+    if args.next().as_deref() != Some("report".as_ref()) {
+        return None;
+    }
+    let mut joblog: Option<OsString> = None;
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--gantt") => joblog = args.next(),
+            _ => {
+                eprintln!("ERROR: unexpected argument '{}'", arg.to_string_lossy());
+                return Some(1);
+            }
+        }
+    }
+    let Some(joblog) = joblog else {
+        eprintln!("ERROR: usage: parallel-sh report --gantt <JOBLOG>");
+        return Some(1);
+    };
+    let mut entries = match fs::read_to_string(&joblog) {
+        Ok(content) => parse(&content),
+        Err(e) => {
+            eprintln!(
+                "ERROR: could not read '{}': {}",
+                joblog.to_string_lossy(),
+                e
+            );
+            return Some(1);
+        }
+    };
+    if entries.is_empty() {
+        eprintln!("ERROR: no jobs in '{}'", joblog.to_string_lossy());
+        return Some(1);
+    }
+    print!("{}", gantt(&mut entries));
+    Some(0)
+}
+
+// Lines that are not jobs (the header, truncated lines) are skipped
+fn parse(joblog: &str) -> Vec<Entry> {
+    joblog
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.splitn(9, '\t').collect();
+            let [seq, _host, start, runtime, _send, _receive, exit, signal, command] = fields[..]
+            else {
+                return None;
+            };
+            Some(Entry {
+                seq: seq.to_string(),
+                start: start.parse().ok()?,
+                runtime: runtime.parse().ok()?,
+                exit: exit.parse().ok()?,
+                signal: signal.parse().ok()?,
+                command: command.to_string(),
+            })
+        })
+        .collect()
+}
+
+// The slot of every entry, in the order of their start times
+fn slots(entries: &mut [Entry]) -> Vec<usize> {
+    entries.sort_by(|a, b| a.start.total_cmp(&b.start));
+    let mut free_at: Vec<f64> = vec![];
+    entries
+        .iter()
+        .map(|entry| {
+            let end = entry.start + entry.runtime;
+            match free_at.iter().position(|&free| free <= entry.start) {
+                Some(slot) => {
+                    free_at[slot] = end;
+                    slot
+                }
+                None => {
+                    free_at.push(end);
+                    free_at.len() - 1
+                }
+            }
+        })
+        .collect()
+}
+
+fn gantt(entries: &mut [Entry]) -> String {
+    let slots = slots(entries);
+    let entries = &*entries;
+    let first = entries[0].start;
+    let last = entries
+        .iter()
+        .map(|entry| entry.start + entry.runtime)
+        .fold(first, f64::max);
+    let span = (last - first).max(0.001);
+    let busy: f64 = entries.iter().map(|entry| entry.runtime).sum();
+    let lanes = slots.iter().max().map_or(1, |&slot| slot + 1);
+    #[allow(clippy::cast_precision_loss)]
+    let utilization = busy / (span * lanes as f64) * 100.0;
+    let scale = (WIDTH - 2.0 * MARGIN) / span;
+    #[allow(clippy::cast_precision_loss)]
+    let height = HEADER + lanes as f64 * ROW + MARGIN;
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{WIDTH}" height="{height}" font-family="sans-serif" font-size="11">"#
+    );
+    let _ = writeln!(
+        svg,
+        r#"<text x="{MARGIN}" y="14">{} jobs on {} slots in {:.1}s, slots busy {:.0}% of the time</text>"#,
+        entries.len(),
+        lanes,
+        span,
+        utilization
+    );
+    let precision = if span < 10.0 { 2 } else { 0 };
+    for tick in 0..=TICKS {
+        let x = MARGIN + f64::from(tick) * (WIDTH - 2.0 * MARGIN) / f64::from(TICKS);
+        let seconds = f64::from(tick) * span / f64::from(TICKS);
+        let _ = writeln!(
+            svg,
+            r##"<line x1="{x:.1}" y1="{}" x2="{x:.1}" y2="{height}" stroke="#ddd"/><text x="{x:.1}" y="{}" text-anchor="middle">{seconds:.precision$}s</text>"##,
+            HEADER - 8.0,
+            HEADER - 10.0
+        );
+    }
+    for (entry, slot) in entries.iter().zip(&slots) {
+        let x = MARGIN + (entry.start - first) * scale;
+        #[allow(clippy::cast_precision_loss)]
+        let y = HEADER + *slot as f64 * ROW;
+        let width = (entry.runtime * scale).max(1.0);
+        let color = if entry.exit == 0 && entry.signal == 0 {
+            "#4c9a2a"
+        } else {
+            "#c0392b"
+        };
+        let _ = writeln!(
+            svg,
+            r#"<rect x="{x:.1}" y="{y:.1}" width="{width:.1}" height="{}" fill="{color}"><title>#{} {} ({:.3}s, exit {}, signal {})</title></rect>"#,
+            ROW - 2.0,
+            entry.seq,
+            escape(&entry.command),
+            entry.runtime,
+            entry.exit,
+            entry.signal
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
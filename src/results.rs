@@ -0,0 +1,87 @@
+// Per job result directories (--results), DIR/<seq>/ holds the job's stdout,
+// stderr and a job.json with its command, exit code and timing
+//
+// With --sshlogin they are sorted by host, DIR/<host>/<seq>/, and jobs run on
+// the local machine (`:`) go to DIR/local/<seq>/.
+use crate::{json::Object, signal, JobResult};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+#[derive(Debug)]
+pub struct Results {
+    dir: PathBuf,
+    by_host: bool,
+}
+
+impl Results {
+    pub fn create(dir: &Path, by_host: bool) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Results {
+            dir: dir.to_path_buf(),
+            by_host,
+        })
+    }
+
+    // Named by sequence number, commands can be arbitrarily long and contain
+    // any character
+    pub fn write(&self, result: &JobResult) -> io::Result<()> {
+        let dir = self.job_dir(result.seq, result.host.as_deref());
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("stdout"), &result.output.stdout)?;
+        fs::write(dir.join("stderr"), &result.output.stderr)?;
+        let started = result
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let job = Object::new()
+            .field("seq", result.seq)
+            .field("cmd", &result.job)
+            .field("label", &result.label)
+            .field("host", &result.host)
+            .field("exit", result.output.status.code())
+            .field("signal", signal(result.output.status))
+            .field("started", started)
+            .field("duration", result.duration.as_secs_f64())
+            .field("timed_out", result.timed_out.is_some())
+            .field("attempts", result.attempts)
+            .build();
+        fs::write(dir.join("job.json"), format!("{job}\n"))
+    }
+
+    fn job_dir(&self, seq: usize, host: Option<&str>) -> PathBuf {
+        if !self.by_host {
+            return self.dir.join(seq.to_string());
+        }
+        // A login may be `user@host`, but not contain a path
+        let host = host.unwrap_or("local").replace(['/', '\\'], "_");
+        self.dir.join(host).join(seq.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_dirs() {
+        let results = Results {
+            dir: PathBuf::from("res"),
+            by_host: false,
+        };
+        assert_eq!(results.job_dir(42, Some("build1")), Path::new("res/42"));
+        let results = Results {
+            dir: PathBuf::from("res"),
+            by_host: true,
+        };
+        assert_eq!(
+            results.job_dir(42, Some("user@build1")),
+            Path::new("res/user@build1/42")
+        );
+        assert_eq!(results.job_dir(7, None), Path::new("res/local/7"));
+        assert_eq!(results.job_dir(7, Some("../x")), Path::new("res/.._x/7"));
+    }
+}
@@ -0,0 +1,158 @@
+// Finished jobs in a SQLite database (--results-db FILE), for queries across
+// a run or several of them
+//
+// The rows are handed to the sqlite3 command line shell, which has to be
+// installed. They are committed once a second, and once the run is over.
+use crate::{json::ToJson, signal, JobResult};
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    process::{Child, ChildStdin, Command, Stdio},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS jobs (
+    run REAL NOT NULL,
+    seq INTEGER NOT NULL,
+    command TEXT NOT NULL,
+    args TEXT NOT NULL,
+    label TEXT,
+    host TEXT,
+    start_time REAL NOT NULL,
+    end_time REAL NOT NULL,
+    exit_code INTEGER,
+    signal INTEGER,
+    stdout TEXT NOT NULL,
+    stderr TEXT NOT NULL
+);";
+
+#[derive(Debug)]
+pub struct ResultsDb {
+    sqlite: Child,
+    sql: BufWriter<ChildStdin>,
+    // Tells the runs apart, the time parallel-sh was started
+    run: f64,
+    committed: Instant,
+}
+
+fn seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+// Any text as an SQL literal, as hex it cannot break the statement
+fn text(out: &mut String, value: &str) {
+    out.push_str("CAST(X'");
+    for b in value.bytes() {
+        let _ = write!(out, "{b:02x}");
+    }
+    out.push_str("' AS TEXT)");
+}
+
+fn optional(out: &mut String, value: Option<impl std::fmt::Display>) {
+    match value {
+        Some(value) => {
+            let _ = write!(out, "{value}");
+        }
+        None => out.push_str("NULL"),
+    }
+}
+
+impl ResultsDb {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut sqlite = Command::new("sqlite3");
+        sqlite
+            .args(["-batch", "-bail"])
+            .arg(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+        // Ctrl-C stops the jobs, the rows of those that finished are still
+        // committed
+        #[cfg(not(target_os = "windows"))]
+        std::os::unix::process::CommandExt::process_group(&mut sqlite, 0);
+        let mut sqlite = sqlite
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("sqlite3 is needed: {e}")))?;
+        let (Some(stdin), Some(stdout)) = (sqlite.stdin.take(), sqlite.stdout.take()) else {
+            unreachable!("stdin and stdout are piped");
+        };
+        let mut sql = BufWriter::new(stdin);
+        // Answered only once the table is there
+        writeln!(sql, "{SCHEMA}\nSELECT 'ready';")?;
+        sql.flush()?;
+        let mut ready = String::new();
+        BufReader::new(stdout).read_line(&mut ready)?;
+        if ready.trim_end() != "ready" {
+            let _ = sqlite.wait();
+            return Err(io::Error::other("sqlite3 could not create the table"));
+        }
+        writeln!(sql, "BEGIN;")?;
+        Ok(ResultsDb {
+            sqlite,
+            sql,
+            run: seconds(SystemTime::now()),
+            committed: Instant::now(),
+        })
+    }
+
+    pub fn insert(&mut self, result: &JobResult) -> io::Result<()> {
+        let started = seconds(result.started);
+        let mut row = String::from(
+            "INSERT INTO jobs (run, seq, command, args, label, host, start_time, end_time, \
+             exit_code, signal, stdout, stderr) VALUES (",
+        );
+        let _ = write!(row, "{}, {}, ", self.run, result.seq);
+        text(&mut row, &result.job);
+        row.push_str(", ");
+        // A JSON array, for json_each()
+        let mut args = String::new();
+        result.args.write_json(&mut args);
+        text(&mut row, &args);
+        row.push_str(", ");
+        match &result.label {
+            Some(label) => text(&mut row, label),
+            None => row.push_str("NULL"),
+        }
+        row.push_str(", ");
+        match &result.host {
+            Some(host) => text(&mut row, host),
+            None => row.push_str("NULL"),
+        }
+        let _ = write!(
+            row,
+            ", {}, {}, ",
+            started,
+            started + result.duration.as_secs_f64()
+        );
+        optional(&mut row, result.output.status.code());
+        row.push_str(", ");
+        optional(&mut row, signal(result.output.status));
+        row.push_str(", ");
+        text(&mut row, &String::from_utf8_lossy(&result.output.stdout));
+        row.push_str(", ");
+        text(&mut row, &String::from_utf8_lossy(&result.output.stderr));
+        row.push_str(");");
+        writeln!(self.sql, "{row}")?;
+        if self.committed.elapsed() >= COMMIT_INTERVAL {
+            writeln!(self.sql, "COMMIT;\nBEGIN;")?;
+            self.sql.flush()?;
+            self.committed = Instant::now();
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        writeln!(self.sql, "COMMIT;")?;
+        self.sql.flush()?;
+        drop(self.sql);
+        let status = self.sqlite.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("sqlite3 {status}")));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+// Jobs in namespaces of their own (--unshare net,pid,..., --private-tmp), on
+// Linux only
+//
+// Without root a user namespace is created as well, with parallel-sh's user
+// and group mapped to themselves, so the other namespaces need no privileges.
+use std::process::Command;
+
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct Sandbox {
+    net: bool,
+    pid: bool,
+    mount: bool,
+    ipc: bool,
+    uts: bool,
+    // An empty tmpfs on /tmp, in a mount namespace
+    private_tmp: bool,
+}
+
+impl std::str::FromStr for Sandbox {
+    type Err = String;
+
+    // A comma separated list of namespaces
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut sandbox = Sandbox::default();
+        for namespace in s.split(',') {
+            match namespace.trim() {
+                "net" => sandbox.net = true,
+                "pid" => sandbox.pid = true,
+                "mount" => sandbox.mount = true,
+                "ipc" => sandbox.ipc = true,
+                "uts" => sandbox.uts = true,
+                other => {
+                    return Err(format!(
+                        "invalid namespace '{other}', expected net, pid, mount, ipc or uts"
+                    ))
+                }
+            }
+        }
+        Ok(sandbox)
+    }
+}
+
+impl Sandbox {
+    pub fn with_private_tmp(self) -> Self {
+        Sandbox {
+            private_tmp: true,
+            ..self
+        }
+    }
+
+    // Has to come after jobctl::configure(), the job's process group is
+    // created before a new PID namespace
+    #[cfg(target_os = "linux")]
+    #[allow(clippy::similar_names)]
+    pub fn apply(self, cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        // A new PID namespace needs /proc of its own to be useful
+        let mount = self.mount || self.private_tmp || self.pid;
+        let mut flags = 0;
+        for (enabled, flag) in [
+            (self.net, libc::CLONE_NEWNET),
+            (self.pid, libc::CLONE_NEWPID),
+            (mount, libc::CLONE_NEWNS),
+            (self.ipc, libc::CLONE_NEWIPC),
+            (self.uts, libc::CLONE_NEWUTS),
+        ] {
+            if enabled {
+                flags |= flag;
+            }
+        }
+        // SAFETY: plain syscalls without memory access
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        if uid != 0 {
+            flags |= libc::CLONE_NEWUSER;
+        }
+        // Prepared before fork, nothing may be allocated after it
+        let uid_map = format!("{uid} {uid} 1");
+        let gid_map = format!("{gid} {gid} 1");
+        // SAFETY: only async-signal-safe functions are called after fork, with
+        // pointers to memory owned by the closure
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::unshare(flags) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if flags & libc::CLONE_NEWUSER != 0 {
+                    write_file(c"/proc/self/setgroups", b"deny")?;
+                    write_file(c"/proc/self/uid_map", uid_map.as_bytes())?;
+                    write_file(c"/proc/self/gid_map", gid_map.as_bytes())?;
+                }
+                if self.pid {
+                    enter_pid_namespace()?;
+                }
+                if mount {
+                    // Keep the mounts of the job from propagating to the host
+                    check(libc::mount(
+                        c"none".as_ptr(),
+                        c"/".as_ptr(),
+                        std::ptr::null(),
+                        libc::MS_REC | libc::MS_PRIVATE,
+                        std::ptr::null(),
+                    ))?;
+                }
+                if self.pid {
+                    check(libc::mount(
+                        c"proc".as_ptr(),
+                        c"/proc".as_ptr(),
+                        c"proc".as_ptr(),
+                        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                        std::ptr::null(),
+                    ))?;
+                }
+                if self.private_tmp {
+                    check(libc::mount(
+                        c"tmpfs".as_ptr(),
+                        c"/tmp".as_ptr(),
+                        c"tmpfs".as_ptr(),
+                        libc::MS_NOSUID | libc::MS_NODEV,
+                        c"mode=1777".as_ptr().cast(),
+                    ))?;
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(self, _cmd: &mut Command) {}
+}
+
+#[cfg(target_os = "linux")]
+fn check(result: libc::c_int) -> std::io::Result<()> {
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn write_file(path: &std::ffi::CStr, content: &[u8]) -> std::io::Result<()> {
+    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+    check(fd)?;
+    let written = libc::write(fd, content.as_ptr().cast(), content.len());
+    libc::close(fd);
+    if written < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Only children end up in a new PID namespace: the job is run by a child,
+// which is the namespace's init, while this process waits for it and exits
+// the same way. Both are in the job's process group.
+#[cfg(target_os = "linux")]
+unsafe fn enter_pid_namespace() -> std::io::Result<()> {
+    let pid = libc::fork();
+    check(pid)?;
+    if pid == 0 {
+        // Like the job itself (jobctl::configure)
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+        return Ok(());
+    }
+    // Otherwise spawning would wait for this process, instead of the exec
+    // of the job
+    libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0);
+    let mut status = 0;
+    while libc::waitpid(pid, &mut status, 0) < 0 {
+        if *libc::__errno_location() != libc::EINTR {
+            libc::_exit(1);
+        }
+    }
+    if libc::WIFSIGNALED(status) {
+        let signal = libc::WTERMSIG(status);
+        libc::signal(signal, libc::SIG_DFL);
+        libc::kill(libc::getpid(), signal);
+        libc::_exit(128 + signal);
+    }
+    libc::_exit(libc::WEXITSTATUS(status))
+}
@@ -0,0 +1,97 @@
+// User supplied Rhai script deciding which jobs run, in which order and
+// whether they succeeded. Every function is optional:
+//
+//   fn accept(job) { ... }          // false skips the job
+//   fn priority(job) { ... }        // jobs with higher values are started first
+//   fn classify(job, exit) { ... }  // true if the job succeeded
+use log::warn;
+use rhai::{Dynamic, Engine, Scope, AST, INT};
+use std::{path::Path, sync::Mutex};
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Mutex<Scope<'static>>,
+}
+
+impl std::fmt::Debug for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Script").finish_non_exhaustive()
+    }
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let engine = Engine::new();
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| e.to_string())?;
+        let mut scope = Scope::new();
+        engine
+            .run_ast_with_scope(&mut scope, &ast)
+            .map_err(|e| e.to_string())?;
+        Ok(Script {
+            engine,
+            ast,
+            scope: Mutex::new(scope),
+        })
+    }
+
+    fn has_fn(&self, name: &str, params: usize) -> bool {
+        self.ast
+            .iter_functions()
+            .any(|f| f.name == name && f.params.len() == params)
+    }
+
+    fn call<T: Clone + Send + Sync + 'static>(
+        &self,
+        name: &str,
+        args: impl rhai::FuncArgs,
+    ) -> Option<T> {
+        let mut scope = self.scope.lock().unwrap();
+        match self
+            .engine
+            .call_fn::<Dynamic>(&mut scope, &self.ast, name, args)
+        {
+            Ok(value) => {
+                let type_name = value.type_name();
+                let value = value.try_cast::<T>();
+                if value.is_none() {
+                    warn!(
+                        "Script function '{}' returned {}, expected {}",
+                        name,
+                        type_name,
+                        std::any::type_name::<T>()
+                    );
+                }
+                value
+            }
+            Err(e) => {
+                warn!("Script function '{}' failed: {}", name, e);
+                None
+            }
+        }
+    }
+
+    pub fn has_priority(&self) -> bool {
+        self.has_fn("priority", 1)
+    }
+
+    pub fn accept(&self, job: &str) -> bool {
+        !self.has_fn("accept", 1) || self.call::<bool>("accept", (job.to_string(),)) != Some(false)
+    }
+
+    pub fn priority(&self, job: &str) -> INT {
+        self.call::<INT>("priority", (job.to_string(),))
+            .unwrap_or_default()
+    }
+
+    // None if the script has no opinion
+    pub fn classify(&self, job: &str, exit: Option<i32>) -> Option<bool> {
+        if !self.has_fn("classify", 2) {
+            return None;
+        }
+        let exit = exit.map_or(Dynamic::UNIT, |code| Dynamic::from(INT::from(code)));
+        self.call::<bool>("classify", (job.to_string(), exit))
+    }
+}
@@ -0,0 +1,73 @@
+// --sem[=NAME], a counting semaphore shared by all invocations with the same
+// NAME, like GNU parallel's sem: `parallel-sh --sem -j4 CMD` waits until less
+// than 4 others hold it, runs CMD and gives it back
+//
+// Every slot is a lock file, taken with a file lock that is released by the
+// system once parallel-sh exits, however it exits.
+use log::info;
+use std::{
+    env,
+    fs::{self, File, OpenOptions, TryLockError},
+    io,
+    path::PathBuf,
+    thread,
+    time::Duration,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// One directory per user, semaphores are not shared with others
+fn dir() -> PathBuf {
+    let user = env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_default();
+    #[cfg(not(target_os = "windows"))]
+    let base = env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map_or_else(env::temp_dir, PathBuf::from);
+    #[cfg(target_os = "windows")]
+    let base = env::temp_dir();
+    base.join(format!("parallel-sh-sem-{user}"))
+}
+
+pub fn validate(name: &str) -> Result<String, String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err(format!(
+            "invalid semaphore name '{name}', expected letters, digits, _, - and ."
+        ));
+    }
+    Ok(name.to_string())
+}
+
+// Blocks until one of `slots` is free, it is held as long as the file is open
+pub fn acquire(name: &str, slots: usize) -> io::Result<File> {
+    let dir = dir();
+    fs::create_dir_all(&dir)?;
+    let mut waiting = false;
+    loop {
+        for slot in 1..=slots {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(dir.join(format!("{name}.{slot}")))?;
+            match file.try_lock() {
+                Ok(()) => return Ok(file),
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => return Err(e),
+            }
+        }
+        if crate::jobctl::stopping() {
+            return Err(io::ErrorKind::Interrupted.into());
+        }
+        if !waiting {
+            info!("Waiting for one of {} slots of semaphore '{}'", slots, name);
+            waiting = true;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,215 @@
+// External job producer started with --source-cmd
+//
+// The producer writes one JSON object per line to its stdout, e.g.
+//   {"id": 17, "cmd": "convert a.png b.jpg", "label": "customer-a", "after": "setup"}
+// optionally with limits overriding the global ones for this job:
+//   {"cmd": "make", "timeout": "10m", "memory": "2G", "cpu": 50, "nice": 10, "retries": 2,
+//    "shell": "bash", "umask": "077"}
+// and a priority, jobs with higher ones are started first:
+//   {"cmd": "make docs", "priority": -1}
+// and a group, of which only `concurrency` jobs (1 by default) run at a time,
+// like jobs sharing a lock (@lock=KEY):
+//   {"cmd": "./migrate.sh users", "group": "db", "concurrency": 2}
+// and gets one JSON object per finished job on its stdin:
+//   {"event": "done", "id": 17, "seq": 3, "exit": 0, "signal": null, "duration": 1.2}
+// and a summary at the end:
+//   {"event": "summary", "exit_codes": {"0": 9854, "1": 120}, "signals": {"9": 3}}
+use crate::{
+    json::{self, Object, Value},
+    output::ExitCodes,
+    parse_cpu_quota, parse_duration, parse_nice, parse_size, parse_umask, JobSpec, Limits,
+};
+use log::warn;
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::Mutex,
+};
+
+#[derive(Debug)]
+pub struct Source {
+    child: Mutex<Child>,
+    stdin: Mutex<Option<ChildStdin>>,
+    stdout: Mutex<Option<ChildStdout>>,
+    // Producer ids of dispatched jobs, by command, oldest first
+    ids: Mutex<HashMap<String, VecDeque<Value>>>,
+}
+
+impl Source {
+    pub fn spawn(mut cmd: Command) -> std::io::Result<Self> {
+        let mut child = cmd.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+        Ok(Source {
+            stdin: Mutex::new(child.stdin.take()),
+            stdout: Mutex::new(child.stdout.take()),
+            child: Mutex::new(child),
+            ids: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // The jobs written by the producer; ends when it closes its stdout
+    pub fn jobs(&self) -> impl Iterator<Item = JobSpec> + '_ {
+        let stdout = self.stdout.lock().unwrap().take();
+        stdout
+            .into_iter()
+            .flat_map(|stdout| BufReader::new(stdout).lines().map_while(Result::ok))
+            .filter_map(|line| self.parse(&line))
+    }
+
+    fn parse(&self, line: &str) -> Option<JobSpec> {
+        if line.trim().is_empty() {
+            return None;
+        }
+        let job = match json::parse(line) {
+            Ok(job) => job,
+            Err(e) => {
+                warn!("Ignoring invalid job from source command: {}", e);
+                return None;
+            }
+        };
+        let Some(cmd) = job.get("cmd").and_then(Value::as_str) else {
+            warn!("Ignoring job without \"cmd\" from source command: {}", line);
+            return None;
+        };
+        let limits = match limits(&job) {
+            Ok(limits) => limits,
+            Err(key) => {
+                warn!(
+                    "Ignoring job with invalid \"{}\" from source command: {}",
+                    key, line
+                );
+                return None;
+            }
+        };
+        // At least one job of the group at a time
+        let Ok(lock_limit) = field(
+            &job,
+            "concurrency",
+            |s| s.parse().ok().filter(|&n| n > 0).ok_or(()),
+            |n| whole(n).filter(|&n: &usize| n > 0),
+        ) else {
+            warn!(
+                "Ignoring job with invalid \"concurrency\" from source command: {}",
+                line
+            );
+            return None;
+        };
+        let id = job.get("id").cloned().unwrap_or(Value::Null);
+        self.ids
+            .lock()
+            .unwrap()
+            .entry(cmd.to_string())
+            .or_default()
+            .push_back(id);
+        Some(JobSpec {
+            cmd: cmd.to_string(),
+            label: job.get("label").and_then(Value::as_str).map(str::to_string),
+            after: job.get("after").and_then(Value::as_str).map(str::to_string),
+            limits,
+            args: vec![],
+            #[allow(clippy::cast_possible_truncation)]
+            priority: match job.get("priority") {
+                Some(Value::Number(priority)) => *priority as i64,
+                _ => 0,
+            },
+            deps: vec![],
+            lock: job.get("group").and_then(Value::as_str).map(str::to_string),
+            lock_limit,
+            template: None,
+        })
+    }
+
+    pub fn done(
+        &self,
+        cmd: &str,
+        seq: usize,
+        exit: Option<i32>,
+        signal: Option<i32>,
+        duration: f64,
+    ) {
+        let id = self
+            .ids
+            .lock()
+            .unwrap()
+            .get_mut(cmd)
+            .and_then(VecDeque::pop_front)
+            .unwrap_or(Value::Null);
+        let event = Object::new()
+            .field("event", "done")
+            .field("id", id)
+            .field("seq", seq)
+            .field("exit", exit)
+            .field("signal", signal)
+            .field("duration", duration)
+            .build();
+        if let Some(stdin) = self.stdin.lock().unwrap().as_mut() {
+            if let Err(e) = writeln!(stdin, "{event}") {
+                warn!("Could not report job to source command: {}", e);
+            }
+        }
+    }
+
+    // Report the exit codes of the whole run, then close the producer's stdin
+    // and wait for it to exit
+    pub fn finish(&self, exit_codes: &ExitCodes) {
+        let event = exit_codes
+            .add_to(Object::new().field("event", "summary"))
+            .build();
+        if let Some(mut stdin) = self.stdin.lock().unwrap().take() {
+            if let Err(e) = writeln!(stdin, "{event}") {
+                warn!("Could not report summary to source command: {}", e);
+            }
+        }
+        match self.child.lock().unwrap().wait() {
+            Ok(status) if !status.success() => warn!("Source command {}", status),
+            Ok(_) => {}
+            Err(e) => warn!("Could not wait for source command: {}", e),
+        }
+    }
+}
+
+// Numbers are seconds, bytes and percent, strings take the units of
+// --timeout, --max-output and --cpu-quota
+// Fails with the key of the first invalid value
+fn limits(job: &Value) -> Result<Limits, &'static str> {
+    Ok(Limits {
+        timeout: field(job, "timeout", parse_duration, |n| {
+            std::time::Duration::try_from_secs_f64(n).ok()
+        })?,
+        retries: field(job, "retries", |s| s.parse().map_err(|_| ()), whole)?,
+        shell: field(job, "shell", |s| Ok::<_, ()>(s.into()), |_| None)?,
+        cpu: field(job, "cpu", parse_cpu_quota, |n| {
+            Some(n).filter(|&n| n >= 1.0)
+        })?,
+        memory: field(job, "memory", parse_size, whole)?,
+        nice: field(job, "nice", parse_nice, |n| {
+            whole(n).filter(|n| (-20..=19).contains(n))
+        })?,
+        // Octal, only as a string
+        umask: field(job, "umask", parse_umask, |_| None)?,
+    })
+}
+
+fn field<T, E>(
+    job: &Value,
+    key: &'static str,
+    parse: impl Fn(&str) -> Result<T, E>,
+    number: impl Fn(f64) -> Option<T>,
+) -> Result<Option<T>, &'static str> {
+    let value = match job.get(key) {
+        None | Some(Value::Null) => return Ok(None),
+        Some(Value::String(s)) => parse(s).ok(),
+        Some(Value::Number(n)) => number(*n),
+        Some(_) => None,
+    };
+    value.map(Some).ok_or(key)
+}
+
+// An integer that fits into T
+fn whole<T: TryFrom<i64>>(n: f64) -> Option<T> {
+    #[allow(clippy::cast_possible_truncation)]
+    let i = n as i64;
+    #[allow(clippy::cast_precision_loss)]
+    let exact = i as f64 == n;
+    exact.then(|| T::try_from(i).ok()).flatten()
+}
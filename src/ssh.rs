@@ -0,0 +1,346 @@
+// Remote hosts (--sshlogin, --sshloginfile), every job runs on a free slot of
+// one of them via ssh(1)
+//
+// A login is `[N/]host`, where host is anything ssh accepts (`user@host`,
+// an alias from ~/.ssh/config) and N the number of jobs run on it at a time.
+// `:` stands for the local machine.
+//
+// With --host-check every remote host has to pass a check before jobs are
+// sent to it: ssh has to connect within the timeout, and the command of the
+// check (e.g. for free disk space or the load) has to succeed. Hosts failing
+// it get no new jobs until they pass it again, it is repeated every interval.
+use crate::{jobctl, template::quote_posix};
+use log::{info, warn};
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const LOCAL: &str = ":";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Login {
+    host: String,
+    slots: Option<usize>,
+}
+
+impl std::str::FromStr for Login {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (slots, host) = match s.split_once('/') {
+            Some((slots, host)) => match slots.parse::<usize>() {
+                Ok(slots) if slots > 0 => (Some(slots), host),
+                _ => return Err(format!("invalid number of slots in ssh login '{s}'")),
+            },
+            None => (None, s),
+        };
+        if host.is_empty() || host.chars().any(char::is_whitespace) {
+            return Err(format!("invalid ssh login '{s}'"));
+        }
+        Ok(Login {
+            host: host.to_string(),
+            slots,
+        })
+    }
+}
+
+// Comma separated logins, like GNU parallel's -S host1,host2
+pub fn parse_logins(s: &str) -> Result<Vec<Login>, String> {
+    s.split(',').map(str::parse).collect()
+}
+
+// One login per line, empty lines and lines starting with # are skipped
+pub fn read_logins(path: &Path) -> Result<Vec<Login>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read '{}': {}", path.display(), e))?;
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::parse)
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct HostCheck {
+    pub command: String,
+    // Also the ssh ConnectTimeout
+    pub timeout: Duration,
+    pub interval: Duration,
+}
+
+impl HostCheck {
+    // Why `host` failed the check, if it did
+    fn run(&self, host: &str) -> Result<(), String> {
+        let mut child = Command::new("ssh")
+            .args(["-o", "BatchMode=yes", "-o"])
+            .arg(format!("ConnectTimeout={}", self.timeout.as_secs().max(1)))
+            .args(["--", host, &self.command])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| e.to_string())?;
+        let started = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+                break status;
+            }
+            if started.elapsed() > self.timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("no answer within {:.1?}", self.timeout));
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        if status.success() {
+            return Ok(());
+        }
+        let mut stderr = String::new();
+        if let Some(mut pipe) = child.stderr.take() {
+            let _ = pipe.read_to_string(&mut stderr);
+        }
+        Err(match stderr.trim() {
+            "" => status.to_string(),
+            message => message.to_string(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Hosts {
+    hosts: Vec<String>,
+    // Free slots per host
+    free: Mutex<Vec<usize>>,
+    released: Condvar,
+    slots: usize,
+    // Hosts that failed the --host-check, they get no new jobs
+    down: Vec<AtomicBool>,
+}
+
+impl Hosts {
+    // Logins without a slot count get `default_slots` (-j)
+    pub fn new(logins: Vec<Login>, default_slots: usize) -> Self {
+        let mut hosts: Vec<String> = vec![];
+        let mut free: Vec<usize> = vec![];
+        for login in logins {
+            let slots = login.slots.unwrap_or(default_slots).max(1);
+            // The same host given twice gets the slots of both
+            match hosts.iter().position(|host| *host == login.host) {
+                Some(i) => free[i] = free[i].saturating_add(slots),
+                None => {
+                    hosts.push(login.host);
+                    free.push(slots);
+                }
+            }
+        }
+        Hosts {
+            slots: free
+                .iter()
+                .fold(0, |sum: usize, &slots| sum.saturating_add(slots)),
+            down: hosts.iter().map(|_| AtomicBool::new(false)).collect(),
+            hosts,
+            free: Mutex::new(free),
+            released: Condvar::new(),
+        }
+    }
+
+    // All slots together, the number of jobs run at a time
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    // Run the check on all remote hosts at once, and take those failing it
+    // out of the rotation (or put them back). The number of hosts up.
+    pub fn check(&self, check: &HostCheck) -> usize {
+        thread::scope(|scope| {
+            for (host, down) in self.hosts.iter().zip(&self.down) {
+                if host == LOCAL {
+                    continue;
+                }
+                scope.spawn(move || match check.run(host) {
+                    Ok(()) => {
+                        if down.swap(false, Ordering::SeqCst) {
+                            info!("{} passed the host check again, sending jobs to it", host);
+                        }
+                    }
+                    Err(e) => {
+                        if !down.swap(true, Ordering::SeqCst) {
+                            warn!(
+                                "{} failed the host check, no more jobs are sent to it: {}",
+                                host, e
+                            );
+                        }
+                    }
+                });
+            }
+        });
+        // Waiting workers may take a host that is back
+        let _free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+        self.released.notify_all();
+        self.down
+            .iter()
+            .filter(|down| !down.load(Ordering::SeqCst))
+            .count()
+    }
+
+    // Repeat the check every interval while jobs are run
+    pub fn watch(self: &Arc<Self>, check: HostCheck) {
+        let hosts = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(check.interval);
+            if jobctl::stopping() {
+                break;
+            }
+            hosts.check(&check);
+        });
+    }
+
+    // The host with the most free slots, waits until there is one
+    pub fn acquire(&self) -> Slot<'_> {
+        let mut free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let best = (0..free.len())
+                .filter(|&i| !self.down[i].load(Ordering::SeqCst))
+                .max_by_key(|&i| free[i]);
+            if let Some(i) = best.filter(|&i| free[i] > 0) {
+                free[i] -= 1;
+                return Slot {
+                    hosts: self,
+                    index: i,
+                };
+            }
+            free = self
+                .released
+                .wait(free)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+// Given back once dropped
+#[derive(Debug)]
+pub struct Slot<'a> {
+    hosts: &'a Hosts,
+    index: usize,
+}
+
+impl Slot<'_> {
+    pub fn host(&self) -> &str {
+        &self.hosts.hosts[self.index]
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.host() == LOCAL
+    }
+
+    // The remote login shell runs `command`, so no local shell is involved
+    pub fn command(&self, command: &str) -> Command {
+        let mut ssh = Command::new("ssh");
+        ssh.args(["-o", "BatchMode=yes", "--", self.host(), command]);
+        ssh
+    }
+
+    // Copy a local file to the same path on the host (--transfer), relative
+    // paths are relative to the remote home directory
+    pub fn transfer(&self, file: &str) -> io::Result<()> {
+        let target = quote_posix(file);
+        let script = match Path::new(file).parent().and_then(Path::to_str) {
+            Some(dir) if !dir.is_empty() => {
+                format!("mkdir -p -- {} && cat > {target}", quote_posix(dir))
+            }
+            _ => format!("cat > {target}"),
+        };
+        self.stage(&script, File::open(file)?.into(), Stdio::null())
+    }
+
+    // Copy a file from the host to the same local path (--return)
+    pub fn fetch(&self, file: &str) -> io::Result<()> {
+        if let Some(dir) = Path::new(file).parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        let script = format!("cat -- {}", quote_posix(file));
+        let result = self.stage(&script, Stdio::null(), File::create(file)?.into());
+        if result.is_err() {
+            let _ = fs::remove_file(file);
+        }
+        result
+    }
+
+    // Delete files on the host (--cleanup)
+    pub fn remove(&self, files: &[String]) -> io::Result<()> {
+        let files: Vec<String> = files.iter().map(|file| quote_posix(file)).collect();
+        self.stage(
+            &format!("rm -f -- {}", files.join(" ")),
+            Stdio::null(),
+            Stdio::null(),
+        )
+    }
+
+    fn stage(&self, script: &str, stdin: Stdio, stdout: Stdio) -> io::Result<()> {
+        let output = self
+            .command(script)
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(Stdio::piped())
+            .output()?;
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(io::Error::other(match stderr.trim() {
+            "" => output.status.to_string(),
+            message => message.to_string(),
+        }))
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut free = self
+            .hosts
+            .free
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        free[self.index] += 1;
+        self.hosts.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logins() {
+        let logins = parse_logins("4/user@build1,build2,:").unwrap();
+        let hosts = Hosts::new(logins, 2);
+        assert_eq!(hosts.hosts, ["user@build1", "build2", LOCAL]);
+        assert_eq!(hosts.slots(), 8);
+        for invalid in ["0/host", "x/host", "", "a b"] {
+            assert!(parse_logins(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn hosts_down_get_no_jobs() {
+        let hosts = Hosts::new(parse_logins("4/a,1/b").unwrap(), 1);
+        hosts.down[0].store(true, Ordering::SeqCst);
+        let slot = hosts.acquire();
+        assert_eq!(slot.host(), "b");
+        drop(slot);
+        hosts.down[0].store(false, Ordering::SeqCst);
+        assert_eq!(hosts.acquire().host(), "a");
+    }
+}
@@ -0,0 +1,228 @@
+// The state of a run: served by a small HTTP server for monitoring
+// (--status-port), `/status` shows the jobs as JSON, `/metrics` has their
+// numbers in the Prometheus text format, and printed on SIGUSR1
+use crate::{json::Object, progress::clock};
+use log::{debug, info};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// `PORT` on the loopback interface, or `ADDRESS:PORT`
+pub fn parse_address(s: &str) -> Result<SocketAddr, String> {
+    if let Ok(port) = s.parse::<u16>() {
+        return Ok(SocketAddr::from(([127, 0, 0, 1], port)));
+    }
+    s.parse()
+        .map_err(|_| format!("invalid address '{s}', expected PORT or ADDRESS:PORT"))
+}
+
+#[derive(Debug)]
+pub struct Status {
+    started: Instant,
+    // The command running in every busy slot, and since when
+    slots: Mutex<BTreeMap<usize, (String, Instant)>>,
+    finished: AtomicUsize,
+    failed: AtomicUsize,
+    // Set once every job has been queued
+    total: AtomicUsize,
+    total_known: AtomicBool,
+}
+
+impl Status {
+    pub fn new() -> Self {
+        Status {
+            started: Instant::now(),
+            slots: Mutex::default(),
+            finished: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            total_known: AtomicBool::new(false),
+        }
+    }
+
+    // Follow-up jobs (--allow-enqueue) may have been added already
+    pub fn set_total(&self, total: usize) {
+        self.total.fetch_add(total, Ordering::SeqCst);
+        self.total_known.store(true, Ordering::SeqCst);
+    }
+
+    pub fn add_to_total(&self, jobs: usize) {
+        self.total.fetch_add(jobs, Ordering::SeqCst);
+    }
+
+    pub fn job_started(&self, slot: usize, command: &str) {
+        self.slots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(slot, (command.to_string(), Instant::now()));
+    }
+
+    pub fn job_stopped(&self, slot: usize) {
+        self.slots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&slot);
+    }
+
+    pub fn job_finished(&self, success: bool) {
+        self.finished.fetch_add(1, Ordering::SeqCst);
+        if !success {
+            self.failed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // Not known while jobs are still read
+    fn queued(&self, running: usize) -> Option<usize> {
+        self.total_known.load(Ordering::SeqCst).then(|| {
+            self.total
+                .load(Ordering::SeqCst)
+                .saturating_sub(self.finished.load(Ordering::SeqCst) + running)
+        })
+    }
+
+    fn json(&self) -> String {
+        let slots = self.slots.lock().unwrap_or_else(PoisonError::into_inner);
+        let running = slots.len();
+        let slots: Vec<Object> = slots
+            .iter()
+            .map(|(slot, (command, since))| {
+                Object::new()
+                    .field("slot", *slot)
+                    .field("command", command)
+                    .field("seconds", since.elapsed().as_secs_f64())
+            })
+            .collect();
+        Object::new()
+            .field("running", running)
+            .field("queued", self.queued(running))
+            .field("completed", self.finished.load(Ordering::SeqCst))
+            .field("failed", self.failed.load(Ordering::SeqCst))
+            .field("slots", slots)
+            .build()
+    }
+
+    // For the terminal, the jobs that run longest first
+    pub fn report(&self) -> String {
+        let slots = self.slots.lock().unwrap_or_else(PoisonError::into_inner);
+        let running = slots.len();
+        let finished = self.finished.load(Ordering::SeqCst);
+        let failed = self.failed.load(Ordering::SeqCst);
+        let queued = self.queued(running);
+        let mut report = format!("{finished} job(s) done");
+        if failed > 0 {
+            let _ = write!(report, " ({failed} failed)");
+        }
+        let _ = write!(report, ", {running} running");
+        match queued {
+            Some(queued) => {
+                let _ = write!(report, ", {queued} queued");
+                if finished > 0 {
+                    #[allow(clippy::cast_precision_loss)]
+                    let pace = (queued + running) as f64 / finished as f64;
+                    let _ = write!(
+                        report,
+                        ", ETA {}",
+                        clock(self.started.elapsed().mul_f64(pace))
+                    );
+                }
+            }
+            None => report.push_str(", more are being read"),
+        }
+        report.push('\n');
+        let mut slots: Vec<_> = slots.iter().collect();
+        slots.sort_by_key(|(_, (_, since))| *since);
+        for (slot, (command, since)) in slots {
+            let _ = writeln!(report, "  [{slot}] {:>7} {command}", clock(since.elapsed()));
+        }
+        report
+    }
+
+    fn metrics(&self) -> String {
+        let running = self
+            .slots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len();
+        let mut metrics = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: usize| {
+            let _ = write!(
+                metrics,
+                "# HELP parallel_sh_{name} {help}\n# TYPE parallel_sh_{name} {kind}\nparallel_sh_{name} {value}\n"
+            );
+        };
+        metric("jobs_running", "gauge", "Jobs running now", running);
+        if let Some(queued) = self.queued(running) {
+            metric("jobs_queued", "gauge", "Jobs waiting to be started", queued);
+        }
+        metric(
+            "jobs_completed_total",
+            "counter",
+            "Jobs finished, including the failed ones",
+            self.finished.load(Ordering::SeqCst),
+        );
+        metric(
+            "jobs_failed_total",
+            "counter",
+            "Jobs that failed",
+            self.failed.load(Ordering::SeqCst),
+        );
+        metrics
+    }
+}
+
+// Requests are answered one after the other, they are cheap
+pub fn serve(address: SocketAddr, status: Arc<Status>) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("Serving status on http://{}/status", listener.local_addr()?);
+    thread::spawn(move || {
+        for stream in listener.incoming().map_while(Result::ok) {
+            if let Err(e) = respond(stream, &status) {
+                debug!("Could not answer status request: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, status: &Status) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers are not needed
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+    let mut words = request.split_whitespace();
+    let (method, path) = (words.next(), words.next().unwrap_or_default());
+    let path = path.split('?').next().unwrap_or_default();
+    let (code, content_type, body) = match (method, path) {
+        (Some("GET"), "/status") => ("200 OK", "application/json", status.json() + "\n"),
+        (Some("GET"), "/metrics") => ("200 OK", "text/plain; version=0.0.4", status.metrics()),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", String::from("Not found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            String::from("Method not allowed\n"),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {code}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
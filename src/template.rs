@@ -0,0 +1,750 @@
+// Command templates (`gzip {}`, `convert {1} {2}`), filled in with arguments
+// from input lines or from `:::` input sources
+use crate::sha1;
+use std::{ffi::OsStr, path::Path};
+
+// Separates the command from an input source on the command line
+pub const SOURCE_SEP: &str = ":::";
+// Followed by files, every one of them an input source with an argument per
+// line (like --arg-file)
+pub const FILE_SOURCE_SEP: &str = "::::";
+
+// With a `+` the source is linked to the one before it
+pub fn is_source_sep(arg: &str) -> bool {
+    let arg = arg.strip_suffix('+').unwrap_or(arg);
+    arg == SOURCE_SEP || arg == FILE_SOURCE_SEP
+}
+
+// Arguments from `:::`, `linked` ones (`:::+`, --link) are paired with the
+// arguments of the source before it instead of combined with each of them
+#[derive(Debug)]
+pub struct InputSource {
+    pub args: Vec<String>,
+    pub linked: bool,
+    // The arguments are the files to read them from (`::::`)
+    pub files: bool,
+}
+
+// How arguments are quoted for the shell running the jobs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quoting {
+    // Arguments inserted as they are
+    None,
+    Posix,
+    PowerShell,
+    Cmd,
+}
+
+impl Quoting {
+    // Without a shell (--no-shell) commands are split into words like a POSIX
+    // shell does
+    fn for_shell(shell: Option<&OsStr>) -> Self {
+        let Some(shell) = shell else {
+            return Quoting::Posix;
+        };
+        let name = Path::new(shell)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        match name.as_str() {
+            "pwsh" | "powershell" => Quoting::PowerShell,
+            "cmd" if cfg!(target_os = "windows") => Quoting::Cmd,
+            _ => Quoting::Posix,
+        }
+    }
+
+    fn quote(self, arg: &str) -> String {
+        let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:=@%+,".contains(c);
+        match self {
+            Quoting::None => arg.to_string(),
+            Quoting::Posix if !arg.is_empty() && arg.chars().all(plain) => arg.to_string(),
+            Quoting::Posix => format!("'{}'", arg.replace('\'', r"'\''")),
+            Quoting::PowerShell => format!("'{}'", arg.replace('\'', "''")),
+            // cmd.exe has no way to escape %VAR% inside quotes, at least spaces
+            // and operators are safe
+            Quoting::Cmd => format!("\"{}\"", arg.replace('"', "\"\"")),
+        }
+    }
+}
+
+// What is inserted of an argument that is a path, as in GNU parallel: `{.}`
+// without its extension, `{/}` its file name, `{//}` its directory and `{/.}`
+// its file name without extension. `{1.}`, `{2/}` etc. for the Nth argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transform {
+    Whole,
+    NoExtension,
+    Basename,
+    Dirname,
+    BasenameNoExtension,
+}
+
+fn is_separator(c: char) -> bool {
+    c == '/' || (cfg!(target_os = "windows") && c == '\\')
+}
+
+// After the last separator, `dir/` has an empty file name
+fn basename(path: &str) -> &str {
+    path.rsplit(is_separator).next().unwrap_or(path)
+}
+
+// Like dirname(1): `.` without a directory, `/` for files in the root
+fn dirname(path: &str) -> &str {
+    let path = match path.trim_end_matches(is_separator) {
+        "" if !path.is_empty() => return &path[..1],
+        path => path,
+    };
+    match path.rfind(is_separator) {
+        None => ".",
+        Some(i) => match path[..i].trim_end_matches(is_separator) {
+            "" => &path[..1],
+            dir => dir,
+        },
+    }
+}
+
+// The last extension of the file name, not the dot of a hidden file
+// (`.bashrc`) or one in a directory name (`v1.2/README`)
+fn remove_extension(path: &str) -> &str {
+    let name = basename(path);
+    match name.rfind('.') {
+        Some(i) if i > 0 => &path[..path.len() - name.len() + i],
+        _ => path,
+    }
+}
+
+impl Transform {
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "" => Transform::Whole,
+            "." => Transform::NoExtension,
+            "/" => Transform::Basename,
+            "//" => Transform::Dirname,
+            "/." => Transform::BasenameNoExtension,
+            _ => return None,
+        })
+    }
+
+    fn apply(self, arg: &str) -> &str {
+        match self {
+            Transform::Whole => arg,
+            Transform::NoExtension => remove_extension(arg),
+            Transform::Basename => basename(arg),
+            Transform::Dirname => dirname(arg),
+            Transform::BasenameNoExtension => remove_extension(basename(arg)),
+        }
+    }
+}
+
+// Applied in turn to what a placeholder is replaced with, after a `|`, e.g.
+// `{1|upper}`, `{|sha1}`, `{#|pad:5}` or `{1|dirname|lower}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Function {
+    Upper,
+    Lower,
+    Basename,
+    Dirname,
+    NoExtension,
+    Sha1,
+    // Zeros in front up to the width
+    Pad(usize),
+}
+
+impl Function {
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s.split_once(':') {
+            Some(("pad", width)) => Function::Pad(width.parse().ok()?),
+            Some(_) => return None,
+            None => match s {
+                "upper" => Function::Upper,
+                "lower" => Function::Lower,
+                "basename" => Function::Basename,
+                "dirname" => Function::Dirname,
+                "noext" => Function::NoExtension,
+                "sha1" => Function::Sha1,
+                _ => return None,
+            },
+        })
+    }
+
+    fn apply(self, value: &str) -> String {
+        match self {
+            Function::Upper => value.to_uppercase(),
+            Function::Lower => value.to_lowercase(),
+            Function::Basename => basename(value).to_string(),
+            Function::Dirname => dirname(value).to_string(),
+            Function::NoExtension => remove_extension(value).to_string(),
+            Function::Sha1 => sha1::hex(value.as_bytes()),
+            Function::Pad(width) => format!("{value:0>width$}"),
+        }
+    }
+}
+
+fn apply(functions: &[Function], value: &str) -> String {
+    functions
+        .iter()
+        .fold(value.to_string(), |value, function| function.apply(&value))
+}
+
+// A replacement string, `{}` stands for all arguments and `{N}` for the Nth.
+// `{#}` (the job's sequence number), `{%}` (its slot) and `{attempt}` (from 1,
+// counting retries) are only known once the job is started, they are replaced
+// then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    All(Transform),
+    Position(usize, Transform),
+    Seq,
+    Slot,
+    Attempt,
+}
+
+impl Placeholder {
+    // Parse the placeholder at the start of `s`, along with the functions
+    // applied to it and its length
+    fn parse(s: &str) -> Option<(Placeholder, Vec<Function>, usize)> {
+        let end = s.find('}')?;
+        let inner = s.strip_prefix('{')?.get(..end - 1)?;
+        let (inner, functions) = match inner.split_once('|') {
+            Some((inner, functions)) => (
+                inner,
+                functions
+                    .split('|')
+                    .map(Function::parse)
+                    .collect::<Option<_>>()?,
+            ),
+            None => (inner, vec![]),
+        };
+        let placeholder = match inner {
+            "#" => Placeholder::Seq,
+            "%" => Placeholder::Slot,
+            "attempt" => Placeholder::Attempt,
+            _ => {
+                let digits =
+                    inner.len() - inner.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+                let transform = Transform::parse(&inner[digits..])?;
+                match &inner[..digits] {
+                    "" => Placeholder::All(transform),
+                    n => Placeholder::Position(n.parse().ok().filter(|&n| n > 0)?, transform),
+                }
+            }
+        };
+        Some((placeholder, functions, end + 1))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    text: String,
+    quoting: Quoting,
+    // Every word is quoted as a whole once filled in (--quote)
+    words: Vec<String>,
+}
+
+impl Template {
+    // Is `command` a template rather than a job of its own
+    pub fn is_template(command: &str) -> bool {
+        command
+            .match_indices('{')
+            .any(|(i, _)| Placeholder::parse(&command[i..]).is_some())
+    }
+
+    pub fn new(text: String, shell: Option<&OsStr>) -> Self {
+        Template {
+            text,
+            quoting: Quoting::for_shell(shell),
+            words: vec![],
+        }
+    }
+
+    // The words of a command given on the command line, which end up as one
+    // word each for the shell whatever they contain (--quote)
+    pub fn quoted(words: Vec<String>, shell: Option<&OsStr>) -> Self {
+        Template {
+            text: words.join(" "),
+            quoting: Quoting::for_shell(shell),
+            words,
+        }
+    }
+
+    // A template for text that is not run by a shell, e.g. a file name
+    pub fn raw(text: String) -> Self {
+        Template {
+            text,
+            quoting: Quoting::None,
+            words: vec![],
+        }
+    }
+
+    // `{name}` for the column `name` of the --header line becomes `{N}`, with
+    // the same transforms and functions, e.g. `{name/.}` or `{name|upper}`
+    pub fn with_columns(&self, names: &[String]) -> Template {
+        let rename = |text: &str| {
+            let mut renamed = String::with_capacity(text.len());
+            let mut rest = text;
+            while let Some(i) = rest.find('{') {
+                renamed.push_str(&rest[..i]);
+                rest = &rest[i..];
+                let Some(end) = rest.find('}') else {
+                    break;
+                };
+                let inner = &rest[1..end];
+                let functions = inner.find('|').map_or("", |i| &inner[i..]);
+                let inner = &inner[..inner.len() - functions.len()];
+                let column = ["/.", "//", ".", "/", ""].iter().find_map(|transform| {
+                    let name = inner.strip_suffix(transform)?;
+                    let n = names.iter().position(|column| column.trim() == name)?;
+                    Some(format!("{{{}{transform}{functions}}}", n + 1))
+                });
+                match column {
+                    Some(column) if !inner.is_empty() => {
+                        renamed.push_str(&column);
+                        rest = &rest[end + 1..];
+                    }
+                    _ => {
+                        renamed.push('{');
+                        rest = &rest[1..];
+                    }
+                }
+            }
+            renamed.push_str(rest);
+            renamed
+        };
+        Template {
+            text: rename(&self.text),
+            quoting: self.quoting,
+            words: self.words.iter().map(|word| rename(word)).collect(),
+        }
+    }
+
+    // The command for a single set of arguments, e.g. an input line or one
+    // argument from every input source
+    // `{#}` and `{%}` are left as they are, until the job is started
+    pub fn expand(&self, args: &[String]) -> String {
+        self.fill_in_all(args, None)
+    }
+
+    // The command of a started job, with its sequence and slot number and the
+    // attempt
+    pub fn expand_job(&self, args: &[String], seq: usize, slot: usize, attempt: u32) -> String {
+        self.fill_in_all(args, Some((seq, slot, attempt)))
+    }
+
+    fn fill_in_all(&self, args: &[String], job: Option<(usize, usize, u32)>) -> String {
+        if self.words.is_empty() {
+            return Self::fill_in(&self.text, args, self.quoting, job);
+        }
+        let words: Vec<_> = self
+            .words
+            .iter()
+            .map(|word| {
+                self.quoting
+                    .quote(&Self::fill_in(word, args, Quoting::None, job))
+            })
+            .collect();
+        words.join(" ")
+    }
+
+    fn fill_in(
+        text: &str,
+        args: &[String],
+        quoting: Quoting,
+        job: Option<(usize, usize, u32)>,
+    ) -> String {
+        let mut command = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(i) = rest.find('{') {
+            command.push_str(&rest[..i]);
+            rest = &rest[i..];
+            match Placeholder::parse(rest) {
+                Some((Placeholder::All(transform), functions, len)) => {
+                    let quoted: Vec<_> = args
+                        .iter()
+                        .map(|arg| quoting.quote(&apply(&functions, transform.apply(arg))))
+                        .collect();
+                    command.push_str(&quoted.join(" "));
+                    rest = &rest[len..];
+                }
+                Some((Placeholder::Position(n, transform), functions, len)) => {
+                    if let Some(arg) = args.get(n - 1) {
+                        command.push_str(&quoting.quote(&apply(&functions, transform.apply(arg))));
+                    }
+                    rest = &rest[len..];
+                }
+                Some((
+                    placeholder @ (Placeholder::Seq | Placeholder::Slot | Placeholder::Attempt),
+                    functions,
+                    len,
+                )) => {
+                    let number = match (placeholder, job) {
+                        (Placeholder::Seq, Some((seq, _, _))) => seq.to_string(),
+                        (Placeholder::Slot, Some((_, slot, _))) => slot.to_string(),
+                        (_, Some((_, _, attempt))) => attempt.to_string(),
+                        (_, None) => {
+                            command.push_str(&rest[..len]);
+                            rest = &rest[len..];
+                            continue;
+                        }
+                    };
+                    // Digits, or hex digits and dots after the functions
+                    command.push_str(&apply(&functions, &number));
+                    rest = &rest[len..];
+                }
+                None => {
+                    command.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        command.push_str(rest);
+        command
+    }
+
+    // How much longer the command gets with `arg` as its argument number `n`
+    // (from 1)
+    fn arg_len(&self, n: usize, arg: &str) -> usize {
+        let quoted = |transform: Transform, functions: &[Function]| {
+            self.quoting
+                .quote(&apply(functions, transform.apply(arg)))
+                .len()
+        };
+        self.text
+            .match_indices('{')
+            .filter_map(|(i, _)| Placeholder::parse(&self.text[i..]))
+            .map(|(placeholder, functions, _)| match placeholder {
+                Placeholder::All(transform) => quoted(transform, &functions) + usize::from(n > 1),
+                Placeholder::Position(i, transform) if i == n => quoted(transform, &functions),
+                Placeholder::Position(..)
+                | Placeholder::Seq
+                | Placeholder::Slot
+                | Placeholder::Attempt => 0,
+            })
+            .sum()
+    }
+}
+
+// Several arguments per command (-N/--max-args, --max-chars)
+#[derive(Debug, Clone, Copy)]
+pub struct Chunking {
+    pub max_args: usize,
+    pub max_chars: usize,
+}
+
+impl Chunking {
+    // The arguments of every command, a single argument longer than
+    // `max_chars` gets a command of its own anyway
+    pub fn chunks<'a>(
+        self,
+        args: impl Iterator<Item = String> + 'a,
+        template: &'a Template,
+    ) -> impl Iterator<Item = Vec<String>> + 'a {
+        let base = template.expand(&[]).len();
+        let mut args = args.peekable();
+        std::iter::from_fn(move || {
+            let mut chunk = vec![];
+            let mut len = base;
+            while chunk.len() < self.max_args {
+                let Some(arg) = args.peek() else {
+                    break;
+                };
+                let added = template.arg_len(chunk.len() + 1, arg);
+                if !chunk.is_empty() && len + added > self.max_chars {
+                    break;
+                }
+                len += added;
+                chunk.extend(args.next());
+            }
+            (!chunk.is_empty()).then_some(chunk)
+        })
+    }
+}
+
+// Replace `{#}`, `{%}` and `{attempt}` in text given with an option (--pre,
+// --post), which is not filled in with arguments
+pub fn fill_in_job(text: &str, seq: usize, slot: usize, attempt: u32) -> String {
+    text.replace("{#}", &seq.to_string())
+        .replace("{%}", &slot.to_string())
+        .replace("{attempt}", &attempt.to_string())
+}
+
+// Quote `arg` for a POSIX shell, e.g. the login shell of a remote host
+pub fn quote_posix(arg: &str) -> String {
+    Quoting::Posix.quote(arg)
+}
+
+// The words of a command run without a shell (--no-shell), following the
+// quoting rules of a POSIX shell: 'single quotes' keep everything as it is,
+// "double quotes" allow \" \\ \$ and \` escapes, a backslash outside of quotes
+// escapes any character. Nothing is expanded.
+pub fn split_words(command: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                word.extend(chars.by_ref().take_while(|&c| c != '\''));
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some('\n') => {}
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => word.push('\\'),
+                        },
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                let word = word.get_or_insert_with(String::new);
+                match chars.next() {
+                    Some('\n') => {}
+                    Some(c) => word.push(c),
+                    None => word.push('\\'),
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+// Split `CMD... ::: A B ::: C D` into the command and its input sources
+pub fn split_sources(mut args: Vec<String>) -> (Vec<String>, Vec<InputSource>) {
+    let Some(first) = args.iter().position(|arg| is_source_sep(arg)) else {
+        return (args, vec![]);
+    };
+    let mut sources: Vec<InputSource> = vec![];
+    for arg in args.drain(first..) {
+        if is_source_sep(&arg) {
+            sources.push(InputSource {
+                args: vec![],
+                linked: arg.ends_with('+'),
+                files: arg.starts_with(FILE_SOURCE_SEP),
+            });
+        } else if let Some(source) = sources.last_mut() {
+            source.args.push(arg);
+        }
+    }
+    (args, sources)
+}
+
+// Linked sources side by side, the shorter ones start over until the
+// longest one is used up, like GNU parallel does
+fn zip(sources: &[InputSource]) -> Vec<Vec<String>> {
+    let len = sources
+        .iter()
+        .map(|source| source.args.len())
+        .max()
+        .unwrap_or(0);
+    (0..len)
+        .map(|i| {
+            sources
+                .iter()
+                .map(|source| source.args[i % source.args.len()].clone())
+                .collect()
+        })
+        .collect()
+}
+
+// Every combination of one argument (or a group of linked ones) from each
+// source, the last source changing fastest. The first source is linked to
+// nothing.
+pub fn product(sources: &[InputSource]) -> Vec<Vec<String>> {
+    let mut groups: Vec<&[InputSource]> = vec![];
+    let mut start = 0;
+    for i in 1..=sources.len() {
+        if sources.get(i).is_none_or(|source| !source.linked) {
+            groups.push(&sources[start..i]);
+            start = i;
+        }
+    }
+    groups.iter().fold(vec![vec![]], |combinations, group| {
+        let rows = zip(group);
+        combinations
+            .iter()
+            .flat_map(|combination| {
+                rows.iter().map(move |row| {
+                    let mut combination = combination.clone();
+                    combination.extend(row.iter().cloned());
+                    combination
+                })
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(template: &str, args: &[&str]) -> String {
+        let args: Vec<String> = args.iter().map(|arg| (*arg).to_string()).collect();
+        Template::raw(template.to_string()).expand(&args)
+    }
+
+    #[test]
+    fn remove_extension() {
+        assert_eq!(expand("{.}", &["dir/file.txt"]), "dir/file");
+        assert_eq!(expand("{.}", &["archive.tar.gz"]), "archive.tar");
+        assert_eq!(expand("{.}", &["v1.2/README"]), "v1.2/README");
+        assert_eq!(expand("{.}", &[".bashrc"]), ".bashrc");
+        assert_eq!(expand("{.}", &["dir/.hidden.conf"]), "dir/.hidden");
+        assert_eq!(expand("{.}", &["file."]), "file");
+        assert_eq!(expand("{.}", &[""]), "");
+    }
+
+    #[test]
+    fn basename() {
+        assert_eq!(expand("{/}", &["/a/b/c.txt"]), "c.txt");
+        assert_eq!(expand("{/}", &["c.txt"]), "c.txt");
+        assert_eq!(expand("{/}", &["dir/"]), "");
+        assert_eq!(expand("{/.}", &["/a/b/c.tar.gz"]), "c.tar");
+        assert_eq!(expand("{/.}", &["a.d/.profile"]), ".profile");
+    }
+
+    #[test]
+    fn dirname() {
+        assert_eq!(expand("{//}", &["/a/b/c.txt"]), "/a/b");
+        assert_eq!(expand("{//}", &["c.txt"]), ".");
+        assert_eq!(expand("{//}", &["/c.txt"]), "/");
+        assert_eq!(expand("{//}", &["a//b"]), "a");
+        assert_eq!(expand("{//}", &["a/b/"]), "a");
+        assert_eq!(expand("{//}", &["/"]), "/");
+        assert_eq!(expand("{//}", &[""]), ".");
+    }
+
+    #[test]
+    fn positions() {
+        assert_eq!(
+            expand("{1/.} {2//} {2} {3.}", &["x/y.png", "out/big", "z.gz"]),
+            "y out out/big z"
+        );
+        assert_eq!(expand("{/}", &["a/1", "b/2"]), "1 2");
+        assert_eq!(expand("{#}-{%}", &["a"]), "{#}-{%}");
+        assert_eq!(expand("{x} {1x} {0}", &["a"]), "{x} {1x} {0}");
+    }
+
+    #[test]
+    fn linked_sources() {
+        let args = ["echo", ":::", "a", "b", "c", ":::+", "1", "2", ":::", "x"];
+        let (command, sources) = split_sources(args.iter().map(|arg| (*arg).to_string()).collect());
+        assert_eq!(command, ["echo"]);
+        assert_eq!(
+            product(&sources),
+            [["a", "1", "x"], ["b", "2", "x"], ["c", "1", "x"]]
+        );
+    }
+
+    #[test]
+    fn quoted_after_transform() {
+        let template = Template::new(String::from("echo {/}"), Some(OsStr::new("sh")));
+        assert_eq!(
+            template.expand(&[String::from("dir/it's here")]),
+            r"echo 'it'\''s here'"
+        );
+        assert!(Template::is_template("gzip {/.}"));
+        assert!(!Template::is_template("awk '{print}'"));
+    }
+
+    #[test]
+    fn named_columns() {
+        let names = ["host", "db", "file"].map(String::from);
+        let template = Template::raw(String::from("backup {host} {db} {file/.} {x} {1} {}"));
+        let args = ["a", "b", "dir/c.sql"].map(String::from);
+        assert_eq!(
+            template.with_columns(&names).expand(&args),
+            "backup a b c {x} a a b dir/c.sql"
+        );
+    }
+
+    #[test]
+    fn job_numbers() {
+        let template = Template::raw(String::from("{}-{#}-{%}-{attempt}"));
+        assert_eq!(
+            template.expand_job(&[String::from("{#}{%}")], 7, 2, 3),
+            "{#}{%}-7-2-3"
+        );
+        assert_eq!(template.expand(&[String::from("a")]), "a-{#}-{%}-{attempt}");
+    }
+
+    #[test]
+    fn quoted_words() {
+        let words = ["printf", "%s $x\\n", "{} {.}"].map(String::from).to_vec();
+        let template = Template::quoted(words, Some(OsStr::new("sh")));
+        assert_eq!(
+            template.expand(&[String::from("a;b.txt")]),
+            r"printf '%s $x\n' 'a;b.txt a;b'"
+        );
+    }
+
+    #[test]
+    fn case_functions() {
+        assert_eq!(expand("{1|upper}", &["a.txt", "b"]), "A.TXT");
+        assert_eq!(expand("{|lower}", &["Ab", "CD"]), "ab cd");
+        assert_eq!(expand("{/.|upper}", &["dir/read.me"]), "READ");
+    }
+
+    #[test]
+    fn sha1_function() {
+        assert_eq!(
+            expand("{|sha1}", &["abc"]),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            expand("{2|sha1}", &["x", ""]),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+    }
+
+    #[test]
+    fn pad_function() {
+        let template = Template::raw(String::from("out-{#|pad:5}-{%|pad:2}-{1|pad:3}"));
+        assert_eq!(
+            template.expand_job(&[String::from("7")], 42, 3, 1),
+            "out-00042-03-007"
+        );
+        assert_eq!(
+            template.expand(&[String::from("abcd")]),
+            "out-{#|pad:5}-{%|pad:2}-abcd"
+        );
+    }
+
+    #[test]
+    fn path_functions() {
+        assert_eq!(expand("{1|dirname}", &["/a/b/c.txt"]), "/a/b");
+        assert_eq!(expand("{1|basename}", &["/a/b/c.txt"]), "c.txt");
+        assert_eq!(expand("{1|basename|noext}", &["/a/b/c.tar.gz"]), "c.tar");
+        assert_eq!(expand("{|dirname|basename}", &["/a/b/c.txt"]), "b");
+    }
+
+    #[test]
+    fn unknown_functions() {
+        assert_eq!(
+            expand("{1|title} {|} {1|pad} {1|pad:x} {1|upper|}", &["a"]),
+            "{1|title} {|} {1|pad} {1|pad:x} {1|upper|}"
+        );
+        assert!(!Template::is_template("awk '{print $1|\"sort\"}'"));
+        assert!(Template::is_template("gzip {|sha1}"));
+    }
+
+    #[test]
+    fn quoted_after_functions() {
+        let template = Template::new(String::from("echo {|lower}"), Some(OsStr::new("sh")));
+        assert_eq!(template.expand(&[String::from("IT'S")]), r"echo 'it'\''s'");
+        let names = [String::from("name")];
+        let template = Template::raw(String::from("{name|upper}"));
+        assert_eq!(template.with_columns(&names).expand(&names), "NAME");
+    }
+}
@@ -0,0 +1,347 @@
+// --watch PATH: the jobs are run again whenever something below PATH changes,
+// until parallel-sh is stopped
+//
+// Every round is parallel-sh started again with the same arguments, so the
+// jobs have to be given on the command line or with --file. With
+// --watch-changed it only runs the jobs with a changed file as an argument,
+// passed on in PARALLEL_SH_CHANGED. Changes made while the jobs run are not
+// noticed, jobs writing below PATH would start themselves over and over.
+use log::{error, info};
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    path::{self, Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+pub const CHANGED_VAR: &str = "PARALLEL_SH_CHANGED";
+
+// Changes coming shortly after each other are one, e.g. an editor saving
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+// Paths are compared as absolute ones, deleted files cannot be resolved
+pub fn absolute(path: &Path) -> PathBuf {
+    path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// The changed files passed on to a round, all jobs are run without
+pub fn changed() -> Option<HashSet<PathBuf>> {
+    let changed = std::env::var_os(CHANGED_VAR)?;
+    let changed: HashSet<PathBuf> = changed
+        .to_string_lossy()
+        .lines()
+        .map(PathBuf::from)
+        .collect();
+    (!changed.is_empty()).then_some(changed)
+}
+
+// Set for the rounds, which do not watch themselves
+pub fn is_round() -> bool {
+    std::env::var_os(CHANGED_VAR).is_some()
+}
+
+// The arguments of a round, without the --watch options before the jobs
+fn round_args() -> Vec<OsString> {
+    let mut args = vec![];
+    let mut rest = std::env::args_os().skip(1);
+    while let Some(arg) = rest.next() {
+        match arg.to_str() {
+            Some("--watch") => {
+                rest.next();
+            }
+            Some(arg) if arg.starts_with("--watch=") || arg == "--watch-changed" => {}
+            Some("--" | ":::" | ":::+" | "::::" | "::::+") => {
+                args.push(arg);
+                args.extend(rest);
+                break;
+            }
+            _ => args.push(arg),
+        }
+    }
+    args
+}
+
+pub fn run(paths: &[PathBuf], changed_only: bool) -> ! {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            error!("Could not find the parallel-sh executable: {}", e);
+            crate::jobctl::exit(1);
+        }
+    };
+    let mut watcher = match sys::Watcher::new(paths) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("Could not watch for changes: {}", e);
+            crate::jobctl::exit(1);
+        }
+    };
+    let args = round_args();
+    let mut changed = vec![];
+    loop {
+        let changed_var = changed
+            .iter()
+            .map(|path: &PathBuf| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join("\n");
+        match Command::new(&exe)
+            .args(&args)
+            .env(CHANGED_VAR, changed_var)
+            .status()
+        {
+            Ok(status) => info!("Jobs done ({}), waiting for changes", status),
+            Err(e) => {
+                error!("Could not run the jobs: {}", e);
+                crate::jobctl::exit(1);
+            }
+        }
+        watcher.clear();
+        changed = watcher.wait();
+        info!("{} path(s) changed, running the jobs again", changed.len());
+        if !changed_only {
+            changed.clear();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::{absolute, SETTLE_TIME};
+    use log::warn;
+    use std::{
+        collections::HashMap,
+        ffi::CString,
+        fs::{self, File},
+        io::{self, Read},
+        os::{
+            fd::{AsRawFd, FromRawFd},
+            unix::ffi::OsStrExt,
+        },
+        path::{Path, PathBuf},
+    };
+
+    const MASK: u32 = libc::IN_MODIFY
+        | libc::IN_ATTRIB
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_DELETE_SELF
+        | libc::IN_MOVE_SELF;
+
+    // The fixed part of struct inotify_event, the name follows
+    const EVENT_SIZE: usize = 16;
+
+    // Directories are watched with all directories below them
+    pub struct Watcher {
+        inotify: File,
+        watched: HashMap<i32, PathBuf>,
+    }
+
+    impl Watcher {
+        pub fn new(paths: &[PathBuf]) -> io::Result<Self> {
+            // SAFETY: inotify_init1(2) has no memory safety implications
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut watcher = Watcher {
+                // SAFETY: fd is a new file descriptor owned by nothing else
+                inotify: unsafe { File::from_raw_fd(fd) },
+                watched: HashMap::new(),
+            };
+            for path in paths {
+                watcher.add(path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("'{}': {}", path.display(), e))
+                })?;
+            }
+            Ok(watcher)
+        }
+
+        fn add(&mut self, path: &Path) -> io::Result<()> {
+            let name = CString::new(path.as_os_str().as_bytes())?;
+            // SAFETY: name is a NUL terminated string that outlives the call
+            let wd =
+                unsafe { libc::inotify_add_watch(self.inotify.as_raw_fd(), name.as_ptr(), MASK) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.watched.insert(wd, path.to_path_buf());
+            if path.is_dir() {
+                for entry in fs::read_dir(path)? {
+                    let entry = entry?;
+                    if entry.file_type()?.is_dir() {
+                        self.add(&entry.path())?;
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        // Whether there is something to read within `timeout`, None blocks
+        fn ready(&self, timeout: Option<std::time::Duration>) -> bool {
+            let mut poll = libc::pollfd {
+                fd: self.inotify.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout = timeout.map_or(-1, |timeout| {
+                libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX)
+            });
+            // SAFETY: poll points to a single valid pollfd
+            unsafe { libc::poll(&raw mut poll, 1, timeout) > 0 }
+        }
+
+        fn read(&mut self, changed: &mut Vec<PathBuf>) {
+            let mut buf = [0; 4096];
+            let read = match self.inotify.read(&mut buf) {
+                Ok(read) => read,
+                Err(e) => {
+                    warn!("Could not read file system events: {}", e);
+                    return;
+                }
+            };
+            let mut events = &buf[..read];
+            while events.len() >= EVENT_SIZE {
+                let field = |i: usize| {
+                    let mut bytes = [0; 4];
+                    bytes.copy_from_slice(&events[i..i + 4]);
+                    bytes
+                };
+                let wd = i32::from_ne_bytes(field(0));
+                let mask = u32::from_ne_bytes(field(4));
+                let len = usize::try_from(u32::from_ne_bytes(field(12))).unwrap_or(usize::MAX);
+                let name = events.get(EVENT_SIZE..EVENT_SIZE + len).unwrap_or_default();
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                events = events.get(EVENT_SIZE + len..).unwrap_or_default();
+                let Some(dir) = self.watched.get(&wd) else {
+                    continue;
+                };
+                let path = if name.is_empty() {
+                    dir.clone()
+                } else {
+                    dir.join(std::ffi::OsStr::from_bytes(name))
+                };
+                if mask & libc::IN_IGNORED != 0 {
+                    self.watched.remove(&wd);
+                    continue;
+                }
+                // New directories are watched as well
+                if mask & libc::IN_ISDIR != 0 && mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                    if let Err(e) = self.add(&path) {
+                        warn!("Could not watch '{}': {}", path.display(), e);
+                    }
+                }
+                changed.push(absolute(&path));
+            }
+        }
+
+        // Forget what changed so far
+        pub fn clear(&mut self) {
+            let mut changed = vec![];
+            while self.ready(Some(std::time::Duration::ZERO)) {
+                self.read(&mut changed);
+            }
+        }
+
+        // Blocks until something changes, the changed paths without
+        // duplicates
+        pub fn wait(&mut self) -> Vec<PathBuf> {
+            let mut changed = vec![];
+            self.ready(None);
+            while self.ready(Some(SETTLE_TIME)) {
+                self.read(&mut changed);
+            }
+            let mut seen = std::collections::HashSet::new();
+            changed.retain(|path| seen.insert(path.clone()));
+            changed
+        }
+    }
+}
+
+// Without file system events the modification times are compared
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use super::{absolute, SETTLE_TIME};
+    use std::{
+        collections::HashMap,
+        fs, io,
+        path::{Path, PathBuf},
+        thread,
+        time::{Duration, SystemTime},
+    };
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    pub struct Watcher {
+        paths: Vec<PathBuf>,
+        files: HashMap<PathBuf, Option<SystemTime>>,
+    }
+
+    fn scan(path: &Path, files: &mut HashMap<PathBuf, Option<SystemTime>>) {
+        let Ok(metadata) = fs::metadata(path) else {
+            return;
+        };
+        files.insert(absolute(path), metadata.modified().ok());
+        if metadata.is_dir() {
+            for entry in fs::read_dir(path).into_iter().flatten().flatten() {
+                scan(&entry.path(), files);
+            }
+        }
+    }
+
+    impl Watcher {
+        pub fn new(paths: &[PathBuf]) -> io::Result<Self> {
+            for path in paths {
+                fs::metadata(path).map_err(|e| {
+                    io::Error::new(e.kind(), format!("'{}': {}", path.display(), e))
+                })?;
+            }
+            let mut watcher = Watcher {
+                paths: paths.to_vec(),
+                files: HashMap::new(),
+            };
+            watcher.clear();
+            Ok(watcher)
+        }
+
+        fn scan(&self) -> HashMap<PathBuf, Option<SystemTime>> {
+            let mut files = HashMap::new();
+            for path in &self.paths {
+                scan(path, &mut files);
+            }
+            files
+        }
+
+        pub fn clear(&mut self) {
+            self.files = self.scan();
+        }
+
+        pub fn wait(&mut self) -> Vec<PathBuf> {
+            loop {
+                thread::sleep(POLL_INTERVAL);
+                if self.scan() != self.files {
+                    break;
+                }
+            }
+            thread::sleep(SETTLE_TIME);
+            let files = self.scan();
+            let mut changed: Vec<PathBuf> = files
+                .iter()
+                .filter(|(path, modified)| self.files.get(*path) != Some(modified))
+                .map(|(path, _)| path.clone())
+                .chain(
+                    self.files
+                        .keys()
+                        .filter(|path| !files.contains_key(*path))
+                        .cloned(),
+                )
+                .collect();
+            changed.sort();
+            self.files = files;
+            changed
+        }
+    }
+}
@@ -0,0 +1,187 @@
+// Time of day new jobs may be started in (--window 22:00-06:00), how far
+// apart (--delay) and how many in a while (--rate 10/1m)
+use log::info;
+use std::{
+    sync::{Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+const DAY: u32 = 24 * 60 * 60;
+
+// Start and end in seconds after local midnight, the window may wrap
+// around midnight
+#[derive(Debug, Clone, Copy)]
+pub struct Window {
+    start: u32,
+    end: u32,
+}
+
+fn parse_time(s: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid time '{s}', expected HH:MM");
+    let (hours, minutes) = s.split_once(':').ok_or_else(invalid)?;
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+    if minutes >= 60 || hours > 24 || (hours == 24 && minutes > 0) {
+        return Err(invalid());
+    }
+    Ok((hours * 60 + minutes) * 60)
+}
+
+impl std::str::FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("invalid window '{s}', expected HH:MM-HH:MM"))?;
+        let start = parse_time(start)? % DAY;
+        let end = parse_time(end)? % DAY;
+        if start == end {
+            return Err(format!("window '{s}' is empty"));
+        }
+        Ok(Window { start, end })
+    }
+}
+
+impl Window {
+    fn contains(self, now: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&now)
+        } else {
+            now >= self.start || now < self.end
+        }
+    }
+
+    // Block until the window is open, or no more jobs should be started
+    pub fn wait(self) {
+        let mut waiting = false;
+        loop {
+            let now = local_time();
+            if self.contains(now) || crate::jobctl::stopping() {
+                return;
+            }
+            if !waiting {
+                info!("Waiting for the window to start new jobs");
+                waiting = true;
+            }
+            // Check again every second, in case the clock changes or the run
+            // is interrupted
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+// Time between two job starts, one gate for all workers so jobs are started
+// one after the other no matter how many are waiting
+#[derive(Debug)]
+pub struct Delay {
+    interval: Duration,
+    next: Mutex<Option<Instant>>,
+}
+
+impl Delay {
+    pub fn new(interval: Duration) -> Self {
+        Delay {
+            interval,
+            next: Mutex::new(None),
+        }
+    }
+
+    // Block until the next job may be started, the first one right away
+    pub fn wait(&self) {
+        let mut next = self.next.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(wait) = next.and_then(|next| next.checked_duration_since(Instant::now())) {
+            thread::sleep(wait);
+        }
+        *next = Some(Instant::now() + self.interval);
+    }
+}
+
+// At most `jobs` job starts per `period`, a token bucket which is full at
+// first, so up to `jobs` are started right away
+#[derive(Debug)]
+pub struct Rate {
+    jobs: u32,
+    period: Duration,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl Rate {
+    pub fn new(jobs: u32, period: Duration) -> Self {
+        Rate {
+            jobs,
+            period,
+            bucket: Mutex::new((f64::from(jobs), Instant::now())),
+        }
+    }
+
+    // Block until a job may be started, or no more jobs should be started
+    pub fn wait(&self) {
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+        let per_job = self.period.as_secs_f64() / f64::from(self.jobs);
+        let mut waiting = false;
+        loop {
+            let (tokens, last) = &mut *bucket;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() / per_job)
+                .min(f64::from(self.jobs));
+            *last = now;
+            if *tokens >= 1.0 {
+                *tokens -= 1.0;
+                return;
+            }
+            if crate::jobctl::stopping() {
+                return;
+            }
+            if !waiting {
+                info!("Rate limit reached, waiting to start new jobs");
+                waiting = true;
+            }
+            let wait = Duration::from_secs_f64((1.0 - *tokens) * per_job);
+            thread::sleep(wait.min(Duration::from_secs(1)));
+        }
+    }
+}
+
+// Seconds since local midnight
+#[cfg(not(target_os = "windows"))]
+fn local_time() -> u32 {
+    // SAFETY: localtime_r only writes to tm, which is zero-initialized
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        tm
+    };
+    #[allow(clippy::cast_sign_loss)]
+    let seconds = (tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec.min(59)) as u32;
+    seconds
+}
+
+#[cfg(target_os = "windows")]
+fn local_time() -> u32 {
+    #[repr(C)]
+    #[derive(Default)]
+    #[allow(dead_code)]
+    struct SystemTime {
+        year: u16,
+        month: u16,
+        day_of_week: u16,
+        day: u16,
+        hour: u16,
+        minute: u16,
+        second: u16,
+        milliseconds: u16,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetLocalTime(time: *mut SystemTime);
+    }
+
+    let mut time = SystemTime::default();
+    // SAFETY: time is a valid SYSTEMTIME
+    unsafe { GetLocalTime(&mut time) };
+    (u32::from(time.hour) * 60 + u32::from(time.minute)) * 60 + u32::from(time.second)
+}